@@ -2,7 +2,7 @@
 
 //! Interaction with physical exceptions
 
-use crate::pager::{Addr, HandlerReturnAction, VirtAddr};
+use crate::pager::{Addr, HandlerReturnAction, VirtAddr, MAX_CORES};
 use crate::Result;
 
 use core::arch::asm;
@@ -73,6 +73,25 @@ fn el1_sp0_sync_handler() -> ! {
     loop {}
 }
 
+const OVERFLOW_STACK_BYTES: usize = 0x4000;
+
+/// Stacks to report a kernel stack overflow on, as the overflowed stack is unusable.
+///
+/// Each core takes the one indexed by its MPIDR_EL1.Aff0, so overflows on different
+/// cores don't share a stack.
+#[repr(C, align(16))]
+struct OverflowStacks([[u8; OVERFLOW_STACK_BYTES]; MAX_CORES]);
+
+#[no_mangle]
+static mut OVERFLOW_STACKS: OverflowStacks = OverflowStacks([[0; OVERFLOW_STACK_BYTES]; MAX_CORES]);
+
+#[no_mangle]
+extern "C" fn el1_sp1_overflow_handler() -> ! {
+    use cortex_a::registers::FAR_EL1;
+
+    crate::pager::kernel_stack_overflow(VirtAddr::at(FAR_EL1.get() as usize))
+}
+
 #[no_mangle]
 extern "C" fn el1_sp1_sync_handler(exc: &ExceptionContext) -> () {
     use cortex_a::registers::{ESR_EL1::*, *};
@@ -246,8 +265,16 @@ vector_table_el1:   mov x0, 0
 				  
 .balign 0x080       /* Exception taken from EL1 with SP_EL1. */
                     /* Synchronous */
-                    /* loop if double-fault due to SP overflow */
-                    ldr     xzr, [sp]                      
+                    /* fault at SP means the stack has overflowed */
+                    /* the kernel keeps nothing in TPIDRRO_EL0, and clears it for EL0 */
+                    msr     TPIDRRO_EL0, x0
+                    mrs     x0, FAR_EL1
+                    cmp     sp, x0
+                    mrs     x0, TPIDRRO_EL0
+                    msr     TPIDRRO_EL0, xzr
+                    b.eq    .stack_overflow
+                    /* fault here if SP is in a guard page */
+                    ldr     xzr, [sp]
 				    EXCEPTION_ENTRY el1_sp1_sync_handler
 .balign 0x080
 				    mov     x0, 5
@@ -339,5 +366,23 @@ vector_table_el1:   mov x0, 0
                     ldp x14, x15, [sp], #16
 
                     eret
-"#
+
+.stack_overflow:    mrs     x0, MPIDR_EL1
+                    and     x0, x0, #0xff        // Aff0
+                    cmp     x0, #{cores}
+                    b.hs    .no_overflow_stack
+                    add     x0, x0, #1
+                    mov     x1, #{stack_bytes}
+                    mul     x0, x0, x1
+                    adrp    x1, OVERFLOW_STACKS
+                    add     x1, x1, :lo12:OVERFLOW_STACKS
+                    add     x0, x0, x1           // top of this core's stack
+                    mov     sp, x0
+                    b       el1_sp1_overflow_handler
+
+.no_overflow_stack: wfe
+                    b       .no_overflow_stack
+"#,
+    cores = const MAX_CORES,
+    stack_bytes = const OVERFLOW_STACK_BYTES,
 );
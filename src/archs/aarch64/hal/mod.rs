@@ -16,3 +16,15 @@ pub fn core_id() -> u8 {
 
     MPIDR_EL1.read(AFF0) as u8
 }
+
+#[inline(always)]
+/// Sleep until an interrupt or event
+pub fn wait_for_event() {
+    cortex_a::asm::wfe()
+}
+
+#[inline(always)]
+/// Wake every core waiting for an event
+pub fn send_event() {
+    cortex_a::asm::sev()
+}
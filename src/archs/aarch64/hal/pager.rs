@@ -5,13 +5,21 @@
 use core::arch::asm;
 
 use crate::archs::aarch64;
-use crate::pager::{Addr, HandlerReturnAction, VirtAddr};
+use crate::pager::{Addr, AddrRange, HandlerReturnAction, VirtAddr, VirtAddrRange};
 use crate::Result;
 
 use super::handler::EsrEL1;
 
 use cortex_a::registers::*;
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
+use tock_registers::{register_bitfields, LocalRegisterCopy};
+
+register_bitfields! {
+    u64,
+    DataAbortISS [
+        WnR OFFSET(6) NUMBITS(1) []                        // Write not Read: caused by a write
+    ]
+}
 
 /// Initialise the MAIR register..
 ///
@@ -24,8 +32,17 @@ pub fn init_mair() {
 
     MAIR_EL1.write(
         Attr0_Device::nonGathering_nonReordering_noEarlyWriteAck
-            + Attr1_Normal_Outer::WriteThrough_NonTransient_ReadWriteAlloc
-            + Attr1_Normal_Inner::WriteThrough_NonTransient_ReadWriteAlloc,
+            + Attr1_Normal_Outer::WriteThrough_NonTransient_ReadAlloc
+            + Attr1_Normal_Inner::WriteThrough_NonTransient_ReadAlloc
+            + Attr2_Normal_Outer::WriteBack_NonTransient_ReadWriteAlloc
+            + Attr2_Normal_Inner::WriteBack_NonTransient_ReadWriteAlloc
+            + Attr3_Normal_Outer::NonCacheable
+            + Attr3_Normal_Inner::NonCacheable
+            + Attr4_Device::nonGathering_nonReordering_EarlyWriteAck
+            + Attr5_Device::Gathering_Reordering_EarlyWriteAck
+            + Attr6_Device::nonGathering_Reordering_EarlyWriteAck
+            + Attr7_Normal_Outer::NonCacheable
+            + Attr7_Normal_Inner::NonCacheable,
     );
 }
 
@@ -71,6 +88,18 @@ pub fn enable_paging(ttb1: u64, ttb0: u64, asid: u16) -> Result<()> {
     Ok(())
 }
 
+/// Replace the lower half translation table and the ASID tagging its TLB entries.
+pub fn set_ttbr0(ttb0: u64, asid: u16) -> Result<()> {
+    use cortex_a::asm::barrier;
+
+    unsafe {
+        TTBR0_EL1.write(TTBR0_EL1::ASID.val(asid as u64) + TTBR0_EL1::BADDR.val(ttb0 >> 1));
+        barrier::isb(barrier::SY);
+    }
+
+    Ok(())
+}
+
 /// Set the stack pointer
 pub fn move_stack(stack_pointer: usize, next: fn() -> !) -> ! {
     unsafe {
@@ -94,15 +123,32 @@ pub fn handle_data_abort_el1(esr: EsrEL1) -> Result<HandlerReturnAction> {
     let dfsc_reason: Value = esr
         .read_as_enum(ESR_EL1::ISS_DATA_FAULT_STATUS_CODE_REASON)
         .expect("unknown ESR_EL1::ISS_DATA_FAULT_STATUS_CODE_REASON");
+    let is_write =
+        LocalRegisterCopy::<u64, DataAbortISS::Register>::new(esr.get()).is_set(DataAbortISS::WnR);
     match dfsc_reason {
         Value::Translation => {
             let fault_addr = VirtAddr::at(FAR_EL1.get() as usize);
             crate::pager::kernel_translation_fault(
                 fault_addr,
                 Some(esr.read(ESR_EL1::ISS_DATA_FAULT_STATUS_CODE_LEVEL)),
+                is_write,
+            )
+        }
+        Value::Permission => {
+            let fault_addr = VirtAddr::at(FAR_EL1.get() as usize);
+            crate::pager::kernel_permission_fault(
+                fault_addr,
+                Some(esr.read(ESR_EL1::ISS_DATA_FAULT_STATUS_CODE_LEVEL)),
+                is_write,
+            )
+        }
+        Value::AccessFlag => {
+            let fault_addr = VirtAddr::at(FAR_EL1.get() as usize);
+            crate::pager::kernel_access_flag_fault(
+                fault_addr,
+                Some(esr.read(ESR_EL1::ISS_DATA_FAULT_STATUS_CODE_LEVEL)),
             )
         }
-        Value::AccessFlag => unimplemented!(),
         _ => unimplemented!(),
     }
 }
@@ -118,7 +164,7 @@ pub fn handle_instr_abort_el1(_esr: EsrEL1) -> Result<HandlerReturnAction> {
     Err(Error::SegmentFault)
 }
 
-/// Invalidate TLB for range
+/// Invalidate TLB for range, on every core in the inner shareable domain.
 pub fn invalidate_tlb(virt_addr: VirtAddr) -> Result<()> {
     use asm::barrier;
     use core::sync::atomic;
@@ -129,12 +175,76 @@ pub fn invalidate_tlb(virt_addr: VirtAddr) -> Result<()> {
     let base = virt_addr.get() >> 12;
 
     unsafe {
+        // make the descriptor update visible to other cores' table walks first
+        barrier::dsb(barrier::ISHST);
         asm!(
-            "tlbi VAAE1, {}",
+            "tlbi vaae1is, {}",
             in(reg) base,
         );
 
         atomic::fence(atomic::Ordering::SeqCst);
+        barrier::dsb(barrier::ISH);
+    }
+
+    Ok(())
+}
+
+/// Invalidate TLB entries for every address and ASID.
+pub fn invalidate_tlb_all() -> Result<()> {
+    use asm::barrier;
+    use cortex_a::asm;
+
+    unsafe {
+        barrier::dsb(barrier::SY);
+        asm!("tlbi vmalle1is");
+        barrier::dsb(barrier::SY);
+        barrier::isb(barrier::SY);
+    }
+
+    Ok(())
+}
+
+/// Invalidate TLB entries tagged with an ASID, on every core in the inner shareable domain.
+pub fn invalidate_tlb_asid(asid: u16) -> Result<()> {
+    use asm::barrier;
+    use cortex_a::asm;
+
+    unsafe {
+        barrier::dsb(barrier::ISHST);
+        asm!(
+            "tlbi aside1is, {}",
+            in(reg) (asid as u64) << 48,
+        );
+        barrier::dsb(barrier::ISH);
+        barrier::isb(barrier::SY);
+    }
+
+    Ok(())
+}
+
+/// Clean and invalidate data cache lines to the point of coherency for a range.
+pub fn clean_invalidate_dcache(virt_addr_range: VirtAddrRange) -> Result<()> {
+    use asm::barrier;
+    use cortex_a::asm;
+
+    let ctr: u64;
+    unsafe {
+        asm!("mrs {}, ctr_el0", out(reg) ctr);
+    }
+    // DminLine: log2 of the words in the smallest data cache line
+    let line: usize = 4 << ((ctr >> 16) & 0xf);
+
+    let mut virt_addr = virt_addr_range.base().align_down(line);
+    while virt_addr < virt_addr_range.top() {
+        unsafe {
+            asm!(
+                "dc civac, {}",
+                in(reg) virt_addr.get(),
+            );
+        }
+        virt_addr = virt_addr.increment(line);
+    }
+    unsafe {
         barrier::dsb(barrier::SY);
     }
 
@@ -21,6 +21,22 @@ pub fn core_id() -> u8 {
     1
 }
 
+pub fn wait_for_event() {}
+
+pub fn send_event() {}
+
 pub fn invalidate_tlb(_virt_addr: VirtAddr) -> Result<()> {
-    unimplemented!()
+    Ok(())
+}
+
+pub fn set_ttbr0(_: u64, _: u16) -> Result<()> {
+    Ok(())
+}
+
+pub fn invalidate_tlb_all() -> Result<()> {
+    Ok(())
+}
+
+pub fn invalidate_tlb_asid(_: u16) -> Result<()> {
+    Ok(())
 }
@@ -12,4 +12,12 @@ impl HandlerTrait for Arch {
         info!("init");
         hal::set_vbar()
     }
+
+    fn wait_for_event() {
+        hal::wait_for_event()
+    }
+
+    fn send_event() {
+        hal::send_event()
+    }
 }
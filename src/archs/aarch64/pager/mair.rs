@@ -7,13 +7,30 @@
 
 use super::hal;
 
+use crate::pager::{AttributeField, Attributes};
 use crate::Result;
 
 /// Type for indexes into the MAIR register (referenced in page table).
-#[derive(Debug)]
+///
+/// Note: Must match the register contents in hal::init_mair.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum MAIR {
+    /// Device-nGnRnE: no gathering, reordering or early write acknowledgement
     DeviceStronglyOrdered = 0,
+    /// Normal memory, cached for reads but not writes
     MemoryWriteThrough,
+    /// Normal memory, cached for reads and writes
+    MemoryWriteBack,
+    /// Normal memory, not cached
+    MemoryNonCacheable,
+    /// Device-nGnRE: writes may be acknowledged before they reach the device
+    DeviceEarlyWriteAck,
+    /// Device-GRE: accesses may also be gathered and reordered
+    DeviceGathering,
+    /// Device-nGRE: accesses may be reordered, but not gathered
+    DeviceReordering,
+    /// Normal memory, not cached, for streams from devices only
+    MemoryNonCacheableIn,
 }
 
 impl From<u64> for MAIR {
@@ -22,14 +39,87 @@ impl From<u64> for MAIR {
         match i {
             0 => DeviceStronglyOrdered,
             1 => MemoryWriteThrough,
+            2 => MemoryWriteBack,
+            3 => MemoryNonCacheable,
+            4 => DeviceEarlyWriteAck,
+            5 => DeviceGathering,
+            6 => DeviceReordering,
+            7 => MemoryNonCacheableIn,
             _ => panic!("unknown MAIR conversion"),
         }
     }
 }
 
+impl From<Attributes> for MAIR {
+    /// Choose the memory type for a mapping.
+    ///
+    /// The stream flags relax the caching of memory, or the ordering of devices.
+    fn from(attributes: Attributes) -> Self {
+        use AttributeField::*;
+        use MAIR::*;
+        match (
+            attributes.is_set(Device),
+            attributes.is_set(StreamIn),
+            attributes.is_set(StreamOut),
+        ) {
+            (true, false, false) => DeviceStronglyOrdered,
+            (true, false, true) => DeviceEarlyWriteAck,
+            (true, true, false) => DeviceReordering,
+            (true, true, true) => DeviceGathering,
+            (false, false, false) => MemoryWriteBack,
+            (false, false, true) => MemoryWriteThrough,
+            (false, true, false) => MemoryNonCacheableIn,
+            (false, true, true) => MemoryNonCacheable,
+        }
+    }
+}
+
+impl MAIR {
+    /// Whether the memory type can be held in the caches.
+    ///
+    /// Cacheable memory is shared within the inner domain, so that all cores see
+    /// a coherent view. Other types are always treated as outer shareable.
+    pub fn is_cacheable(self) -> bool {
+        matches!(self, MAIR::MemoryWriteThrough | MAIR::MemoryWriteBack)
+    }
+
+    /// The attribute flags which select this memory type.
+    pub fn attributes(self) -> Attributes {
+        use AttributeField::*;
+        use MAIR::*;
+        let result = Attributes::new();
+        match self {
+            DeviceStronglyOrdered => result.set(Device),
+            DeviceEarlyWriteAck => result.set(Device).set(StreamOut),
+            DeviceGathering => result.set(Device).set(StreamIn).set(StreamOut),
+            DeviceReordering => result.set(Device).set(StreamIn),
+            MemoryWriteBack => result,
+            MemoryWriteThrough => result.set(StreamOut),
+            MemoryNonCacheable => result.set(StreamIn).set(StreamOut),
+            MemoryNonCacheableIn => result.set(StreamIn),
+        }
+    }
+}
+
 /// Initialise the MAIR register.
 pub fn init() -> Result<()> {
     info!("init");
     hal::init_mair();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for i in 0..8 {
+            let mair = MAIR::from(i);
+            assert_eq!(mair, MAIR::from(mair.attributes()));
+        }
+        assert_eq!(MAIR::MemoryWriteBack, MAIR::from(Attributes::RAM));
+        assert_eq!(MAIR::MemoryNonCacheable, MAIR::from(Attributes::DMA));
+        assert_eq!(MAIR::DeviceStronglyOrdered, MAIR::from(Attributes::DEVICE));
+    }
+}
@@ -17,31 +17,60 @@ use table::{PageTable, TableDescriptor, LEVEL_OFFSETS, LEVEL_WIDTH};
 
 use super::{hal, Arch};
 
-use crate::archs::aarch64::pager::walk::{PageDirectoryWalk, TraversalOrder};
+use crate::archs::aarch64::pager::walk::{MappingWalk, PageDirectoryWalk, TraversalOrder};
 use crate::archs::{DeviceTrait, PagerTrait};
 use crate::device;
 use crate::pager::{
     Addr, AddrRange, AttributeField, Attributes, FixedOffset, FrameAllocator, FramePurpose,
-    PhysAddr, PhysAddrRange, Translate, VirtAddr, VirtAddrRange, PAGESIZE_BYTES,
+    NullTranslation, PhysAddr, PhysAddrRange, PhysAddrRanges, Translate, VirtAddr, VirtAddrRange,
+    PAGESIZE_BYTES,
 };
 use crate::util::locked::Locked;
 use crate::{Error, Result};
 
 use core::any::Any;
 use core::intrinsics::unchecked_sub;
+use core::num::NonZeroU64;
+use core::ptr::NonNull;
+
+/// Number of entries in a contiguous run.
+const CONTIG_SPAN: usize = 16;
 
 static mut RAM_RANGE: PhysAddrRange = PhysAddrRange::fixed(PhysAddr::null(), 0);
+static mut RAM_BANKS: PhysAddrRanges = PhysAddrRanges::new();
+static mut RESERVED_RANGES: PhysAddrRanges = PhysAddrRanges::new();
+static mut KASLR_SEED: Option<u64> = None;
 
 impl PagerTrait for Arch {
     fn ram_range() -> PhysAddrRange {
         unsafe { RAM_RANGE }
     }
 
+    fn ram_banks() -> PhysAddrRanges {
+        unsafe { RAM_BANKS }
+    }
+
+    fn reserved_ranges() -> PhysAddrRanges {
+        unsafe { RESERVED_RANGES }
+    }
+
     fn kernel_base() -> VirtAddr {
         let result = VirtAddr::at(!((1 << super::UPPER_VA_BITS) - 1));
         result
     }
 
+    fn user_range() -> VirtAddrRange {
+        // first level 0 entry holds the debug uart identity mapping, shared with the kernel
+        VirtAddrRange::between(
+            VirtAddr::at(1 << LEVEL_OFFSETS[TTB0_FIRST_LEVEL as usize]),
+            VirtAddr::at(1 << super::LOWER_VA_BITS),
+        )
+    }
+
+    fn kaslr_seed() -> Option<u64> {
+        unsafe { KASLR_SEED }
+    }
+
     fn kernel_offset() -> FixedOffset {
         layout::kernel_offset()
     }
@@ -74,7 +103,22 @@ impl PagerTrait for Arch {
         info!("init");
         mair::init()?;
         unsafe {
-            RAM_RANGE = device::get_ram_range_early()?;
+            let memory_map = device::get_memory_map_early()?;
+            info!("{:?}", memory_map);
+            RAM_RANGE = memory_map.banks.span().ok_or(Error::DeviceIncompatible)?;
+            RAM_BANKS = memory_map.banks;
+            RESERVED_RANGES = memory_map.reserved;
+            #[cfg(feature = "kaslr")]
+            {
+                // a malformed seed leaves the layout unrandomised, rather than stopping boot
+                KASLR_SEED = match device::get_kaslr_seed_early() {
+                    Ok(seed) => seed,
+                    Err(e) => {
+                        error!("kaslr seed: {:?}", e);
+                        None
+                    }
+                };
+            }
         }
         Ok(())
     }
@@ -100,6 +144,37 @@ impl PagerTrait for Arch {
         info!("move_stack: {:?}", stack_pointer);
         hal::move_stack(stack_pointer.get(), next)
     }
+
+    fn switch_address_space(
+        page_directory: &impl crate::archs::PageDirectory,
+        asid: u16,
+    ) -> Result<()> {
+        info!("switch_address_space: {}", asid);
+
+        let page_directory = page_directory
+            .as_any()
+            .downcast_ref::<PageDirectory>()
+            .expect("PageDirectory downcast");
+
+        let ttb0 = page_directory.ttb0().ok_or(Error::UnInitialised)?.get() as u64;
+        hal::set_ttbr0(ttb0, asid)
+    }
+
+    fn invalidate_tlb(virt_addr: VirtAddr) -> Result<()> {
+        hal::invalidate_tlb(virt_addr)
+    }
+
+    fn invalidate_tlb_all() -> Result<()> {
+        hal::invalidate_tlb_all()
+    }
+
+    fn invalidate_tlb_asid(asid: u16) -> Result<()> {
+        hal::invalidate_tlb_asid(asid)
+    }
+
+    fn clean_invalidate_dcache(virt_addr_range: VirtAddrRange) -> Result<()> {
+        hal::clean_invalidate_dcache(virt_addr_range)
+    }
 }
 
 /// Starting level of kernel range.
@@ -193,8 +268,6 @@ impl PageDirectory {
         index: usize,
         page_table_virt_addr_range_base: VirtAddr,
     ) -> VirtAddrRange {
-        const CONTIG_SPAN: usize = 16;
-
         let level_offset = LEVEL_OFFSETS[level as usize];
         let entry_size = 1usize << level_offset;
         let index = index - index % CONTIG_SPAN;
@@ -264,6 +337,14 @@ impl PageDirectory {
                         translation,
                         maybe_output_addr
                     );
+                    let counted = maybe_output_addr
+                        .filter(|_| !attributes.is_set(AttributeField::SuppressMapCount));
+                    // one lock across the check, the entry and the counts, so that another
+                    // mapping of the frame can't be recorded in between
+                    let mut locked = counted.map(|_| allocator.lock());
+                    if let (3, Some(phys_addr), Some(allocator)) = (level, counted, &mut locked) {
+                        allocator.check_page_block_descriptor(phys_addr)?;
+                    }
                     page_table[index] = PageBlockDescriptor::new_entry(
                         level,
                         maybe_output_addr,
@@ -271,14 +352,17 @@ impl PageDirectory {
                         contiguous,
                     )
                     .into();
-                    if let Some(phys_addr) = maybe_output_addr {
-                        if !attributes.is_set(AttributeField::SuppressMapCount) {
-                            let phys_addr_range =
-                                PhysAddrRange::new(phys_addr, entry_range.length());
-                            let mut allocator = allocator.lock();
-                            for phys_addr in phys_addr_range.chunks(PAGESIZE_BYTES) {
-                                allocator.increment_map_count(phys_addr)?;
-                            }
+                    if let (Some(phys_addr), Some(allocator)) = (counted, &mut locked) {
+                        let phys_addr_range = PhysAddrRange::new(phys_addr, entry_range.length());
+                        for phys_addr in phys_addr_range.chunks(PAGESIZE_BYTES) {
+                            allocator.increment_map_count(phys_addr)?;
+                        }
+                        if level == 3 {
+                            allocator.set_page_block_descriptor(
+                                phys_addr,
+                                entry_range.base(),
+                                NonNull::from(&mut page_table[index]).cast(),
+                            )?;
                         }
                     }
                     trace!("{:?}", page_table[index]);
@@ -301,7 +385,7 @@ impl PageDirectory {
                         2 => FramePurpose::LeafPageTable,
                         _ => FramePurpose::BranchPageTable,
                     };
-                    Some(allocator.lock().alloc_zeroed(purpose)?)
+                    Some(FrameAllocator::alloc_zeroed_local(allocator, purpose)?)
                 };
                 dbg!(maybe_phys_addr);
                 let is_kernel = target_range.base() >= Arch::kernel_base();
@@ -327,6 +411,21 @@ impl PageDirectory {
         Ok(target_range)
     }
 
+    /// Find the level 3 entry for a page, if the tables leading to it exist.
+    fn leaf_entry<'a>(
+        &self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &'a FixedOffset,
+    ) -> Result<Option<&'a mut PageTableEntry>> {
+        let virt_addr_range = VirtAddrRange::page_containing(virt_addr);
+        for (level, _, pte) in self.postorder(virt_addr_range, mem_access_translation)? {
+            if level == 3 {
+                return Ok(Some(pte));
+            }
+        }
+        Ok(None)
+    }
+
     fn free_table_if_empty(
         level: u8,
         pte: &mut PageTableEntry,
@@ -351,6 +450,131 @@ impl PageDirectory {
             Ok(false)
         }
     }
+
+    /// Clear a run of entries, freeing the tables beneath them and the frames they map.
+    ///
+    /// No TLB maintenance, as the entries are only reachable through an unused ASID.
+    fn free_entries<'a>(
+        level: u8,
+        entries: impl Iterator<Item = &'a mut PageTableEntry>,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()> {
+        for pte in entries {
+            if let Some(sector) = PageBlockDescriptor::from(*pte).swapped_sector() {
+                allocator.lock().release_swapped(sector)?;
+            } else if pte.is_valid() {
+                let phys_addr = pte.next_level_table_address();
+                if pte.is_table(level) {
+                    let page_table = unsafe {
+                        mem_access_translation
+                            .translate_phys(phys_addr)?
+                            .as_mut_ref::<PageTable>()
+                    };
+                    Self::free_entries(
+                        level + 1,
+                        page_table.iter_mut(),
+                        allocator,
+                        mem_access_translation,
+                    )?;
+                    allocator.lock().free(phys_addr)?;
+                } else if Arch::ram_range().contains(phys_addr) {
+                    let page_block_descriptor = NonNull::from(&mut *pte).cast();
+                    let mut allocator = allocator.lock();
+                    if level == 3 {
+                        allocator.clear_page_block_descriptor(phys_addr, page_block_descriptor)?;
+                    }
+                    allocator.free(phys_addr)?;
+                }
+            }
+            *pte = PageTableEntry::null();
+        }
+        Ok(())
+    }
+
+    /// Replace a valid entry, invalidating it and its TLB entries first.
+    fn break_before_make(
+        pte: &mut PageTableEntry,
+        entry_range: VirtAddrRange,
+        new_pte: PageTableEntry,
+    ) -> Result<()> {
+        *pte = PageTableEntry::null();
+        hal::invalidate_tlb(entry_range.base())?;
+        *pte = new_pte;
+        Ok(())
+    }
+
+    /// Clear the contiguous hint across the whole run containing an entry.
+    fn break_contiguous(entry_range: VirtAddrRange, pte: &mut PageTableEntry) -> Result<()> {
+        if !PageBlockDescriptor::from(*pte).is_contiguous() {
+            return Ok(());
+        }
+        debug!("break_contiguous: {:?}", entry_range);
+        let index = (pte as *mut PageTableEntry as usize % PAGESIZE_BYTES)
+            / core::mem::size_of::<PageTableEntry>();
+        let offset = index % CONTIG_SPAN;
+        let first = unsafe { (pte as *mut PageTableEntry).sub(offset) };
+        let run = unsafe { core::slice::from_raw_parts_mut(first, CONTIG_SPAN) };
+        let base = entry_range.base().decrement(offset * entry_range.length());
+        let saved: [PageTableEntry; CONTIG_SPAN] = {
+            let mut saved = [PageTableEntry::null(); CONTIG_SPAN];
+            saved.copy_from_slice(run);
+            saved
+        };
+        for (i, pte) in run.iter_mut().enumerate() {
+            *pte = PageTableEntry::null();
+            hal::invalidate_tlb(base.increment(i * entry_range.length()))?;
+        }
+        for (pte, old) in run.iter_mut().zip(saved.iter()) {
+            *pte = PageBlockDescriptor::from(*old).without_contiguous().into();
+        }
+        Ok(())
+    }
+
+    /// Replace a block with a table of entries at the next level mapping the same memory.
+    /// Ranges are changed a whole page at a time.
+    fn check_page_aligned(virt_addr_range: VirtAddrRange) -> Result<()> {
+        if !virt_addr_range.is_aligned(PAGESIZE_BYTES) {
+            return Err(Error::UnexpectedValue);
+        }
+        Ok(())
+    }
+
+    fn split_block(
+        level: u8,
+        entry_range: VirtAddrRange,
+        pte: &mut PageTableEntry,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &impl Translate,
+    ) -> Result<()> {
+        debug!("split_block: {} {:?}", level, entry_range);
+        if level >= 3 {
+            // a range can't end part way through a page
+            return Err(Error::UnexpectedValue);
+        }
+        let purpose = match level {
+            2 => FramePurpose::LeafPageTable,
+            _ => FramePurpose::BranchPageTable,
+        };
+        let phys_addr_table = FrameAllocator::alloc_zeroed_local(allocator, purpose)?;
+        let page_table = unsafe {
+            mem_access_translation
+                .translate_phys(phys_addr_table)?
+                .as_mut_ref::<PageTable>()
+        };
+        let block = PageBlockDescriptor::from(*pte);
+        let entry_size = entry_range.length() / TABLE_ENTRIES;
+        for i in 0..TABLE_ENTRIES {
+            let phys_addr = block.output_address().increment(i * entry_size);
+            page_table[i] = block.split(level + 1, phys_addr).into();
+        }
+        let is_kernel = entry_range.base() >= Arch::kernel_base();
+        // the table restricts its pages no more than the block did
+        let table_attributes = Attributes::from(block);
+        let table =
+            TableDescriptor::new_entry(is_kernel, level, Some(phys_addr_table), table_attributes);
+        Self::break_before_make(pte, entry_range, table.into())
+    }
 }
 
 impl crate::archs::PageDirectory for PageDirectory {
@@ -358,6 +582,59 @@ impl crate::archs::PageDirectory for PageDirectory {
         self
     }
 
+    fn new_user(
+        &self,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &impl Translate,
+    ) -> Result<Self> {
+        info!("new_user");
+
+        let ttb0 = FrameAllocator::alloc_zeroed_local(allocator, FramePurpose::BranchPageTable)?;
+        if let Some(kernel_ttb0) = self.ttb0 {
+            let (src, dst) = unsafe {
+                (
+                    mem_access_translation
+                        .translate_phys(kernel_ttb0)?
+                        .as_mut_ref::<PageTable>(),
+                    mem_access_translation
+                        .translate_phys(ttb0)?
+                        .as_mut_ref::<PageTable>(),
+                )
+            };
+            let first_user_index = Arch::user_range().base().get() >> LEVEL_OFFSETS[0];
+            for i in 0..first_user_index {
+                dst[i] = src[i];
+            }
+        }
+        Ok(Self {
+            ttb0: Some(ttb0),
+            ttb1: self.ttb1,
+        })
+    }
+
+    fn free_user(
+        &mut self,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()> {
+        info!("free_user");
+
+        let ttb0 = self.ttb0.take().ok_or(Error::UnInitialised)?;
+        let root = unsafe {
+            mem_access_translation
+                .translate_phys(ttb0)?
+                .as_mut_ref::<PageTable>()
+        };
+        let first_user_index = Arch::user_range().base().get() >> LEVEL_OFFSETS[0];
+        Self::free_entries(
+            TTB0_FIRST_LEVEL,
+            root.iter_mut().skip(first_user_index),
+            allocator,
+            mem_access_translation,
+        )?;
+        allocator.lock().free(ttb0)
+    }
+
     // FIXME: Use page directory walk for map_translation
     fn map_translation(
         &mut self,
@@ -371,6 +648,7 @@ impl crate::archs::PageDirectory for PageDirectory {
             "map_translation(&mut self, va_range: {:?}, {:?}, {:?}, ...)",
             target_range, translation, attributes
         );
+        attributes.validate()?;
 
         if target_range.base() < Arch::kernel_base() {
             self.ttb0 = self
@@ -379,7 +657,9 @@ impl crate::archs::PageDirectory for PageDirectory {
         } else {
             self.ttb1 = self
                 .ttb1
-                .or_else(|| allocator.lock().alloc_zeroed(FramePurpose::Kernel).ok())
+                .or_else(|| {
+                    FrameAllocator::alloc_zeroed_local(allocator, FramePurpose::Kernel).ok()
+                })
         }
 
         let (phys_addr_table, first_level) = self.start_walk(target_range)?;
@@ -428,6 +708,20 @@ impl crate::archs::PageDirectory for PageDirectory {
         Err(Error::SegmentFault)
     }
 
+    fn attributes(
+        &self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<Attributes> {
+        let pte = self
+            .leaf_entry(virt_addr, mem_access_translation)?
+            .ok_or(Error::SegmentFault)?;
+        if !pte.is_valid() {
+            return Err(Error::SegmentFault);
+        }
+        Ok(PageBlockDescriptor::from(*pte).into())
+    }
+
     fn unmap(
         &mut self,
         virt_addr_range: VirtAddrRange,
@@ -439,6 +733,11 @@ impl crate::archs::PageDirectory for PageDirectory {
         for (level, entry_range, pte) in self.postorder(virt_addr_range, mem_access_translation)? {
             dbg!(level);
             dbg!(entry_range);
+            if let Some(sector) = PageBlockDescriptor::from(*pte).swapped_sector() {
+                *pte = PageTableEntry::null();
+                allocator.lock().release_swapped(sector)?;
+                continue;
+            }
             if !pte.is_valid() {
                 return Err(Error::SegmentFault);
             };
@@ -451,16 +750,270 @@ impl crate::archs::PageDirectory for PageDirectory {
             } else {
                 assert!(virt_addr_range.covers(&entry_range));
                 let phys_addr = pte.next_level_table_address();
+                let page_block_descriptor = NonNull::from(&mut *pte).cast();
                 *pte = PageTableEntry::null();
                 hal::invalidate_tlb(entry_range.base())?;
                 if Arch::ram_range().contains(phys_addr) {
-                    allocator.lock().free(phys_addr)?;
+                    let mut allocator = allocator.lock();
+                    if level == 3 {
+                        allocator.clear_page_block_descriptor(phys_addr, page_block_descriptor)?;
+                    }
+                    allocator.free(phys_addr)?;
                 }
             };
         }
         Ok(())
     }
 
+    fn protect(
+        &mut self,
+        virt_addr_range: VirtAddrRange,
+        attributes: Attributes,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()> {
+        info!("protect: {:?} {:?}", virt_addr_range, attributes);
+        attributes.validate()?;
+        Self::check_page_aligned(virt_addr_range)?;
+        for (level, entry_range, pte) in self.preorder(virt_addr_range, mem_access_translation)? {
+            if pte.is_table(level) {
+                continue;
+            }
+            let desc = PageBlockDescriptor::from(*pte);
+            if desc.swapped_sector().is_some() {
+                // not valid, so not cached
+                *pte = desc.with_attributes(level, attributes).into();
+                continue;
+            }
+            if !pte.is_valid() {
+                // mapped on demand, or not at all
+                continue;
+            }
+            Self::break_contiguous(entry_range, pte)?;
+            if !virt_addr_range.covers(&entry_range) {
+                // walk continues into the new table
+                Self::split_block(level, entry_range, pte, allocator, mem_access_translation)?;
+                continue;
+            }
+            let desc = PageBlockDescriptor::from(*pte).with_attributes(level, attributes);
+            Self::break_before_make(pte, entry_range, desc.into())?;
+        }
+        Ok(())
+    }
+
+    fn remap(
+        &mut self,
+        virt_addr_range: VirtAddrRange,
+        translation: impl Translate + core::fmt::Debug,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()> {
+        info!("remap: {:?} {:?}", virt_addr_range, translation);
+        Self::check_page_aligned(virt_addr_range)?;
+        // refuse before changing anything
+        for (level, entry_range, pte) in self.preorder(virt_addr_range, mem_access_translation)? {
+            if pte.is_table(level) {
+                continue;
+            }
+            if PageBlockDescriptor::from(*pte).swapped_sector().is_some() {
+                return Err(Error::SegmentFault);
+            }
+            if !pte.is_valid() {
+                continue;
+            }
+            let covered = virt_addr_range
+                .intersection(&entry_range)
+                .ok_or(Error::UnexpectedValue)?;
+            let mut page = VirtAddrRange::page_containing(covered.base());
+            while page.base() < covered.top() {
+                translation
+                    .translate_maybe(page.base())
+                    .ok_or(Error::SegmentFault)?;
+                page = page.step();
+            }
+        }
+        for (level, entry_range, pte) in self.preorder(virt_addr_range, mem_access_translation)? {
+            if pte.is_table(level) {
+                continue;
+            }
+            if !pte.is_valid() {
+                // mapped on demand, or not at all
+                continue;
+            }
+            let phys_addr = translation
+                .translate_maybe(entry_range.base())
+                .ok_or(Error::SegmentFault)?;
+            Self::break_contiguous(entry_range, pte)?;
+            if !virt_addr_range.covers(&entry_range) || !phys_addr.is_aligned(entry_range.length())
+            {
+                // walk continues into the new table
+                Self::split_block(level, entry_range, pte, allocator, mem_access_translation)?;
+                continue;
+            }
+            let desc = PageBlockDescriptor::from(*pte);
+            let attributes = Attributes::from(desc);
+            let old_phys_addr_range =
+                PhysAddrRange::new(desc.output_address(), entry_range.length());
+            let phys_addr_range = PhysAddrRange::new(phys_addr, entry_range.length());
+            // a private copy of a copy-on-write frame is counted, even if the shared one wasn't
+            let old_counted = !attributes.is_set(AttributeField::SuppressMapCount);
+            let counted = old_counted || attributes.is_set(AttributeField::CopyOnWrite);
+            // one lock across the check, the entry and the counts, so that another
+            // mapping of the frame can't be recorded in between
+            let mut allocator = allocator.lock();
+            if counted && level == 3 && Arch::ram_range().covers(&phys_addr_range) {
+                allocator.check_page_block_descriptor(phys_addr)?;
+            }
+            let mut new_desc = desc.with_output_address(phys_addr);
+            if counted {
+                new_desc = new_desc.counted();
+            }
+            Self::break_before_make(pte, entry_range, new_desc.into())?;
+            let page_block_descriptor = NonNull::from(pte).cast();
+
+            if counted && Arch::ram_range().covers(&phys_addr_range) {
+                for phys_addr in phys_addr_range.chunks(PAGESIZE_BYTES) {
+                    allocator.increment_map_count(phys_addr)?;
+                }
+                if level == 3 {
+                    allocator.set_page_block_descriptor(
+                        phys_addr,
+                        entry_range.base(),
+                        page_block_descriptor,
+                    )?;
+                }
+            }
+            if old_counted && Arch::ram_range().covers(&old_phys_addr_range) {
+                if level == 3 {
+                    allocator.clear_page_block_descriptor(
+                        old_phys_addr_range.base(),
+                        page_block_descriptor,
+                    )?;
+                }
+                for phys_addr in old_phys_addr_range.chunks(PAGESIZE_BYTES) {
+                    allocator.free(phys_addr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn swapped_out(
+        &self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<Option<NonZeroU64>> {
+        Ok(self
+            .leaf_entry(virt_addr, mem_access_translation)?
+            .and_then(|pte| PageBlockDescriptor::from(*pte).swapped_sector()))
+    }
+
+    fn swap_in(
+        &mut self,
+        virt_addr: VirtAddr,
+        phys_addr: PhysAddr,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()> {
+        info!("swap_in: {:?} <- {:?}", virt_addr, phys_addr);
+        let pte = self
+            .leaf_entry(virt_addr, mem_access_translation)?
+            .ok_or(Error::SegmentFault)?;
+        let mut desc = PageBlockDescriptor::from(*pte);
+        if desc.swapped_sector().is_none() {
+            return Err(Error::UnexpectedValue);
+        }
+        // No need to invalidate TLB because invalid entries are not cached
+        desc.swap_in(phys_addr);
+        // reloaded page matches its slot
+        desc.clear_dirty();
+        *pte = desc.into();
+        let mut allocator = allocator.lock();
+        allocator.increment_map_count(phys_addr)?;
+        allocator.set_page_block_descriptor(
+            phys_addr,
+            virt_addr.page_base(),
+            NonNull::from(pte).cast(),
+        )
+    }
+
+    fn map_swapped(
+        &mut self,
+        virt_addr: VirtAddr,
+        sector: NonZeroU64,
+        attributes: Attributes,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()> {
+        info!("map_swapped: {:?} <- {}", virt_addr, sector);
+        let page = VirtAddrRange::page_containing(virt_addr);
+        self.map_translation(
+            page,
+            NullTranslation::new(),
+            attributes,
+            allocator,
+            mem_access_translation,
+        )?;
+        let pte = self
+            .leaf_entry(virt_addr, mem_access_translation)?
+            .ok_or(Error::SegmentFault)?;
+        let mut desc = PageBlockDescriptor::from(*pte);
+        desc.swap_out(sector);
+        *pte = desc.into();
+        Ok(())
+    }
+
+    fn set_accessed(
+        &mut self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<PhysAddr> {
+        info!("set_accessed: {:?}", virt_addr);
+        let pte = self
+            .leaf_entry(virt_addr, mem_access_translation)?
+            .ok_or(Error::SegmentFault)?;
+        if !pte.is_valid() {
+            return Err(Error::SegmentFault);
+        }
+        // No need to invalidate TLB because entries without AF are not cached
+        let mut desc = PageBlockDescriptor::from(*pte);
+        desc.set_accessed();
+        *pte = desc.into();
+        Ok(desc.output_address())
+    }
+
+    fn set_dirty(
+        &mut self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<bool> {
+        info!("set_dirty: {:?}", virt_addr);
+        let pte = self
+            .leaf_entry(virt_addr, mem_access_translation)?
+            .ok_or(Error::SegmentFault)?;
+        if !pte.is_valid() {
+            return Err(Error::SegmentFault);
+        }
+        let mut desc = PageBlockDescriptor::from(*pte);
+        if !desc.set_dirty() {
+            return Ok(false);
+        }
+        *pte = desc.into();
+        hal::invalidate_tlb(virt_addr)?;
+        Ok(true)
+    }
+
+    type Mappings<'a> = MappingWalk<'a>;
+
+    fn mappings<'a>(
+        &'a self,
+        virt_addr_range: VirtAddrRange,
+        mem_access_translation: &'a FixedOffset,
+    ) -> Result<Self::Mappings<'a>> {
+        let walk = self.preorder(virt_addr_range, mem_access_translation)?;
+        Ok(MappingWalk::new(walk, virt_addr_range))
+    }
+
     // FIXME: Use page directory walk for dump
     #[allow(dead_code)]
     fn dump(&self, mem_access_translation: &impl Translate) {
@@ -589,7 +1142,7 @@ fn make_boot_ram_descriptor() -> u64 {
     .get()
 }
 
-pub const BOOT_RAM_DESCRIPTOR: u64 = 0x40000040000605;
+pub const BOOT_RAM_DESCRIPTOR: u64 = 0x40000040000709;
 
 /// Create a level 1 block descriptor to map the device
 ///
@@ -605,7 +1158,7 @@ fn make_boot_device_descriptor() -> u64 {
     PageBlockDescriptor::new_entry(level, Some(phys_addr), Attributes::DEVICE, contiguous).get()
 }
 
-pub const BOOT_DEVICE_DESCRIPTOR: u64 = 0x60000000000601;
+pub const BOOT_DEVICE_DESCRIPTOR: u64 = 0x260000000000601;
 
 /// Iterator over the virtual address ranges implied by entries in a page table.
 struct PageTableEntries {
@@ -829,6 +1382,193 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_protect_and_remap() {
+        use table::PageBlockDescriptorFields::AP;
+
+        let mut page_dir = super::PageDirectory::new();
+        let base = Arch::kernel_base();
+        let target_range = VirtAddrRange::new(base, 0x20_0000);
+        let translation = FixedOffset::new(PhysAddr::null(), base);
+        let allocator = Locked::new(TestAllocator::new(3));
+        let mem_access_translation = FixedOffset::identity();
+
+        assert_ok!(page_dir.map_translation(
+            target_range,
+            translation,
+            Attributes::RAM,
+            &allocator,
+            &mem_access_translation,
+        ));
+
+        // refused before any block is split
+        let straddle = VirtAddrRange::new(base.increment(0x800), 0x1000);
+        assert_err!(page_dir.protect(
+            straddle,
+            Attributes::KERNEL_RO_DATA,
+            &allocator,
+            &mem_access_translation,
+        ));
+        assert_err!(page_dir.remap(straddle, translation, &allocator, &mem_access_translation));
+
+        // splits the 2MB block
+        let page = VirtAddrRange::new(base.increment(0x1000), 0x1000);
+        assert_ok!(page_dir.protect(
+            page,
+            Attributes::KERNEL_RO_DATA,
+            &allocator,
+            &mem_access_translation,
+        ));
+        let mut pages = 0;
+        for (level, entry_range, pte) in page_dir
+            .preorder(target_range, &mem_access_translation)
+            .unwrap()
+        {
+            if level != 3 {
+                continue;
+            }
+            let desc = PageBlockDescriptor::from(*pte);
+            assert_eq!(
+                PhysAddr::at(entry_range.base().offset_above(base)),
+                desc.output_address()
+            );
+            let expected = if entry_range == page {
+                AP::PrivReadOnly
+            } else {
+                AP::PrivOnly
+            };
+            assert_eq!(expected.value, desc.read(AP));
+            pages += 1;
+        }
+        assert_eq!(TABLE_ENTRIES, pages);
+
+        let page = page.step();
+        let translation = FixedOffset::new(PhysAddr::at(0x10_0000), page.base());
+        assert_ok!(page_dir.remap(page, translation, &allocator, &mem_access_translation));
+        assert_ok_eq!(
+            page_dir.maps_to(page.base(), &mem_access_translation),
+            PhysAddr::at(0x10_0000)
+        );
+    }
+
+    #[test]
+    fn test_remap_uncounted() {
+        unsafe { RAM_RANGE = PhysAddrRange::new(PhysAddr::at(0x40000000), 0x4000000) };
+        let mut page_dir = super::PageDirectory::new();
+        let base = Arch::kernel_base();
+        let target_range = VirtAddrRange::new(base, 0x20_0000);
+        let translation = FixedOffset::new(Arch::ram_range().base(), base);
+        // counting would panic, as the allocator doesn't keep map counts
+        let allocator = Locked::new(TestAllocator::new(3));
+        let mem_access_translation = FixedOffset::identity();
+
+        assert_ok!(page_dir.map_translation(
+            target_range,
+            translation,
+            Attributes::RAM,
+            &allocator,
+            &mem_access_translation,
+        ));
+        let page = VirtAddrRange::new(base.increment(0x1000), 0x1000);
+        let phys_addr = Arch::ram_range().base().increment(0x10_0000);
+        let translation = FixedOffset::new(phys_addr, page.base());
+        assert_ok!(page_dir.remap(page, translation, &allocator, &mem_access_translation));
+        assert_ok_eq!(
+            page_dir.maps_to(page.base(), &mem_access_translation),
+            phys_addr
+        );
+        let attributes = page_dir
+            .attributes(page.base(), &mem_access_translation)
+            .unwrap();
+        assert!(attributes.is_set(AttributeField::SuppressMapCount));
+    }
+
+    #[test]
+    fn test_mappings() {
+        let mut page_dir = super::PageDirectory::new();
+        let base = Arch::kernel_base();
+        let target_range = VirtAddrRange::new(base, 0x20_0000);
+        let translation = FixedOffset::new(PhysAddr::null(), base);
+        let allocator = Locked::new(TestAllocator::new(3));
+        let mem_access_translation = FixedOffset::identity();
+
+        assert_ok!(page_dir.map_translation(
+            target_range,
+            translation,
+            Attributes::RAM,
+            &allocator,
+            &mem_access_translation,
+        ));
+        let mut mappings = page_dir
+            .mappings(target_range, &mem_access_translation)
+            .unwrap();
+        let (virt_addr_range, phys_addr_range, _, level) = mappings.next().unwrap();
+        assert_eq!(target_range, virt_addr_range);
+        assert_some_eq!(phys_addr_range, PhysAddrRange::new(PhysAddr::null(), 0x20_0000));
+        assert_eq!(2, level);
+        assert_none!(mappings.next());
+
+        let page = VirtAddrRange::new(base.increment(0x1000), 0x1000);
+        assert_ok!(page_dir.protect(
+            page,
+            Attributes::KERNEL_RO_DATA,
+            &allocator,
+            &mem_access_translation,
+        ));
+        let mappings = page_dir
+            .mappings(target_range, &mem_access_translation)
+            .unwrap();
+        let lengths = mappings.map(|(virt_addr_range, phys_addr_range, _, level)| {
+            assert_eq!(3, level);
+            assert_eq!(
+                phys_addr_range.unwrap().base(),
+                PhysAddr::at(virt_addr_range.base().offset_above(base))
+            );
+            virt_addr_range.length()
+        });
+        assert!(lengths.eq([0x1000, 0x1000, 0x1f_e000].iter().copied()));
+
+        // clipped to the range
+        let straddle = VirtAddrRange::new(base.increment(0x800), 0x1000);
+        let mut mappings = page_dir
+            .mappings(straddle, &mem_access_translation)
+            .unwrap();
+        let (first, _, attributes, _) = mappings.next().unwrap();
+        assert_eq!(VirtAddrRange::new(base.increment(0x800), 0x800), first);
+        assert!(attributes.is_set(AttributeField::KernelWrite));
+        let (second, _, attributes, _) = mappings.next().unwrap();
+        assert_eq!(page.resize(0x800), second);
+        assert!(!attributes.is_set(AttributeField::KernelWrite));
+        assert_none!(mappings.next());
+    }
+
+    #[test]
+    fn test_map_swapped() {
+        let mut page_dir = super::PageDirectory::new();
+        let page = VirtAddrRange::new(Arch::kernel_base(), 0x1000);
+        let sector = NonZeroU64::new(8).unwrap();
+        let allocator = Locked::new(TestAllocator::new(3));
+        let mem_access_translation = FixedOffset::identity();
+
+        assert_ok!(page_dir.map_swapped(
+            page.base(),
+            sector,
+            Attributes::KERNEL_DATA,
+            &allocator,
+            &mem_access_translation,
+        ));
+        assert_ok_eq!(
+            page_dir.swapped_out(page.base(), &mem_access_translation),
+            Some(sector)
+        );
+        assert_err!(page_dir.maps_to(page.base(), &mem_access_translation));
+        let mut mappings = page_dir.mappings(page, &mem_access_translation).unwrap();
+        let (virt_addr_range, phys_addr_range, attributes, _) = mappings.next().unwrap();
+        assert_eq!(page, virt_addr_range);
+        assert_none!(phys_addr_range);
+        assert!(attributes.is_set(AttributeField::KernelWrite));
+    }
+
     #[test]
     fn test_boot_descriptors() {
         unsafe { RAM_RANGE = PhysAddrRange::new(PhysAddr::at(0x40000000), 0x4000000) };
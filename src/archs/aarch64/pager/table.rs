@@ -7,8 +7,9 @@ use crate::util::bitfield::{register_bitfields, Bitfield};
 
 use core::fmt::{Debug, Formatter};
 use core::mem;
+use core::num::NonZeroU64;
 use core::ops::{Index, IndexMut};
-use core::slice::Iter;
+use core::slice::{Iter, IterMut};
 
 use tock_registers::fields::FieldValue;
 
@@ -16,6 +17,7 @@ pub type PageTableEntryType = u64;
 
 /// An entry in one of the levels of an ARMv8 page table.
 #[derive(Copy, Clone)]
+#[repr(transparent)]
 pub struct PageTableEntry(PageTableEntryType);
 
 impl Debug for PageTableEntry {
@@ -83,6 +85,9 @@ impl PageTable {
     pub(crate) fn iter(&self) -> Iter<PageTableEntry> {
         self.0.iter()
     }
+    pub(crate) fn iter_mut(&mut self) -> IterMut<PageTableEntry> {
+        self.0.iter_mut()
+    }
     #[cfg(test)]
     pub fn new() -> Self {
         PageTable([PageTableEntry::null(); TABLE_ENTRIES])
@@ -234,10 +239,13 @@ register_bitfields! {
     u64,
     pub PageBlockDescriptorFields [
         Available OFFSET(55) NUMBITS(9) [],
+        Swapped OFFSET(55) NUMBITS(1) [],                  // Software: OutputAddress is swap sector
+        COW OFFSET(56) NUMBITS(1) [],                      // Software: copy frame on write
+        Uncounted OFFSET(57) NUMBITS(1) [],                // Software: frame map count not kept
         UXN OFFSET(54) NUMBITS(1) [],                      // Unprivileged Execute Never
         PXN OFFSET(53) NUMBITS(1) [],                      // Privileged Execute Never
         Contiguous OFFSET(52) NUMBITS(1) [],               // One of a contiguous set of entries
-        Dirty OFFSET(51) NUMBITS(1) [],                    // Dirty (DBM?)
+        Dirty OFFSET(51) NUMBITS(1) [],                    // Dirty bit modifier: clean if AP2 set
         OutputAddress OFFSET(12) NUMBITS(35) [],
         nG OFFSET(11) NUMBITS(1) [],                       // Not Global - all or current ASID
         AF OFFSET(10) NUMBITS(1) [],                       // Access flag
@@ -246,6 +254,7 @@ register_bitfields! {
             OuterShareable = 0b10,
             InnerShareable = 0b11
         ],
+        AP2 OFFSET(7) NUMBITS(1) [],                       // Read-only half of AP
         AP OFFSET(6) NUMBITS(2) [                          // Data access permissions
             PrivOnly = 0b00,
             ReadWrite = 0b01,
@@ -286,6 +295,9 @@ impl Debug for PageBlockDescriptor {
         if self.is_set(AF) {
             write!(f, " AF")?;
         }
+        if self.is_set(COW) {
+            write!(f, " CoW")?;
+        }
         match self.read_as_enum(SH) {
             Some(SH::Value::NonShareable) => write!(f, " NonShareable")?,
             Some(SH::Value::OuterShareable) => write!(f, " OuterShareable")?,
@@ -315,9 +327,17 @@ impl From<Attributes> for PageBlockDescriptorMask {
             (false, KernelExec, PXN::SET),
             (true, Device, AF::SET),
             (true, Accessed, AF::SET),
+            (true, CopyOnWrite, COW::SET),
+            (true, SuppressMapCount, Uncounted::SET),
         ];
 
-        let mut result = Self::from(SH::OuterShareable);
+        let mair = MAIR::from(attributes);
+        let mut result = AttrIndx.val(mair as u64);
+        result += if mair.is_cacheable() {
+            SH::InnerShareable
+        } else {
+            SH::OuterShareable
+        };
         for (agree, attribute, field) in ATTRIBUTE_FIELD_MAP {
             if *agree == (attributes.is_set(*attribute)) {
                 result += *field;
@@ -349,16 +369,65 @@ impl From<Attributes> for PageBlockDescriptorMask {
             x => panic!("{:?}", x),
         }
 
-        if attributes.is_set(Device) {
-            result += AttrIndx.val(MAIR::DeviceStronglyOrdered as u64);
-        } else {
-            result += AttrIndx.val(MAIR::MemoryWriteThrough as u64);
+        // user pages belong to one address space, so are tagged with its ASID
+        if attributes.is_set(UserRead)
+            || attributes.is_set(UserWrite)
+            || attributes.is_set(UserExec)
+        {
+            result += nG::SET;
         }
 
         result
     }
 }
 
+impl From<PageBlockDescriptor> for Attributes {
+    fn from(desc: PageBlockDescriptor) -> Self {
+        use super::mair::MAIR;
+        use AttributeField::*;
+        use PageBlockDescriptorFields::*;
+
+        // a clean page is read-only until its first write
+        let mut permissions = desc;
+        if desc.is_set(Dirty) {
+            permissions.modify(AP2::CLEAR);
+        }
+        let mut result = match permissions.read_as_enum(AP) {
+            Some(AP::Value::PrivOnly) => Attributes::new().set(KernelRead).set(KernelWrite),
+            Some(AP::Value::ReadWrite) => Attributes::new()
+                .set(KernelRead)
+                .set(KernelWrite)
+                .set(UserRead)
+                .set(UserWrite),
+            Some(AP::Value::PrivReadOnly) => Attributes::new().set(KernelRead),
+            Some(AP::Value::ReadOnly) => Attributes::new().set(KernelRead).set(UserRead),
+            None => unreachable!(),
+        };
+        if !desc.is_set(UXN) {
+            result = result.set(UserExec);
+        }
+        if !desc.is_set(PXN) {
+            result = result.set(KernelExec);
+        }
+        if desc.is_set(AF) {
+            result = result.set(Accessed);
+        }
+        if desc.is_set(COW) {
+            result = result.set(CopyOnWrite);
+        }
+        if desc.is_set(Uncounted) {
+            result = result.set(SuppressMapCount);
+        }
+        let memory_type = MAIR::from(desc.read(AttrIndx)).attributes();
+        for field in &[Device, StreamIn, StreamOut] {
+            if memory_type.is_set(*field) {
+                result = result.set(*field);
+            }
+        }
+        result
+    }
+}
+
 impl PageBlockDescriptor {
     /// Create a new page block descriptor entry from attributes.
     pub fn new_entry(
@@ -378,6 +447,13 @@ impl PageBlockDescriptor {
         }
         if level == 3 {
             field += Type::Page;
+            // only pages track whether they have been written
+            if (attributes.is_set(AttributeField::KernelWrite)
+                || attributes.is_set(AttributeField::UserWrite))
+                && !attributes.is_set(AttributeField::Device)
+            {
+                field += Dirty::SET;
+            }
         }
         Self::from(field)
     }
@@ -396,6 +472,141 @@ impl PageBlockDescriptor {
             (self.read(PageBlockDescriptorFields::OutputAddress) * PAGESIZE_BYTES as u64) as usize,
         )
     }
+
+    /// Invalidate the entry, keeping its attributes, and record the sector holding the page.
+    pub fn swap_out(&mut self, sector: NonZeroU64) {
+        use PageBlockDescriptorFields::*;
+        self.modify(Valid::CLEAR + Swapped::SET + OutputAddress.val(sector.get()));
+    }
+
+    /// The sector holding the page, if the entry has been swapped out.
+    pub fn swapped_sector(&self) -> Option<NonZeroU64> {
+        use PageBlockDescriptorFields::*;
+        if self.is_set(Valid) || !self.is_set(Swapped) {
+            return None;
+        }
+        NonZeroU64::new(self.read(OutputAddress))
+    }
+
+    /// Invalidate the entry and forget its attributes, so the page is mapped afresh on next access.
+    pub fn unmap(&mut self) {
+        *self = Self::new(0);
+    }
+
+    /// Point a swapped-out entry at the frame the page has been reloaded into.
+    pub fn swap_in(&mut self, phys_addr: PhysAddr) {
+        use PageBlockDescriptorFields::*;
+        self.modify(
+            Swapped::CLEAR
+                + AF::SET
+                + Valid::SET
+                + OutputAddress.val(phys_addr.page() as PageTableEntryType),
+        );
+    }
+
+    /// The same mapping with new attributes, keeping the access, dirty, global, swap and map
+    /// count state.
+    pub fn with_attributes(self, level: u8, attributes: Attributes) -> Self {
+        use PageBlockDescriptorFields::*;
+        let clean = self.is_set(Dirty) && !self.is_dirty();
+        if let Some(sector) = self.swapped_sector() {
+            let mut result = Self::new_entry(level, None, attributes, false);
+            result.swap_out(sector);
+            if clean {
+                result.clear_dirty();
+            }
+            return result;
+        }
+        let mut result = Self::new_entry(level, Some(self.output_address()), attributes, false);
+        if clean {
+            result.clear_dirty();
+        }
+        if self.is_set(AF) {
+            result.modify(AF::SET);
+        }
+        if self.is_set(nG) {
+            result.modify(nG::SET);
+        }
+        // the frame's map count was kept, or not, when it was mapped
+        result.modify(Uncounted.val(self.read(Uncounted)));
+        result
+    }
+
+    /// The same mapping pointing at a different frame.
+    pub fn with_output_address(mut self, phys_addr: PhysAddr) -> Self {
+        use PageBlockDescriptorFields::*;
+        self.modify(OutputAddress.val(phys_addr.page() as PageTableEntryType));
+        self
+    }
+
+    /// The same mapping, with the map count of its frame kept.
+    pub fn counted(mut self) -> Self {
+        self.modify(PageBlockDescriptorFields::Uncounted::CLEAR);
+        self
+    }
+
+    /// An entry for part of this block in a table at the next level.
+    pub fn split(self, level: u8, phys_addr: PhysAddr) -> Self {
+        use PageBlockDescriptorFields::*;
+        let mut result = self.without_contiguous().with_output_address(phys_addr);
+        if level == 3 {
+            result.modify(Type::Page);
+        }
+        result
+    }
+
+    /// Whether the entry is one of a contiguous run.
+    pub fn is_contiguous(&self) -> bool {
+        self.is_set(PageBlockDescriptorFields::Contiguous)
+    }
+
+    /// The same mapping without the contiguous hint.
+    pub fn without_contiguous(mut self) -> Self {
+        self.modify(PageBlockDescriptorFields::Contiguous::CLEAR);
+        self
+    }
+
+    /// Whether the page has been accessed since the flag was last cleared.
+    pub fn is_accessed(&self) -> bool {
+        self.is_set(PageBlockDescriptorFields::AF)
+    }
+
+    /// Record an access, so that the next access does not fault.
+    pub fn set_accessed(&mut self) {
+        self.modify(PageBlockDescriptorFields::AF::SET);
+    }
+
+    /// Cause the next access to fault, so it can be recorded.
+    pub fn clear_accessed(&mut self) {
+        self.modify(PageBlockDescriptorFields::AF::CLEAR);
+    }
+
+    /// Whether the page may have been written since it was last cleaned.
+    ///
+    /// Writable pages which do not track their dirty state are always dirty.
+    pub fn is_dirty(&self) -> bool {
+        !self.is_set(PageBlockDescriptorFields::AP2)
+    }
+
+    /// Allow writes to a clean page whose first write has faulted.
+    ///
+    /// Returns false if the page does not track its dirty state.
+    pub fn set_dirty(&mut self) -> bool {
+        use PageBlockDescriptorFields::*;
+        if !self.is_set(Dirty) || !self.is_set(AP2) {
+            return false;
+        }
+        self.modify(AP2::CLEAR);
+        true
+    }
+
+    /// Make a tracked page read-only, so that the next write faults and marks it dirty.
+    pub fn clear_dirty(&mut self) {
+        use PageBlockDescriptorFields::*;
+        if self.is_set(Dirty) {
+            self.modify(AP2::SET);
+        }
+    }
 }
 
 impl From<PageBlockDescriptor> for PageTableEntry {
@@ -494,6 +705,195 @@ mod tests {
         assert_eq!(0x78000000000e01, result.get())
     }
 
+    #[test]
+    fn test_swapped_desc() {
+        let attributes = Attributes::USER_DATA | AttributeField::Accessed;
+        let phys_addr = PhysAddr::at(0x1234_9000);
+        let mut desc = PageBlockDescriptor::new_entry(3, Some(phys_addr), attributes, false);
+        let original = desc.get();
+        assert_none!(desc.swapped_sector());
+
+        let sector = NonZeroU64::new(0x48).unwrap();
+        desc.swap_out(sector);
+        assert!(!PageTableEntry::from(desc).is_valid());
+        assert_some_eq!(desc.swapped_sector(), sector);
+
+        desc.swap_in(phys_addr);
+        assert_none!(desc.swapped_sector());
+        assert_eq!(original, desc.get());
+    }
+
+    #[test]
+    fn test_protect_desc() {
+        use PageBlockDescriptorFields::*;
+
+        let read_only = Attributes::USER_RO_DATA | AttributeField::KernelRead;
+        let phys_addr = PhysAddr::at(0x1234_9000);
+        let desc = PageBlockDescriptor::new_entry(3, Some(phys_addr), Attributes::USER_DATA, true);
+        let result = desc.with_attributes(3, read_only);
+        assert_eq!(result.read(AP), AP::ReadOnly.value);
+        assert_eq!(result.output_address(), phys_addr);
+        assert!(!result.is_contiguous());
+        assert!(!result.is_accessed());
+
+        let sector = NonZeroU64::new(0x48).unwrap();
+        let mut swapped = desc;
+        swapped.swap_out(sector);
+        let result = swapped.with_attributes(3, read_only);
+        assert_some_eq!(result.swapped_sector(), sector);
+        assert_eq!(result.read(AP), AP::ReadOnly.value);
+    }
+
+    #[test]
+    fn test_not_global_desc() {
+        use PageBlockDescriptorFields::*;
+
+        let phys_addr = PhysAddr::at(0x1234_9000);
+        let desc = PageBlockDescriptor::new_entry(3, Some(phys_addr), Attributes::USER_DATA, false);
+        assert!(desc.is_set(nG));
+        let desc = PageBlockDescriptor::new_entry(3, Some(phys_addr), Attributes::USER_EXEC, false);
+        assert!(desc.is_set(nG));
+        let desc =
+            PageBlockDescriptor::new_entry(3, Some(phys_addr), Attributes::KERNEL_DATA, false);
+        assert!(!desc.is_set(nG));
+        assert!(!Attributes::from(desc).is_set(AttributeField::UserRead));
+    }
+
+    #[test]
+    fn test_memory_types() {
+        use super::super::mair::MAIR;
+        use AttributeField::{Device, StreamIn, StreamOut};
+        use PageBlockDescriptorFields::*;
+
+        let phys_addr = PhysAddr::at(0x1234_9000);
+        let expected = [
+            (Attributes::RAM, MAIR::MemoryWriteBack, SH::InnerShareable),
+            (Attributes::KERNEL_DATA, MAIR::MemoryWriteBack, SH::InnerShareable),
+            (
+                Attributes::KERNEL_DATA | StreamOut,
+                MAIR::MemoryWriteThrough,
+                SH::InnerShareable,
+            ),
+            (Attributes::DMA, MAIR::MemoryNonCacheable, SH::OuterShareable),
+            (
+                Attributes::KERNEL_DATA | StreamIn,
+                MAIR::MemoryNonCacheableIn,
+                SH::OuterShareable,
+            ),
+            (Attributes::DEVICE, MAIR::DeviceStronglyOrdered, SH::OuterShareable),
+            (
+                Attributes::DEVICE | StreamOut,
+                MAIR::DeviceEarlyWriteAck,
+                SH::OuterShareable,
+            ),
+            (
+                Attributes::DEVICE | StreamIn,
+                MAIR::DeviceReordering,
+                SH::OuterShareable,
+            ),
+            (
+                Attributes::DEVICE | StreamIn | StreamOut,
+                MAIR::DeviceGathering,
+                SH::OuterShareable,
+            ),
+        ];
+        for (attributes, mair, shareability) in expected.iter() {
+            let desc = PageBlockDescriptor::new_entry(3, Some(phys_addr), *attributes, false);
+            assert_eq!(desc.read(AttrIndx), *mair as u64);
+            assert_eq!(desc.read(SH), shareability.value);
+            let result = Attributes::from(desc);
+            for field in &[Device, StreamIn, StreamOut] {
+                assert_eq!(result.is_set(*field), attributes.is_set(*field));
+            }
+        }
+    }
+
+    #[test]
+    fn test_desc_attributes() {
+        let attributes = Attributes::KERNEL_ZERO;
+        let desc =
+            PageBlockDescriptor::new_entry(3, Some(PhysAddr::at(0x1234_9000)), attributes, false);
+        let result = Attributes::from(desc);
+        assert!(result.is_set(AttributeField::CopyOnWrite));
+        assert!(result.is_set(AttributeField::SuppressMapCount));
+        assert!(result.is_set(AttributeField::KernelRead));
+        assert!(!result.is_set(AttributeField::KernelWrite));
+        assert!(!result.is_set(AttributeField::KernelExec));
+        assert!(!result.is_set(AttributeField::Device));
+
+        let result = Attributes::from(desc.with_attributes(3, Attributes::KERNEL_DATA));
+        assert!(!result.is_set(AttributeField::CopyOnWrite));
+        assert!(result.is_set(AttributeField::KernelWrite));
+        assert!(result.is_set(AttributeField::Accessed));
+        assert!(result.is_set(AttributeField::SuppressMapCount));
+        let result = Attributes::from(desc.counted().with_attributes(3, Attributes::KERNEL_DATA));
+        assert!(!result.is_set(AttributeField::SuppressMapCount));
+    }
+
+    #[test]
+    fn test_split_desc() {
+        use PageBlockDescriptorFields::*;
+
+        let attributes = Attributes::KERNEL_DATA | AttributeField::Accessed;
+        let block =
+            PageBlockDescriptor::new_entry(2, Some(PhysAddr::at(0x4020_0000)), attributes, true);
+        assert_eq!(block.read(Type), Type::Block.value);
+        let page = block.split(3, PhysAddr::at(0x4020_1000));
+        assert_eq!(page.read(Type), Type::Page.value);
+        assert_eq!(page.output_address(), PhysAddr::at(0x4020_1000));
+        assert!(!page.is_contiguous());
+        assert_eq!(page.read(AP), block.read(AP));
+    }
+
+    #[test]
+    fn test_accessed_desc() {
+        let attributes = Attributes::USER_DATA | AttributeField::Accessed;
+        let mut desc =
+            PageBlockDescriptor::new_entry(3, Some(PhysAddr::at(0x1234_9000)), attributes, false);
+        let original = desc.get();
+        assert!(desc.is_accessed());
+        desc.clear_accessed();
+        assert!(!desc.is_accessed());
+        assert!(PageTableEntry::from(desc).is_valid());
+        desc.set_accessed();
+        assert_eq!(original, desc.get());
+    }
+
+    #[test]
+    fn test_dirty_desc() {
+        let phys_addr = PhysAddr::at(0x1234_9000);
+        let mut desc =
+            PageBlockDescriptor::new_entry(3, Some(phys_addr), Attributes::USER_DATA, false);
+        let original = desc.get();
+        assert!(desc.is_dirty());
+        assert!(!desc.set_dirty());
+
+        desc.clear_dirty();
+        assert!(!desc.is_dirty());
+        let attributes = Attributes::from(desc);
+        assert!(attributes.is_set(AttributeField::UserWrite));
+        assert!(attributes.is_set(AttributeField::KernelWrite));
+        assert!(!desc.with_attributes(3, Attributes::USER_DATA).is_dirty());
+
+        assert!(desc.set_dirty());
+        assert!(desc.is_dirty());
+        assert_eq!(original, desc.get());
+
+        // read-only pages and blocks are not tracked
+        let mut desc =
+            PageBlockDescriptor::new_entry(3, Some(phys_addr), Attributes::USER_RO_DATA, false);
+        desc.clear_dirty();
+        assert!(!desc.set_dirty());
+        let mut block = PageBlockDescriptor::new_entry(
+            2,
+            Some(PhysAddr::at(0x4020_0000)),
+            Attributes::KERNEL_DATA,
+            false,
+        );
+        block.clear_dirty();
+        assert!(block.is_dirty());
+    }
+
     #[test]
     fn test_demand_page_desc() {
         use PageBlockDescriptorFields::*;
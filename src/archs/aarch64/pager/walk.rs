@@ -4,9 +4,16 @@
 //!
 //! Cannot use heap, because this is used before kernel heap is mapped.
 
-use super::{PageTable, PageTableEntry, LEVEL_OFFSETS, LEVEL_WIDTH, MAX_LEVELS, TABLE_ENTRIES};
+use super::{
+    PageBlockDescriptor, PageTable, PageTableEntry, LEVEL_OFFSETS, LEVEL_WIDTH, MAX_LEVELS,
+    TABLE_ENTRIES,
+};
 
-use crate::pager::{Addr, AddrRange, FixedOffset, PhysAddr, Translate, VirtAddr, VirtAddrRange};
+use crate::archs::Mapping;
+use crate::pager::{
+    Addr, AddrRange, Attributes, FixedOffset, PhysAddr, PhysAddrRange, Translate, VirtAddr,
+    VirtAddrRange,
+};
 
 use core::ptr::NonNull;
 
@@ -291,6 +298,107 @@ impl PageDirectoryWalk<'_> {
     }
 }
 
+/// Iterate over the leaf entries of a preorder walk, coalescing runs which map alike.
+pub struct MappingWalk<'a> {
+    walk: PageDirectoryWalk<'a>,
+    target_range: VirtAddrRange,
+    pending: Option<Mapping>, // run being extended
+}
+
+impl<'a> MappingWalk<'a> {
+    pub fn new(walk: PageDirectoryWalk<'a>, target_range: VirtAddrRange) -> Self {
+        Self {
+            walk,
+            target_range,
+            pending: None,
+        }
+    }
+
+    /// The part of a leaf entry within the target range, unless the entry maps nothing.
+    fn mapping(
+        &self,
+        level: u8,
+        entry_range: VirtAddrRange,
+        pte: &PageTableEntry,
+    ) -> Option<Mapping> {
+        if pte.is_valid() && pte.is_table(level) {
+            return None;
+        }
+        let desc = PageBlockDescriptor::from(*pte);
+        if !pte.is_valid() && desc.swapped_sector().is_none() {
+            return None;
+        }
+        let virt_addr_range = entry_range
+            .intersection(&self.target_range)
+            .filter(|range| range.length() > 0)?;
+        let phys_addr_range = if pte.is_valid() {
+            let offset = virt_addr_range.base().offset_above(entry_range.base());
+            Some(PhysAddrRange::new(
+                desc.output_address().increment(offset),
+                virt_addr_range.length(),
+            ))
+        } else {
+            None
+        };
+        Some((
+            virt_addr_range,
+            phys_addr_range,
+            Attributes::from(desc),
+            level,
+        ))
+    }
+}
+
+/// Extend a run of entries with the next, if it carries on from the run.
+fn coalesce(run: Mapping, next: Mapping) -> core::result::Result<Mapping, (Mapping, Mapping)> {
+    let (run_range, run_phys, run_attributes, run_level) = run;
+    let (next_range, next_phys, next_attributes, next_level) = next;
+    let phys_adjacent = match (run_phys, next_phys) {
+        (Some(run_phys), Some(next_phys)) => run_phys.top() == next_phys.base(),
+        (None, None) => true,
+        _ => false,
+    };
+    if run_range.top() == next_range.base()
+        && phys_adjacent
+        && run_attributes == next_attributes
+        && run_level == next_level
+    {
+        let length = run_range.length() + next_range.length();
+        Ok((
+            run_range.resize(length),
+            run_phys.map(|run_phys| run_phys.resize(length)),
+            run_attributes,
+            run_level,
+        ))
+    } else {
+        Err((run, next))
+    }
+}
+
+impl Iterator for MappingWalk<'_> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((level, entry_range, pte)) = self.walk.next() {
+            let mapping = match self.mapping(level, entry_range, pte) {
+                Some(mapping) => mapping,
+                None => continue,
+            };
+            match self.pending.take() {
+                None => self.pending = Some(mapping),
+                Some(run) => match coalesce(run, mapping) {
+                    Ok(run) => self.pending = Some(run),
+                    Err((run, mapping)) => {
+                        self.pending = Some(mapping);
+                        return Some(run);
+                    }
+                },
+            }
+        }
+        self.pending.take()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -13,4 +13,10 @@ pub trait HandlerTrait {
     fn wait_forever() -> ! {
         unimplemented!()
     }
+
+    /// Sleep until an interrupt or event, when there is nothing else to do.
+    fn wait_for_event() {}
+
+    /// Wake every core sleeping in wait_for_event.
+    fn send_event() {}
 }
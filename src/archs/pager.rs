@@ -3,20 +3,36 @@
 //! Interface for paging functions.
 
 use crate::pager::{
-    Attributes, FixedOffset, FrameAllocator, PhysAddr, PhysAddrRange, Translate, VirtAddr,
-    VirtAddrRange,
+    Attributes, FixedOffset, FrameAllocator, PhysAddr, PhysAddrRange, PhysAddrRanges, Translate,
+    VirtAddr, VirtAddrRange,
 };
 use crate::util::locked::Locked;
 use crate::Result;
 
 use core::any::Any;
+use core::num::NonZeroU64;
+
+/// A run of adjacent page directory entries.
+///
+/// The virtual range, the physical range it maps to (None if swapped out), the
+/// attributes, and the table level of the entries.
+pub type Mapping = (VirtAddrRange, Option<PhysAddrRange>, Attributes, u8);
 
 /// Each architecture must supply the following entry points for paging..
 pub trait PagerTrait {
-    /// Physical address range of ram
+    /// Physical address range spanning all banks of ram
     fn ram_range() -> PhysAddrRange;
+    /// Banks of ram, with holes between them
+    fn ram_banks() -> PhysAddrRanges;
+    /// Ranges of ram reserved by firmware, which must not be allocated
+    fn reserved_ranges() -> PhysAddrRanges;
     /// Base virtual address of kernel address space
     fn kernel_base() -> VirtAddr;
+    /// Virtual address range private to each user address space
+    fn user_range() -> VirtAddrRange;
+
+    /// Seed for address space layout randomisation, if the firmware provides one
+    fn kaslr_seed() -> Option<u64>;
 
     /// Kernel offset on boot
     fn kernel_offset() -> FixedOffset;
@@ -39,6 +55,17 @@ pub trait PagerTrait {
     fn enable_paging(page_directory: &impl PageDirectory) -> Result<()>;
     /// Move the stack pointer and branch
     fn move_stack(stack_pointer: VirtAddr, next: fn() -> !) -> !;
+    /// Load the user half of a page directory, tagging its TLB entries with an ASID.
+    fn switch_address_space(page_directory: &impl PageDirectory, asid: u16) -> Result<()>;
+    /// Discard any cached translation for a virtual address, on every core.
+    fn invalidate_tlb(virt_addr: VirtAddr) -> Result<()>;
+    /// Discard every cached translation, for all ASIDs.
+    fn invalidate_tlb_all() -> Result<()>;
+    /// Discard every cached translation tagged with an ASID, on every core.
+    fn invalidate_tlb_asid(asid: u16) -> Result<()>;
+    /// Write cached data for a range back to memory and discard it, so that devices
+    /// and non-cacheable mappings see the same contents.
+    fn clean_invalidate_dcache(virt_addr_range: VirtAddrRange) -> Result<()>;
 }
 
 /// Methods to maintain a directory of virtual to physical addresses.
@@ -46,6 +73,26 @@ pub trait PageDirectory {
     /// Enable downshift to arch-specific concrete page directories.
     fn as_any(&self) -> &dyn Any;
 
+    /// Create a directory for a new address space with an empty user range.
+    ///
+    /// The kernel half, and lower half mappings outside the user range, are shared.
+    fn new_user(
+        &self,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &impl Translate,
+    ) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Free the tables of the user range, and any memory they map.
+    ///
+    /// The directory must not be loaded on any core.
+    fn free_user(
+        &mut self,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()>;
+
     /// Map physical address range at offset.
     fn map_translation(
         &mut self,
@@ -63,6 +110,13 @@ pub trait PageDirectory {
         mem_access_translation: &FixedOffset,
     ) -> Result<PhysAddr>;
 
+    /// Return the attributes of the page mapped at a virtual address.
+    fn attributes(
+        &self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<Attributes>;
+
     /// Unmap a previously mapped range, and return any memory to the allocator.
     fn unmap(
         &mut self,
@@ -71,6 +125,83 @@ pub trait PageDirectory {
         mem_access_translation: &FixedOffset,
     ) -> Result<()>;
 
+    /// Change the access attributes of a mapped range.
+    ///
+    /// Blocks and contiguous runs which extend outside the range are split first.
+    fn protect(
+        &mut self,
+        virt_addr_range: VirtAddrRange,
+        attributes: Attributes,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()>;
+
+    /// Point a mapped range at different physical memory, keeping its attributes.
+    ///
+    /// Map counts move from the old frames to the new, and old frames which are
+    /// no longer mapped are freed.
+    fn remap(
+        &mut self,
+        virt_addr_range: VirtAddrRange,
+        translation: impl Translate + core::fmt::Debug,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()>;
+
+    /// Return the swap sector holding the page at a virtual address, if it is swapped out.
+    fn swapped_out(
+        &self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<Option<NonZeroU64>>;
+
+    /// Map a reloaded frame in place of a swapped-out page.
+    fn swap_in(
+        &mut self,
+        virt_addr: VirtAddr,
+        phys_addr: PhysAddr,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()>;
+
+    /// Map a page which is already held in a swap slot, to be reloaded on first access.
+    fn map_swapped(
+        &mut self,
+        virt_addr: VirtAddr,
+        sector: NonZeroU64,
+        attributes: Attributes,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()>;
+
+    /// Set the access flag on the page at a virtual address and return the frame it maps.
+    fn set_accessed(
+        &mut self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<PhysAddr>;
+
+    /// Allow writes to a clean page after its first write has faulted.
+    ///
+    /// Returns false if the page does not track its dirty state.
+    fn set_dirty(
+        &mut self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<bool>;
+
+    /// Iterator over the mappings of a range.
+    type Mappings<'a>: Iterator<Item = Mapping>
+    where
+        Self: 'a;
+
+    /// Return the mappings within a range, coalescing adjacent entries which map alike.
+    fn mappings<'a>(
+        &'a self,
+        virt_addr_range: VirtAddrRange,
+        mem_access_translation: &'a FixedOffset,
+    ) -> Result<Self::Mappings<'a>>;
+
     /// Log the state of the page directory at debug.
     fn dump(&self, mem_access_translation: &impl Translate);
 }
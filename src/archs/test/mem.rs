@@ -0,0 +1,132 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Simulated physical memory.
+//!
+//! Frames at the base of the RAM range are held in host memory, so pager code
+//! can reach them through a FixedOffset translation just as it reaches real RAM
+//! through the kernel's mapping.
+
+use super::Arch;
+
+use crate::archs::PagerTrait;
+use crate::pager::{
+    Addr, AddrRange, FixedOffset, FrameAllocator, FramePurpose, Page, PhysAddr, PhysAddrRange,
+    VirtAddr, PAGESIZE_BYTES,
+};
+use crate::{Error, Result};
+
+use alloc::{boxed::Box, vec, vec::Vec};
+
+/// Frames of simulated RAM, which also serve as a simple frame allocator.
+pub struct PhysicalMemory {
+    frames: &'static mut [Page],
+    allocated: Vec<bool>,
+    map_counts: Vec<u32>,
+}
+
+impl PhysicalMemory {
+    /// Create zeroed memory with a number of frames.
+    ///
+    /// The host memory is never freed, because descriptors may still point into it.
+    pub fn new(frames: usize) -> Self {
+        Self {
+            frames: Box::leak(vec![Page::new(); frames].into_boxed_slice()),
+            allocated: vec![false; frames],
+            map_counts: vec![0; frames],
+        }
+    }
+
+    /// The physical addresses which are backed.
+    pub fn range(&self) -> PhysAddrRange {
+        PhysAddrRange::new(Arch::ram_range().base(), self.frames.len() * PAGESIZE_BYTES)
+    }
+
+    /// Translation from physical addresses to the host memory holding them.
+    pub fn translation(&self) -> FixedOffset {
+        FixedOffset::new(Arch::ram_range().base(), VirtAddr::from(&self.frames[0]))
+    }
+
+    /// Number of page table entries mapping a frame.
+    pub fn map_count(&self, phys_addr: PhysAddr) -> Result<u32> {
+        let i = self.index(phys_addr).ok_or(Error::SegmentFault)?;
+        Ok(self.map_counts[i])
+    }
+
+    /// Number of frames which have been allocated and not yet freed.
+    pub fn allocated(&self) -> usize {
+        self.allocated.iter().filter(|allocated| **allocated).count()
+    }
+
+    fn index(&self, phys_addr: PhysAddr) -> Option<usize> {
+        if !self.range().contains(phys_addr) {
+            return None;
+        }
+        Some(phys_addr.offset_above(self.range().base()) / PAGESIZE_BYTES)
+    }
+}
+
+impl FrameAllocator for PhysicalMemory {
+    fn alloc_zeroed(&mut self, _purpose: FramePurpose) -> Result<PhysAddr> {
+        let i = self
+            .allocated
+            .iter()
+            .position(|allocated| !allocated)
+            .ok_or(Error::OutOfMemory)?;
+        self.allocated[i] = true;
+        self.frames[i] = Page::new();
+        Ok(self.range().base().increment(i * PAGESIZE_BYTES))
+    }
+
+    fn increment_map_count(&mut self, phys_addr: PhysAddr) -> Result<()> {
+        // frames outside the simulated memory, like devices, are not counted
+        if let Some(i) = self.index(phys_addr) {
+            self.map_counts[i] += 1;
+        }
+        Ok(())
+    }
+
+    fn free(&mut self, phys_addr: PhysAddr) -> Result<()> {
+        if let Some(i) = self.index(phys_addr) {
+            // page tables are freed without having been mapped
+            self.map_counts[i] = self.map_counts[i].saturating_sub(1);
+            if self.map_counts[i] == 0 {
+                self.allocated[i] = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::Translate;
+
+    #[test]
+    fn alloc_and_free() {
+        let mut memory = PhysicalMemory::new(2);
+        let a = memory.alloc_zeroed(FramePurpose::Kernel).unwrap();
+        let b = memory.alloc_zeroed(FramePurpose::Kernel).unwrap();
+        assert_eq!(a.increment(PAGESIZE_BYTES), b);
+        assert_err!(memory.alloc_zeroed(FramePurpose::Kernel));
+
+        assert_ok!(memory.increment_map_count(a));
+        assert_ok!(memory.increment_map_count(a));
+        assert_ok!(memory.free(a));
+        assert_eq!(2, memory.allocated());
+        assert_ok!(memory.free(a));
+        assert_eq!(1, memory.allocated());
+        assert_ok_eq!(memory.alloc_zeroed(FramePurpose::Kernel), a);
+    }
+
+    #[test]
+    fn translation() {
+        let mut memory = PhysicalMemory::new(2);
+        let phys_addr = memory.alloc_zeroed(FramePurpose::Kernel).unwrap();
+        let p: *mut u64 = memory.translation().translate_phys(phys_addr).unwrap().into();
+        unsafe {
+            *p.offset(3) = 99;
+        }
+        assert_eq!(99, memory.frames[0].slice()[3]);
+    }
+}
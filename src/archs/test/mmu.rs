@@ -0,0 +1,881 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Simulated memory management unit.
+//!
+//! The page directory holds a descriptor for each mapped page, rather than
+//! tables in simulated memory, and blocks are not simulated. Loads, stores and
+//! instruction fetches by a simulated core walk the directory and enforce the
+//! attributes of the page. Faults are taken to handlers as the exception vector
+//! would take them, and the access is retried once the handler returns.
+
+use super::Arch;
+
+use crate::archs::{Mapping, PagerTrait};
+use crate::pager::{
+    Addr, AddrRange, AttributeField, Attributes, FixedOffset, FrameAllocator, HandlerReturnAction,
+    NullTranslation, PhysAddr, PhysAddrRange, Translate, VirtAddr, VirtAddrRange,
+    PAGESIZE_BYTES,
+};
+use crate::util::locked::Locked;
+use crate::{Error, Result};
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+
+use core::any::Any;
+use core::num::NonZeroU64;
+use core::ptr::NonNull;
+
+/// A simulated page descriptor: flags, and a physical address or swap sector.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PageBlockDescriptor(u64);
+
+impl PageBlockDescriptor {
+    const VALID: u64 = 1 << 0;
+    const ACCESSED: u64 = 1 << 1;
+    /// Writable, and write-protected while clean so that the first write faults
+    const TRACKED: u64 = 1 << 2;
+    const CLEAN: u64 = 1 << 3;
+    const SWAPPED: u64 = 1 << 4;
+    const FLAGS: u64 = 0xfff;
+
+    /// Create a descriptor for a page with some attributes.
+    ///
+    /// Writable pages start dirty, as they do on aarch64.
+    pub fn new_entry(maybe_output_addr: Option<PhysAddr>, attributes: Attributes) -> Self {
+        use AttributeField::*;
+
+        let mut bits = 0;
+        if let Some(output_addr) = maybe_output_addr {
+            bits |= Self::VALID | output_addr.get() as u64;
+        }
+        if attributes.is_set(Accessed) || attributes.is_set(Device) {
+            bits |= Self::ACCESSED;
+        }
+        if attributes.is_writable() && !attributes.is_set(Device) {
+            bits |= Self::TRACKED;
+        }
+        Self(bits)
+    }
+
+    fn is_set(&self, flag: u64) -> bool {
+        0 != self.0 & flag
+    }
+
+    /// Whether the descriptor maps a frame.
+    pub fn is_valid(&self) -> bool {
+        self.is_set(Self::VALID)
+    }
+
+    /// The frame the descriptor maps.
+    pub fn output_address(&self) -> PhysAddr {
+        PhysAddr::at((self.0 & !Self::FLAGS) as usize)
+    }
+
+    /// The same flags, mapping a different frame.
+    pub fn with_output_address(self, phys_addr: PhysAddr) -> Self {
+        Self(self.0 & Self::FLAGS | phys_addr.get() as u64)
+    }
+
+    /// The same mapping with new attributes, keeping the access, dirty and swap state.
+    pub fn with_attributes(self, attributes: Attributes) -> Self {
+        let clean = self.is_set(Self::TRACKED) && self.is_set(Self::CLEAN);
+        let mut result = Self::new_entry(None, attributes);
+        result.0 |= self.0 & !Self::FLAGS;
+        result.0 |= self.0 & (Self::VALID | Self::SWAPPED | Self::ACCESSED);
+        if clean {
+            result.clear_dirty();
+        }
+        result
+    }
+
+    pub fn swap_out(&mut self, sector: NonZeroU64) {
+        self.0 = self.0 & Self::FLAGS & !Self::VALID | Self::SWAPPED | sector.get() << 12;
+    }
+
+    pub fn swapped_sector(&self) -> Option<NonZeroU64> {
+        if self.is_valid() || !self.is_set(Self::SWAPPED) {
+            return None;
+        }
+        NonZeroU64::new(self.0 >> 12)
+    }
+
+    pub fn swap_in(&mut self, phys_addr: PhysAddr) {
+        let flags = self.0 & Self::FLAGS & !Self::SWAPPED;
+        self.0 = flags | Self::VALID | Self::ACCESSED | phys_addr.get() as u64;
+    }
+
+    pub fn is_accessed(&self) -> bool {
+        self.is_set(Self::ACCESSED)
+    }
+
+    pub fn set_accessed(&mut self) {
+        self.0 |= Self::ACCESSED;
+    }
+
+    pub fn clear_accessed(&mut self) {
+        self.0 &= !Self::ACCESSED;
+    }
+
+    pub fn unmap(&mut self) {
+        self.0 = 0;
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.is_set(Self::TRACKED) && !self.is_set(Self::CLEAN)
+    }
+
+    /// Allow writes to a clean page, returning false if it does not track its dirty state.
+    pub fn set_dirty(&mut self) -> bool {
+        if !self.is_set(Self::TRACKED) || !self.is_set(Self::CLEAN) {
+            return false;
+        }
+        self.0 &= !Self::CLEAN;
+        true
+    }
+
+    pub fn clear_dirty(&mut self) {
+        if self.is_set(Self::TRACKED) {
+            self.0 |= Self::CLEAN;
+        }
+    }
+}
+
+/// A descriptor, and the attributes it was mapped with.
+///
+/// Boxed, so the frame table can keep pointers to the descriptor.
+struct Leaf {
+    desc: PageBlockDescriptor,
+    attributes: Attributes,
+}
+
+impl Leaf {
+    fn attributes(&self) -> Attributes {
+        if self.desc.is_accessed() {
+            self.attributes.set(AttributeField::Accessed)
+        } else {
+            self.attributes.clear(AttributeField::Accessed)
+        }
+    }
+
+    /// Mappings of the zero frame and of memory outside the frame table are not counted.
+    fn is_counted(&self) -> bool {
+        !self.attributes.is_set(AttributeField::SuppressMapCount)
+    }
+}
+
+/// The kinds of access a simulated core can make.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// The faults a simulated access can raise.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// The page is not mapped, or is swapped out
+    Translation,
+    /// The page is mapped, but has not been accessed since its flag was cleared
+    AccessFlag,
+    /// The attributes do not allow the access, or the page is clean
+    Permission,
+}
+
+/// Directory of simulated descriptors, by virtual page number.
+pub struct PageDirectory {
+    leaves: BTreeMap<usize, Box<Leaf>>,
+}
+
+impl PageDirectory {
+    pub const fn new() -> Self {
+        Self {
+            leaves: BTreeMap::new(),
+        }
+    }
+
+    fn page_number(virt_addr: VirtAddr) -> usize {
+        virt_addr.get() / PAGESIZE_BYTES
+    }
+
+    /// Each page of a range.
+    fn pages(virt_addr_range: VirtAddrRange) -> impl Iterator<Item = VirtAddr> {
+        let base = virt_addr_range.base().page_base();
+        (0..virt_addr_range.length_in_pages()).map(move |i| base.increment(i * PAGESIZE_BYTES))
+    }
+
+    fn leaf(&self, virt_addr: VirtAddr) -> Option<&Leaf> {
+        self.leaves
+            .get(&Self::page_number(virt_addr))
+            .map(|leaf| &**leaf)
+    }
+
+    fn leaf_mut(&mut self, virt_addr: VirtAddr) -> Option<&mut Leaf> {
+        self.leaves
+            .get_mut(&Self::page_number(virt_addr))
+            .map(|leaf| &mut **leaf)
+    }
+
+    fn valid_leaf_mut(&mut self, virt_addr: VirtAddr) -> Result<&mut Leaf> {
+        match self.leaf_mut(virt_addr) {
+            Some(leaf) if leaf.desc.is_valid() => Ok(leaf),
+            _ => Err(Error::SegmentFault),
+        }
+    }
+
+    /// Translate an access, as the MMU would.
+    ///
+    /// The kernel may read and write user pages, but only execute its own.
+    pub fn walk(
+        &self,
+        virt_addr: VirtAddr,
+        access: Access,
+        is_user: bool,
+    ) -> core::result::Result<PhysAddr, Fault> {
+        use AttributeField::*;
+
+        let leaf = self.leaf(virt_addr).ok_or(Fault::Translation)?;
+        if !leaf.desc.is_valid() {
+            return Err(Fault::Translation);
+        }
+        if !leaf.desc.is_accessed() {
+            return Err(Fault::AccessFlag);
+        }
+        let attributes = leaf.attributes;
+        let allowed = match (access, is_user) {
+            (Access::Read, true) => attributes.is_set(UserRead),
+            (Access::Write, true) => attributes.is_set(UserWrite),
+            (Access::Execute, true) => attributes.is_set(UserExec),
+            (Access::Read, false) => attributes.is_set(KernelRead) || attributes.is_set(UserRead),
+            (Access::Write, false) => attributes.is_writable(),
+            (Access::Execute, false) => attributes.is_set(KernelExec),
+        };
+        let clean = leaf.desc.is_set(PageBlockDescriptor::CLEAN);
+        if !allowed
+            || (access == Access::Write && clean)
+            || (access == Access::Execute && attributes.is_set(Device))
+        {
+            return Err(Fault::Permission);
+        }
+        Ok(leaf.desc.output_address().increment(virt_addr.page_offset()))
+    }
+
+    /// Make a writable page clean, so that its next write faults.
+    pub fn clear_dirty(&mut self, virt_addr: VirtAddr) -> Result<()> {
+        self.valid_leaf_mut(virt_addr)?.desc.clear_dirty();
+        Ok(())
+    }
+}
+
+#[allow(unused_variables)]
+impl crate::archs::PageDirectory for PageDirectory {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// Simulated directories hold only their own pages, so kernel pages are not shared.
+    fn new_user(
+        &self,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &impl Translate,
+    ) -> Result<Self> {
+        Ok(Self::new())
+    }
+
+    fn free_user(
+        &mut self,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()> {
+        info!("free_user");
+        let user_range = Arch::user_range();
+        let first = Self::page_number(user_range.base());
+        let top = Self::page_number(user_range.top());
+        let pages = self
+            .leaves
+            .range(first..top)
+            .map(|(page, _)| *page)
+            .collect::<Vec<usize>>();
+        let mut allocator = allocator.lock();
+        for page in pages {
+            let leaf = self.leaves.remove(&page).expect("leaf");
+            if let Some(sector) = leaf.desc.swapped_sector() {
+                allocator.release_swapped(sector)?;
+            } else if leaf.desc.is_valid() && leaf.is_counted() {
+                let phys_addr = leaf.desc.output_address();
+                allocator.clear_page_block_descriptor(phys_addr, NonNull::from(&leaf.desc))?;
+                allocator.free(phys_addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn map_translation(
+        &mut self,
+        virt_addr_range: VirtAddrRange,
+        translation: impl Translate,
+        attributes: Attributes,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &impl Translate,
+    ) -> Result<VirtAddrRange> {
+        info!("map_translation: {:?} {:?}", virt_addr_range, attributes);
+        attributes.validate()?;
+        for page in Self::pages(virt_addr_range) {
+            if self.leaf(page).map_or(false, |leaf| leaf.desc.is_valid()) {
+                // FIXME: re-mapping
+                return Err(Error::SegmentFault);
+            }
+            let maybe_output_addr = translation.translate_maybe(page);
+            let mut leaf = Box::new(Leaf {
+                desc: PageBlockDescriptor::new_entry(maybe_output_addr, attributes),
+                attributes,
+            });
+            if let Some(phys_addr) = maybe_output_addr {
+                if leaf.is_counted() {
+                    let mut allocator = allocator.lock();
+                    allocator.check_page_block_descriptor(phys_addr)?;
+                    allocator.increment_map_count(phys_addr)?;
+                    allocator.set_page_block_descriptor(
+                        phys_addr,
+                        page,
+                        NonNull::from(&mut leaf.desc),
+                    )?;
+                }
+            }
+            self.leaves.insert(Self::page_number(page), leaf);
+        }
+        Ok(virt_addr_range)
+    }
+
+    fn maps_to(
+        &self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<PhysAddr> {
+        match self.leaf(virt_addr) {
+            Some(leaf) if leaf.desc.is_valid() => Ok(leaf
+                .desc
+                .output_address()
+                .increment(virt_addr.page_offset())),
+            _ => Err(Error::SegmentFault),
+        }
+    }
+
+    fn unmap(
+        &mut self,
+        virt_addr_range: VirtAddrRange,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()> {
+        info!("unmap: {:?}", virt_addr_range);
+        for page in Self::pages(virt_addr_range) {
+            let leaf = self
+                .leaves
+                .remove(&Self::page_number(page))
+                .ok_or(Error::SegmentFault)?;
+            if let Some(sector) = leaf.desc.swapped_sector() {
+                allocator.lock().release_swapped(sector)?;
+                continue;
+            }
+            if !leaf.desc.is_valid() {
+                return Err(Error::SegmentFault);
+            }
+            Arch::invalidate_tlb(page)?;
+            if leaf.is_counted() {
+                let phys_addr = leaf.desc.output_address();
+                let mut allocator = allocator.lock();
+                allocator.clear_page_block_descriptor(phys_addr, NonNull::from(&leaf.desc))?;
+                allocator.free(phys_addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn attributes(
+        &self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<Attributes> {
+        match self.leaf(virt_addr) {
+            Some(leaf) if leaf.desc.is_valid() => Ok(leaf.attributes()),
+            _ => Err(Error::SegmentFault),
+        }
+    }
+
+    fn protect(
+        &mut self,
+        virt_addr_range: VirtAddrRange,
+        attributes: Attributes,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()> {
+        info!("protect: {:?} {:?}", virt_addr_range, attributes);
+        attributes.validate()?;
+        for page in Self::pages(virt_addr_range) {
+            let leaf = match self.leaf_mut(page) {
+                Some(leaf) if leaf.desc.is_valid() || leaf.desc.swapped_sector().is_some() => leaf,
+                _ => continue, // mapped on demand, or not at all
+            };
+            leaf.desc = leaf.desc.with_attributes(attributes);
+            leaf.attributes = attributes;
+        }
+        Ok(())
+    }
+
+    fn remap(
+        &mut self,
+        virt_addr_range: VirtAddrRange,
+        translation: impl Translate + core::fmt::Debug,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()> {
+        info!("remap: {:?} {:?}", virt_addr_range, translation);
+        // refuse before changing anything
+        for page in Self::pages(virt_addr_range) {
+            match self.leaf(page) {
+                Some(leaf) if leaf.desc.swapped_sector().is_some() => {
+                    return Err(Error::SegmentFault)
+                }
+                Some(leaf) if leaf.desc.is_valid() => {
+                    translation
+                        .translate_maybe(page)
+                        .ok_or(Error::SegmentFault)?;
+                }
+                _ => {}
+            }
+        }
+        for page in Self::pages(virt_addr_range) {
+            let phys_addr = match translation.translate_maybe(page) {
+                Some(phys_addr) => phys_addr,
+                None => continue,
+            };
+            let leaf = match self.leaf_mut(page) {
+                Some(leaf) if leaf.desc.is_valid() => leaf,
+                _ => continue, // mapped on demand, or not at all
+            };
+            let old_phys_addr = leaf.desc.output_address();
+            let old_counted = leaf.is_counted();
+            let mut allocator = allocator.lock();
+            if old_counted || leaf.attributes.is_set(AttributeField::CopyOnWrite) {
+                allocator.check_page_block_descriptor(phys_addr)?;
+            }
+            leaf.desc = leaf.desc.with_output_address(phys_addr);
+            // a private copy of a copy-on-write frame is counted, even if the shared one wasn't
+            if leaf.attributes.is_set(AttributeField::CopyOnWrite) {
+                leaf.attributes = leaf.attributes.clear(AttributeField::SuppressMapCount);
+            }
+            if leaf.is_counted() {
+                allocator.increment_map_count(phys_addr)?;
+                allocator.set_page_block_descriptor(
+                    phys_addr,
+                    page,
+                    NonNull::from(&mut leaf.desc),
+                )?;
+            }
+            if old_counted {
+                allocator.clear_page_block_descriptor(old_phys_addr, NonNull::from(&leaf.desc))?;
+                allocator.free(old_phys_addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn swapped_out(
+        &self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<Option<NonZeroU64>> {
+        Ok(self
+            .leaf(virt_addr)
+            .and_then(|leaf| leaf.desc.swapped_sector()))
+    }
+
+    fn swap_in(
+        &mut self,
+        virt_addr: VirtAddr,
+        phys_addr: PhysAddr,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()> {
+        info!("swap_in: {:?} <- {:?}", virt_addr, phys_addr);
+        let leaf = self.leaf_mut(virt_addr).ok_or(Error::SegmentFault)?;
+        if leaf.desc.swapped_sector().is_none() {
+            return Err(Error::UnexpectedValue);
+        }
+        leaf.desc.swap_in(phys_addr);
+        // reloaded page matches its slot
+        leaf.desc.clear_dirty();
+        let mut allocator = allocator.lock();
+        allocator.increment_map_count(phys_addr)?;
+        allocator.set_page_block_descriptor(
+            phys_addr,
+            virt_addr.page_base(),
+            NonNull::from(&mut leaf.desc),
+        )
+    }
+
+    fn map_swapped(
+        &mut self,
+        virt_addr: VirtAddr,
+        sector: NonZeroU64,
+        attributes: Attributes,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<()> {
+        info!("map_swapped: {:?} <- {}", virt_addr, sector);
+        let page = VirtAddrRange::page_containing(virt_addr);
+        self.map_translation(
+            page,
+            NullTranslation::new(),
+            attributes,
+            allocator,
+            mem_access_translation,
+        )?;
+        let leaf = self.leaf_mut(virt_addr).ok_or(Error::SegmentFault)?;
+        leaf.desc.swap_out(sector);
+        Ok(())
+    }
+
+    fn set_accessed(
+        &mut self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<PhysAddr> {
+        info!("set_accessed: {:?}", virt_addr);
+        let leaf = self.valid_leaf_mut(virt_addr)?;
+        leaf.desc.set_accessed();
+        Ok(leaf.desc.output_address())
+    }
+
+    fn set_dirty(
+        &mut self,
+        virt_addr: VirtAddr,
+        mem_access_translation: &FixedOffset,
+    ) -> Result<bool> {
+        info!("set_dirty: {:?}", virt_addr);
+        Ok(self.valid_leaf_mut(virt_addr)?.desc.set_dirty())
+    }
+
+    type Mappings<'a> = alloc::vec::IntoIter<Mapping>;
+
+    /// Each page of the range which has a descriptor.
+    fn mappings<'a>(
+        &'a self,
+        virt_addr_range: VirtAddrRange,
+        mem_access_translation: &'a FixedOffset,
+    ) -> Result<Self::Mappings<'a>> {
+        let first = Self::page_number(virt_addr_range.base());
+        let top = Self::page_number(virt_addr_range.top().align_up(PAGESIZE_BYTES));
+        let result = self
+            .leaves
+            .range(first..top)
+            .map(|(page, leaf)| {
+                let virt_addr = VirtAddr::at(page * PAGESIZE_BYTES);
+                let phys_addr_range = if leaf.desc.is_valid() {
+                    Some(PhysAddrRange::new(leaf.desc.output_address(), PAGESIZE_BYTES))
+                } else {
+                    None
+                };
+                (
+                    VirtAddrRange::new(virt_addr, PAGESIZE_BYTES),
+                    phys_addr_range,
+                    leaf.attributes(),
+                    3,
+                )
+            })
+            .collect::<Vec<Mapping>>();
+        Ok(result.into_iter())
+    }
+
+    fn dump(&self, mem_access_translation: &impl Translate) {
+        for (page, leaf) in self.leaves.iter() {
+            debug!(
+                "{:?}: {:?} {:?}",
+                VirtAddr::at(page * PAGESIZE_BYTES),
+                leaf.desc,
+                leaf.attributes
+            );
+        }
+    }
+}
+
+/// Where the simulated exception vector sends each kind of fault.
+#[derive(Copy, Clone)]
+pub struct Handlers {
+    pub translation: fn(VirtAddr, Option<u64>, bool) -> Result<HandlerReturnAction>,
+    pub permission: fn(VirtAddr, Option<u64>, bool) -> Result<HandlerReturnAction>,
+    pub access_flag: fn(VirtAddr, Option<u64>) -> Result<HandlerReturnAction>,
+}
+
+/// The pager's handlers for faults in kernel memory.
+pub const KERNEL_HANDLERS: Handlers = Handlers {
+    translation: crate::pager::kernel_translation_fault,
+    permission: crate::pager::kernel_permission_fault,
+    access_flag: crate::pager::kernel_access_flag_fault,
+};
+
+/// Most faults a single access may take, so a handler which does not resolve
+/// its fault can't retry forever.
+const MAX_FAULTS: usize = 4;
+
+/// A simulated core, accessing memory through a page directory.
+pub struct Cpu<'a> {
+    page_directory: &'a Locked<PageDirectory>,
+    handlers: Handlers,
+    mem_access_translation: FixedOffset,
+    is_user: bool,
+}
+
+impl<'a> Cpu<'a> {
+    /// A core running the kernel, which reaches physical memory through a translation.
+    pub fn new(
+        page_directory: &'a Locked<PageDirectory>,
+        handlers: Handlers,
+        mem_access_translation: FixedOffset,
+    ) -> Self {
+        Self {
+            page_directory,
+            handlers,
+            mem_access_translation,
+            is_user: false,
+        }
+    }
+
+    /// The same core running a user thread.
+    pub fn user(self) -> Self {
+        Self {
+            is_user: true,
+            ..self
+        }
+    }
+
+    /// Translate an access, taking faults to the handlers until it succeeds.
+    pub fn access(&self, virt_addr: VirtAddr, access: Access) -> Result<PhysAddr> {
+        for _ in 0..MAX_FAULTS {
+            // the handlers lock the page directory themselves
+            let walk = self.page_directory.lock().walk(virt_addr, access, self.is_user);
+            let fault = match walk {
+                Ok(phys_addr) => return Ok(phys_addr),
+                Err(fault) => fault,
+            };
+            debug!("{:?} fault: {:?} {:?}", fault, virt_addr, access);
+            let is_write = access == Access::Write;
+            let action = match fault {
+                Fault::Translation => (self.handlers.translation)(virt_addr, None, is_write)?,
+                Fault::Permission => (self.handlers.permission)(virt_addr, None, is_write)?,
+                Fault::AccessFlag => (self.handlers.access_flag)(virt_addr, None)?,
+            };
+            if action == HandlerReturnAction::Yield {
+                return Err(Error::WouldBlock);
+            }
+        }
+        Err(Error::SegmentFault)
+    }
+
+    /// Load a word.
+    pub fn load(&self, virt_addr: VirtAddr) -> Result<u64> {
+        let phys_addr = self.access(virt_addr, Access::Read)?;
+        let p: *const u64 = self.mem_access_translation.translate_phys(phys_addr)?.into();
+        unsafe { Ok(core::ptr::read_volatile(p)) }
+    }
+
+    /// Store a word.
+    pub fn store(&self, virt_addr: VirtAddr, value: u64) -> Result<()> {
+        let phys_addr = self.access(virt_addr, Access::Write)?;
+        let p: *mut u64 = self.mem_access_translation.translate_phys(phys_addr)?.into();
+        unsafe { core::ptr::write_volatile(p, value) };
+        Ok(())
+    }
+
+    /// Fetch an instruction, without decoding it.
+    pub fn fetch(&self, virt_addr: VirtAddr) -> Result<()> {
+        self.access(virt_addr, Access::Execute).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PhysicalMemory;
+    use super::*;
+    use crate::archs::PageDirectory as _;
+    use crate::pager::FramePurpose;
+
+    fn kernel_page(n: usize) -> VirtAddrRange {
+        VirtAddrRange::new(
+            Arch::kernel_base().increment(n * PAGESIZE_BYTES),
+            PAGESIZE_BYTES,
+        )
+    }
+
+    fn unhandled(_: VirtAddr, _: Option<u64>, _: bool) -> Result<HandlerReturnAction> {
+        Err(Error::SegmentFault)
+    }
+
+    fn unhandled_access_flag(_: VirtAddr, _: Option<u64>) -> Result<HandlerReturnAction> {
+        Err(Error::SegmentFault)
+    }
+
+    const UNHANDLED: Handlers = Handlers {
+        translation: unhandled,
+        permission: unhandled,
+        access_flag: unhandled_access_flag,
+    };
+
+    /// Memory which outlives the descriptors pointing into it.
+    fn memory(frames: usize) -> (&'static Locked<PhysicalMemory>, FixedOffset) {
+        let memory = PhysicalMemory::new(frames);
+        let mem = memory.translation();
+        (Box::leak(Box::new(Locked::new(memory))), mem)
+    }
+
+    fn map_frame(
+        page_directory: &mut PageDirectory,
+        page: VirtAddrRange,
+        attributes: Attributes,
+        allocator: &Locked<PhysicalMemory>,
+        mem: &FixedOffset,
+    ) -> PhysAddr {
+        let phys_addr = allocator.lock().alloc_zeroed(FramePurpose::Kernel).unwrap();
+        let translation = FixedOffset::new(phys_addr, page.base());
+        assert_ok!(page_directory.map_translation(page, translation, attributes, allocator, mem));
+        phys_addr
+    }
+
+    #[test]
+    fn descriptor() {
+        let phys_addr = PhysAddr::at(0x4000_3000);
+        let attributes = Attributes::KERNEL_DATA | AttributeField::Accessed;
+        let mut desc = PageBlockDescriptor::new_entry(Some(phys_addr), attributes);
+        let original = desc;
+        assert!(desc.is_valid());
+        assert!(desc.is_dirty());
+        assert_eq!(phys_addr, desc.output_address());
+
+        let sector = NonZeroU64::new(0x48).unwrap();
+        desc.swap_out(sector);
+        assert!(!desc.is_valid());
+        assert_some_eq!(desc.swapped_sector(), sector);
+        desc.swap_in(phys_addr);
+        assert_none!(desc.swapped_sector());
+        assert_eq!(original, desc);
+
+        desc.clear_dirty();
+        assert!(!desc.is_dirty());
+        assert!(desc.with_attributes(attributes).set_dirty());
+
+        let read_only = PageBlockDescriptor::new_entry(Some(phys_addr), Attributes::KERNEL_RO_DATA);
+        assert!(!read_only.is_dirty());
+        assert!(!read_only.is_accessed());
+    }
+
+    #[test]
+    fn permissions() {
+        use Access::*;
+
+        let (allocator, mem) = memory(4);
+        let mut page_directory = PageDirectory::new();
+        let accessed = AttributeField::Accessed;
+        let user_page = VirtAddrRange::new(Arch::user_range().base(), PAGESIZE_BYTES);
+        for (page, attributes) in [
+            (kernel_page(0), Attributes::KERNEL_RO_DATA | accessed),
+            (kernel_page(1), Attributes::KERNEL_EXEC | accessed),
+            (kernel_page(2), Attributes::KERNEL_DATA),
+            (user_page, Attributes::USER_DATA | accessed),
+        ] {
+            map_frame(&mut page_directory, page, attributes, allocator, &mem);
+        }
+
+        let kernel = |n: usize, access| page_directory.walk(kernel_page(n).base(), access, false);
+        assert_ok!(kernel(0, Read));
+        assert_eq!(Err(Fault::Permission), kernel(0, Write));
+        assert_eq!(Err(Fault::Permission), kernel(0, Execute));
+        assert_ok!(kernel(1, Execute));
+        assert_eq!(Err(Fault::AccessFlag), kernel(2, Read));
+        assert_eq!(Err(Fault::Translation), kernel(3, Read));
+
+        let user = |page: VirtAddrRange, access| page_directory.walk(page.base(), access, true);
+        assert_eq!(Err(Fault::Permission), user(kernel_page(0), Read));
+        assert_eq!(Err(Fault::Permission), user(kernel_page(1), Execute));
+        assert_ok!(user(user_page, Write));
+        assert_ok!(page_directory.walk(user_page.base(), Read, false));
+        assert_eq!(Err(Fault::Permission), page_directory.walk(user_page.base(), Execute, false));
+
+        assert_ok!(page_directory.clear_dirty(user_page.base()));
+        assert_eq!(Err(Fault::Permission), page_directory.walk(user_page.base(), Write, true));
+        assert_ok!(page_directory.walk(user_page.base(), Read, true));
+    }
+
+    static DIRECTORY: Locked<PageDirectory> = Locked::new(PageDirectory::new());
+
+    fn mark_accessed(virt_addr: VirtAddr, _: Option<u64>) -> Result<HandlerReturnAction> {
+        let mem = FixedOffset::identity();
+        DIRECTORY.lock().set_accessed(virt_addr, &mem)?;
+        Ok(HandlerReturnAction::Return)
+    }
+
+    fn mark_dirty(
+        virt_addr: VirtAddr,
+        _: Option<u64>,
+        is_write: bool,
+    ) -> Result<HandlerReturnAction> {
+        let mem = FixedOffset::identity();
+        if !is_write || !DIRECTORY.lock().set_dirty(virt_addr, &mem)? {
+            return Err(Error::SegmentFault);
+        }
+        Ok(HandlerReturnAction::Return)
+    }
+
+    #[test]
+    fn faults_reach_handlers() {
+        let (allocator, mem) = memory(2);
+        let page = kernel_page(0);
+        let addr = page.base().increment(8);
+        {
+            let mut page_directory = DIRECTORY.lock();
+            map_frame(&mut page_directory, page, Attributes::KERNEL_DATA, allocator, &mem);
+            assert_ok!(page_directory.clear_dirty(addr));
+        }
+
+        assert_err!(Cpu::new(&DIRECTORY, UNHANDLED, mem).load(addr));
+
+        let handlers = Handlers {
+            translation: unhandled,
+            permission: mark_dirty,
+            access_flag: mark_accessed,
+        };
+        let cpu = Cpu::new(&DIRECTORY, handlers, mem);
+        assert_ok!(cpu.store(addr, 42));
+        assert_ok_eq!(cpu.load(addr), 42);
+        assert_err!(cpu.fetch(addr));
+        assert_err!(cpu.load(kernel_page(1).base()));
+        assert_err!(cpu.user().load(addr));
+    }
+
+    #[test]
+    fn map_counts() {
+        let (allocator, mem) = memory(3);
+        let mut page_directory = PageDirectory::new();
+        let attributes = Attributes::KERNEL_DATA | AttributeField::Accessed;
+        let pages = kernel_page(0).resize(2 * PAGESIZE_BYTES);
+        let old = map_frame(&mut page_directory, kernel_page(0), attributes, allocator, &mem);
+        assert_ok_eq!(allocator.lock().map_count(old), 1);
+        let addr = kernel_page(0).base().increment(8);
+        assert_ok_eq!(page_directory.maps_to(addr, &mem), old.increment(8));
+
+        let new = allocator.lock().alloc_zeroed(FramePurpose::Kernel).unwrap();
+        let translation = FixedOffset::new(new, kernel_page(0).base());
+        assert_ok!(page_directory.remap(kernel_page(0), translation, allocator, &mem));
+        assert_ok_eq!(allocator.lock().map_count(new), 1);
+        assert_eq!(1, allocator.lock().allocated());
+
+        let sector = NonZeroU64::new(8).unwrap();
+        let swapped = kernel_page(1).base();
+        assert_ok!(page_directory.map_swapped(swapped, sector, attributes, allocator, &mem));
+        assert_ok_eq!(page_directory.swapped_out(swapped, &mem), Some(sector));
+        assert_eq!(2, page_directory.mappings(pages, &mem).unwrap().count());
+
+        assert_ok!(page_directory.unmap(pages, allocator, &mem));
+        assert_eq!(0, allocator.lock().allocated());
+        assert_err!(page_directory.maps_to(addr, &mem));
+    }
+}
@@ -1,14 +1,22 @@
 // SPDX-License-Identifier: Unlicense
 
-//! Dead-bat implementation of an architecture to allow tests to compile.
+//! Architecture for host unit tests.
+//!
+//! Paging is simulated in software, with physical memory held on the host heap
+//! and an MMU which faults into the pager's handlers.
 
 #![allow(missing_docs)]
 
+mod mem;
+mod mmu;
+
+pub use mem::PhysicalMemory;
+pub use mmu::{Access, Cpu, Fault, Handlers, PageBlockDescriptor, PageDirectory, KERNEL_HANDLERS};
+
 use crate::pager::{
-    Addr, AddrRange, Attributes, FixedOffset, FrameAllocator, HandlerReturnAction, PhysAddr,
-    PhysAddrRange, Translate, VirtAddr, VirtAddrRange,
+    Addr, AddrRange, FixedOffset, HandlerReturnAction, PhysAddr, PhysAddrRange, PhysAddrRanges,
+    VirtAddr, VirtAddrRange,
 };
-use crate::util::locked::Locked;
 use crate::Result;
 
 pub struct Arch {}
@@ -19,14 +27,30 @@ impl super::PagerTrait for Arch {
         PhysAddrRange::between(PhysAddr::at(0x4000_0000), PhysAddr::at(0x8000_0000))
     }
 
+    fn ram_banks() -> PhysAddrRanges {
+        PhysAddrRanges::from(Self::ram_range())
+    }
+
+    fn reserved_ranges() -> PhysAddrRanges {
+        PhysAddrRanges::new()
+    }
+
     fn kernel_base() -> VirtAddr {
         VirtAddr::at(0x1_0000_0000_0000)
     }
 
+    fn user_range() -> VirtAddrRange {
+        VirtAddrRange::between(VirtAddr::at(1 << 39), VirtAddr::at(1 << 48))
+    }
+
     fn kernel_offset() -> FixedOffset {
         FixedOffset::new(PhysAddr::at(0x4000_0000), VirtAddr::at(0x1_4000_0000))
     }
 
+    fn kaslr_seed() -> Option<u64> {
+        None
+    }
+
     fn boot_image() -> PhysAddrRange {
         PhysAddrRange::between(PhysAddr::at(0x4008_0000), PhysAddr::at(0x400b_d000))
     }
@@ -62,59 +86,30 @@ impl super::PagerTrait for Arch {
     fn move_stack(stack_pointer: VirtAddr, next: fn() -> !) -> ! {
         unimplemented!()
     }
-}
-
-pub struct PageBlockDescriptor(u64);
-
-pub struct PageDirectory {}
 
-impl PageDirectory {
-    pub const fn new() -> Self {
-        Self {}
-    }
-}
-
-#[allow(unused_variables)]
-impl super::PageDirectory for PageDirectory {
-    fn as_any(&self) -> &dyn Any {
+    fn switch_address_space(page_directory: &impl super::PageDirectory, asid: u16) -> Result<()> {
         unimplemented!()
     }
 
-    fn map_translation(
-        &mut self,
-        virt_addr_range: VirtAddrRange,
-        translation: impl Translate,
-        attributes: Attributes,
-        allocator: &Locked<impl FrameAllocator>,
-        mem_access_translation: &impl Translate,
-    ) -> Result<VirtAddrRange> {
-        unimplemented!()
+    fn invalidate_tlb(virt_addr: VirtAddr) -> Result<()> {
+        Ok(())
     }
 
-    fn maps_to(
-        &self,
-        virt_addr: VirtAddr,
-        mem_access_translation: &FixedOffset,
-    ) -> Result<PhysAddr> {
-        unimplemented!()
+    fn invalidate_tlb_all() -> Result<()> {
+        Ok(())
     }
 
-    fn unmap(
-        &mut self,
-        virt_addr_range: VirtAddrRange,
-        allocator: &Locked<impl FrameAllocator>,
-        mem_access_translation: &FixedOffset,
-    ) -> Result<()> {
-        unimplemented!()
+    fn invalidate_tlb_asid(_asid: u16) -> Result<()> {
+        Ok(())
     }
 
-    fn dump(&self, mem_access_translation: &impl Translate) {
-        unimplemented!()
+    fn clean_invalidate_dcache(_virt_addr_range: VirtAddrRange) -> Result<()> {
+        Ok(())
     }
 }
 
 pub fn new_page_directory() -> impl super::PageDirectory {
-    PageDirectory {}
+    PageDirectory::new()
 }
 
 impl super::DeviceTrait for Arch {
@@ -139,7 +134,6 @@ pub unsafe extern "C" fn reset() -> ! {
 }
 
 use crate::pager::Page;
-use core::any::Any;
 
 #[no_mangle]
 pub static text_base: Page = Page::new();
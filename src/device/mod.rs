@@ -14,7 +14,8 @@ pub mod virtio;
 use crate::archs::arch::Arch;
 use crate::archs::DeviceTrait;
 use crate::pager::{
-    get_range, Addr, AddrRange, HandlerReturnAction, PhysAddr, PhysAddrRange, RangeContent,
+    get_range, Addr, AddrRange, HandlerReturnAction, PhysAddr, PhysAddrRange, PhysAddrRanges,
+    RangeContent,
 };
 use crate::util::locked::Locked;
 use crate::{Error, Result};
@@ -28,17 +29,101 @@ use dtb::{StructItem, StructItems};
 /// Set during reset, before memory is overwritten, so that pager can reserve and map.
 pub static mut PDTB: Option<PhysAddrRange> = None;
 
-/// Get physical memory location from direct-mapped physical DTB address (to bootstrap paging)
+/// Physical memory described by the device tree.
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryMap {
+    /// Banks of RAM, from the reg properties of the memory nodes.
+    pub banks: PhysAddrRanges,
+    /// Regions of RAM set aside by firmware, which must not be allocated.
+    pub reserved: PhysAddrRanges,
+}
+
+/// Get physical memory layout from direct-mapped physical DTB address (to bootstrap paging)
+///
+/// Reserved regions come from the children of /reserved-memory and from the
+/// memory reservation block.
+///
+/// Unsafety: This function must only be called while physical memory is identity-mapped.
+pub unsafe fn get_memory_map_early() -> Result<MemoryMap> {
+    let dtb_addr = PDTB.ok_or(Error::UnInitialised)?.base();
+    let reader =
+        dtb::Reader::read_from_address(dtb_addr.get()).or(Err(Error::DeviceIncompatible))?;
+    let dtb_root = reader.struct_items();
+
+    let mut banks = PhysAddrRanges::new();
+    for (prop, _) in dtb_root.path_struct_items("/memory/reg") {
+        make_addr_ranges(prop, &mut banks)?;
+    }
+    if banks.as_slice().is_empty() {
+        return Err(Error::DeviceIncompatible);
+    }
+
+    let mut reserved = PhysAddrRanges::new();
+    for entry in reader.reserved_mem_entries() {
+        let phys_addr_range = PhysAddrRange::new(
+            PhysAddr::fixed(entry.address as usize),
+            entry.size as usize,
+        );
+        reserved.push(phys_addr_range)?;
+    }
+    for (_, node_iter) in dtb_root.path_struct_items("/reserved-memory") {
+        let mut depth = 0;
+        for item in node_iter {
+            match item {
+                StructItem::BeginNode { .. } => depth += 1,
+                StructItem::EndNode if depth == 0 => break,
+                StructItem::EndNode => depth -= 1,
+                StructItem::Property { name: "reg", .. } if depth == 1 => {
+                    make_addr_ranges(item, &mut reserved)?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(MemoryMap { banks, reserved })
+}
+
+/// Get the seed for address space layout randomisation from the direct-mapped DTB, if any.
+///
+/// Taken from /chosen/kaslr-seed, or failing that /chosen/rng-seed, with the
+/// cells folded into one value.
 ///
 /// Unsafety: This function must only be called while physical memory is identity-mapped.
-pub unsafe fn get_ram_range_early() -> Result<PhysAddrRange> {
+pub unsafe fn get_kaslr_seed_early() -> Result<Option<u64>> {
     let dtb_addr = PDTB.ok_or(Error::UnInitialised)?.base();
     let reader =
         dtb::Reader::read_from_address(dtb_addr.get()).or(Err(Error::DeviceIncompatible))?;
     let dtb_root = reader.struct_items();
-    let (prop, _) = dtb_root.path_struct_items("/memory/reg").next().unwrap();
-    let phys_addr_range = make_addr_range(prop)?;
-    Ok(phys_addr_range)
+
+    for path in ["/chosen/kaslr-seed", "/chosen/rng-seed"].iter() {
+        if let Some((prop, _)) = dtb_root.path_struct_items(path).next() {
+            let mut buf = [0u8; 256];
+            let list = prop
+                .value_u32_list(&mut buf)
+                .or(Err(Error::DeviceIncompatible))?;
+            let seed = list
+                .iter()
+                .fold(0u64, |seed, cell| seed.rotate_left(32) ^ *cell as u64);
+            return Ok(Some(seed));
+        }
+    }
+    Ok(None)
+}
+
+/// Add each (address, size) pair of a reg property to a list of ranges.
+fn make_addr_ranges(prop: StructItem, ranges: &mut PhysAddrRanges) -> Result<()> {
+    let mut buf = [0u8; 128];
+    let list = prop
+        .value_u32_list(&mut buf)
+        .or(Err(Error::DeviceIncompatible))?;
+    for pair in list.chunks_exact(4) {
+        ranges.push(PhysAddrRange::new(
+            PhysAddr::fixed((pair[0] as usize) << 32 | (pair[1] as usize)),
+            (pair[2] as usize) << 32 | (pair[3] as usize),
+        ))?;
+    }
+    Ok(())
 }
 
 fn make_addr_range(prop: StructItem) -> Result<PhysAddrRange> {
@@ -101,9 +186,19 @@ pub struct Sector(pub u64);
 /// Functions for a block storage device
 pub trait Block {
     fn name(&self) -> String;
+    /// Size of the device in sectors.
+    fn capacity(&self) -> Sector;
+    /// Bytes transferred by a request, or WouldBlock until it completes.
+    ///
+    /// The request is forgotten once it has completed, so its status can only be taken once.
     fn status(&mut self, id: RequestId) -> Result<u32>;
     fn read(&mut self, page_addrs: &[PhysAddr], sector: Sector) -> Result<RequestId>;
     fn write(&mut self, page_addrs: &[PhysAddr], sector: Sector) -> Result<RequestId>;
+    /// Read into physical segments of any length, such as from `Paging::dma_segments`,
+    /// which together make up whole sectors.
+    fn read_segments(&mut self, segments: &[PhysAddrRange], sector: Sector) -> Result<RequestId>;
+    /// Write from physical segments of any length which together make up whole sectors.
+    fn write_segments(&mut self, segments: &[PhysAddrRange], sector: Sector) -> Result<RequestId>;
     fn discard(&mut self, sector: Sector, pages: usize) -> Result<RequestId>;
     fn zero(&mut self, sector: Sector, pages: usize) -> Result<RequestId>;
     fn flush(&mut self) -> Result<RequestId>;
@@ -6,7 +6,10 @@ use super::queue;
 use super::{DeviceID, FeaturesSelect, MagicValue, Status, VirtIODevice};
 
 use crate::device::{RequestId, RequestStatus, Sector};
-use crate::pager::{Addr, OwnedMapping, Pager, Paging, PhysAddr, VirtAddr, PAGESIZE_BYTES};
+use crate::pager::{
+    Addr, AddrRange, OwnedMapping, Pager, Paging, PhysAddr, PhysAddrRange, VirtAddr,
+    PAGESIZE_BYTES,
+};
 use crate::{Error, Result};
 
 use alloc::boxed::Box;
@@ -16,6 +19,7 @@ use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 
+use core::convert::TryFrom;
 use core::mem;
 use core::pin::Pin;
 use core::sync::atomic;
@@ -218,10 +222,33 @@ impl Default for ReqHeader {
     }
 }
 
+/// Bytes in a sector, whatever the block size of the device.
+const SECTOR_BYTES: usize = 512;
+
+/// Range of sectors to discard or write zeroes to.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+struct RangeSegment {
+    sector: Sector,
+    num_sectors: u32,
+    flags: u32,
+}
+
+impl Default for RangeSegment {
+    fn default() -> Self {
+        Self {
+            sector: Sector(!0),
+            num_sectors: 0,
+            flags: 0,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C, align(32))]
 struct Request {
     header: ReqHeader,
+    segment: RangeSegment,
     status: RequestStatus,
     init_len: u32,
     id: RequestId,
@@ -233,6 +260,7 @@ impl Default for Request {
     fn default() -> Self {
         Self {
             header: ReqHeader::default(),
+            segment: RangeSegment::default(),
             status: RequestStatus::Init,
             init_len: !0,
             id: RequestId(!0),
@@ -250,6 +278,13 @@ impl Request {
         ))
     }
 
+    pub fn segment_descriptor(&self) -> Result<(PhysAddr, u32)> {
+        Ok((
+            Pager::maps_to((&self.segment).into())?,
+            mem::size_of_val(&self.segment) as u32,
+        ))
+    }
+
     pub fn status_descriptor(&self) -> Result<(PhysAddr, u32)> {
         Ok((
             Pager::maps_to((&self.status).into())?,
@@ -287,6 +322,84 @@ impl<'a> BlockDevice<'a> {
             requests: BTreeMap::new(),
         }
     }
+
+    /// Submit a read or write of a list of physical ranges, starting at a sector.
+    fn transfer(
+        &mut self,
+        req_type: RequestType,
+        data: Vec<(PhysAddr, u32)>,
+        sector: Sector,
+    ) -> Result<RequestId> {
+        let descriptor_count = 2 + data.len();
+        let id = self.virt_queue.next(descriptor_count)?;
+        let mut request = Box::pin(Request {
+            header: ReqHeader {
+                req_type,
+                sector,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        request.descriptor_count = descriptor_count as u16;
+
+        let mut read_ranges = vec![request.header_descriptor()?];
+        let mut write_ranges = Vec::new();
+        match req_type {
+            RequestType::In => write_ranges.extend(data),
+            _ => read_ranges.extend(data),
+        }
+        write_ranges.push(request.status_descriptor()?);
+
+        self.virt_queue.submit(id, &read_ranges, &write_ranges)?;
+        atomic::fence(Ordering::SeqCst);
+        self.regs
+            .virtio_device
+            .queue_notify
+            .set(self.virt_queue.index());
+
+        self.requests.insert(id, request);
+        Ok(id)
+    }
+
+    /// Submit a request which transfers no data, optionally for a range of pages.
+    fn command(
+        &mut self,
+        req_type: RequestType,
+        range: Option<(Sector, usize)>,
+    ) -> Result<RequestId> {
+        let descriptor_count = if range.is_some() { 3 } else { 2 };
+        let id = self.virt_queue.next(descriptor_count)?;
+        let mut request = Box::pin(Request {
+            header: ReqHeader {
+                req_type,
+                sector: Sector(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        request.descriptor_count = descriptor_count as u16;
+
+        let mut read_ranges = vec![request.header_descriptor()?];
+        if let Some((sector, pages)) = range {
+            request.segment = RangeSegment {
+                sector,
+                num_sectors: (pages * PAGESIZE_BYTES / SECTOR_BYTES) as u32,
+                flags: 0,
+            };
+            read_ranges.push(request.segment_descriptor()?);
+        }
+        let write_ranges = vec![request.status_descriptor()?];
+
+        self.virt_queue.submit(id, &read_ranges, &write_ranges)?;
+        atomic::fence(Ordering::SeqCst);
+        self.regs
+            .virtio_device
+            .queue_notify
+            .set(self.virt_queue.index());
+
+        self.requests.insert(id, request);
+        Ok(id)
+    }
 }
 
 /// tock_registers structs are implemented using UnsafeCells are not Send.
@@ -300,6 +413,10 @@ impl crate::device::Block for BlockDevice<'_> {
         self.name.clone()
     }
 
+    fn capacity(&self) -> Sector {
+        Sector(unsafe { core::ptr::read_volatile(&self.regs.config.capacity) })
+    }
+
     fn status(&mut self, id: RequestId) -> Result<u32> {
         let req = self.requests.get(&id).ok_or(Error::UnexpectedValue)?;
         dbg!(&req);
@@ -315,6 +432,8 @@ impl crate::device::Block for BlockDevice<'_> {
         let req = self.requests.get(&id).ok_or(Error::UnexpectedValue)?;
         dbg!(&req);
         if req.used {
+            // a completed request is forgotten once its status has been taken
+            let req = self.requests.remove(&id).ok_or(Error::UnexpectedValue)?;
             match req.status {
                 RequestStatus::Ok => Ok(req.init_len - 1), // less one byte for status
                 RequestStatus::IOErr => Err(Error::IOError),
@@ -327,51 +446,65 @@ impl crate::device::Block for BlockDevice<'_> {
     }
 
     fn read(&mut self, page_addrs: &[PhysAddr], sector: Sector) -> Result<RequestId> {
-        let id = self.virt_queue.next(3)?;
-        let request = Box::pin(Request {
-            header: ReqHeader {
-                req_type: RequestType::In,
-                sector,
-                ..Default::default()
-            },
-            ..Default::default()
-        });
-
-        dbg!(&request);
-
-        let read_ranges = vec![request.header_descriptor()?];
-        let mut write_ranges: Vec<(PhysAddr, u32)> = page_addrs
+        let data = page_addrs
             .iter()
-            .map(|phys_addr| (phys_addr.clone(), PAGESIZE_BYTES as u32))
+            .map(|phys_addr| (*phys_addr, PAGESIZE_BYTES as u32))
             .collect();
-        write_ranges.push(request.status_descriptor()?);
+        self.transfer(RequestType::In, data, sector)
+    }
 
-        self.virt_queue.submit(id, &read_ranges, &write_ranges)?;
-        atomic::fence(Ordering::SeqCst);
-        self.regs
-            .virtio_device
-            .queue_notify
-            .set(self.virt_queue.index());
+    fn write(&mut self, page_addrs: &[PhysAddr], sector: Sector) -> Result<RequestId> {
+        let data = page_addrs
+            .iter()
+            .map(|phys_addr| (*phys_addr, PAGESIZE_BYTES as u32))
+            .collect();
+        self.transfer(RequestType::Out, data, sector)
+    }
 
-        self.requests.insert(id, request);
-        Ok(id)
+    fn read_segments(&mut self, segments: &[PhysAddrRange], sector: Sector) -> Result<RequestId> {
+        let data = segment_descriptors(segments)?;
+        self.transfer(RequestType::In, data, sector)
     }
 
-    fn write(&mut self, page_addrs: &[PhysAddr], sector: Sector) -> Result<RequestId> {
-        todo!()
+    fn write_segments(&mut self, segments: &[PhysAddrRange], sector: Sector) -> Result<RequestId> {
+        let data = segment_descriptors(segments)?;
+        self.transfer(RequestType::Out, data, sector)
     }
 
     fn discard(&mut self, sector: Sector, length: usize) -> Result<RequestId> {
-        todo!()
+        let (_, features_lo32) = self.features;
+        if !features_lo32.is_set(BlockDeviceFeaturesLo32::BLK_DISCARD) {
+            return Err(Error::Unsupported);
+        }
+        self.command(RequestType::Discard, Some((sector, length)))
     }
 
     fn zero(&mut self, sector: Sector, length: usize) -> Result<RequestId> {
-        todo!()
+        let (_, features_lo32) = self.features;
+        if !features_lo32.is_set(BlockDeviceFeaturesLo32::BLK_WRITE_ZEROES) {
+            return Err(Error::Unsupported);
+        }
+        self.command(RequestType::WriteZeroes, Some((sector, length)))
     }
 
     fn flush(&mut self) -> Result<RequestId> {
-        todo!()
+        self.command(RequestType::Flush, None)
+    }
+}
+
+/// Descriptors for segments of any length which together make up whole sectors.
+fn segment_descriptors(segments: &[PhysAddrRange]) -> Result<Vec<(PhysAddr, u32)>> {
+    let length: usize = segments.iter().map(|segment| segment.length()).sum();
+    if length == 0 || length % SECTOR_BYTES != 0 {
+        return Err(Error::UnexpectedValue);
     }
+    segments
+        .iter()
+        .map(|segment| {
+            let length = u32::try_from(segment.length()).or(Err(Error::UnexpectedValue))?;
+            Ok((segment.base(), length))
+        })
+        .collect()
 }
 
 /// Attempt to initialise a block device behind a virtio node.
@@ -17,6 +17,18 @@ pub fn init_core() -> Result<()> {
     Arch::handler_init()
 }
 
+/// Sleep until an interrupt or event, when the core has nothing to do.
+pub fn wait_for_event() {
+    use crate::archs::{arch::Arch, HandlerTrait};
+    Arch::wait_for_event()
+}
+
+/// Wake cores sleeping in wait_for_event, because there is work for them.
+pub fn send_event() {
+    use crate::archs::{arch::Arch, HandlerTrait};
+    Arch::send_event()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -9,6 +9,8 @@
 #![feature(linkage)] // for weak linkage of panic::_panic_exit
 #![feature(core_intrinsics)] // for unchecked_sub in checking perms for ptes
 #![feature(alloc_error_handler)] // for kernel heap
+#![feature(allocator_api)] // for Box::try_new
+#![feature(asm_const)] // for sizes in exception vectors
 #![feature(const_mut_refs)] // for as_mut_ref
 #![feature(const_fn_trait_bound)] // for BitField::new
 #![feature(const_btree_new)] // for device-name maps
@@ -21,8 +21,6 @@ extern "C" fn kernel_init() -> ! {
 fn kernel_main() -> ! {
     major!("kernel_main");
 
-    device::init().expect("device::init");
-
     // thread::init().expect("thread::init");
 
     // release_cores();
@@ -38,7 +36,27 @@ fn kernel_main() -> ! {
     // thread::terminate()
 
     major!("looping");
-    loop {}
+    loop {
+        idle()
+    }
+}
+
+/// Keep frames free and zeroed in the background, sleeping when there is nothing to do.
+///
+/// The frame table sends an event whenever a frame is allocated or freed, which wakes
+/// the core to look for work again.
+fn idle() {
+    let evicted = pager::evict_cold_pages().unwrap_or_else(|e| {
+        error!("evict_cold_pages: {:?}", e);
+        0
+    });
+    let zeroed = pager::zero_free_frames().unwrap_or_else(|e| {
+        error!("zero_free_frames: {:?}", e);
+        0
+    });
+    if evicted + zeroed == 0 {
+        handler::wait_for_event();
+    }
 }
 
 /// Additional cores entry point, called from architecture-specific reset.
@@ -57,7 +75,9 @@ extern "C" fn core_init() -> ! {
         ACCESS.store(true, Ordering::Relaxed);
         major!("core initialised");
         major!("looping");
-        loop {}
+        loop {
+            idle()
+        }
     }
 
     handler::init_core().expect("handler::init_core");
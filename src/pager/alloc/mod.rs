@@ -0,0 +1,353 @@
+// SPDX-License-Identifier: Unlicense
+
+//! A kernel heap.
+//!
+//! Small allocations come from slab caches by size class, which are built from
+//! frames of the frame table and reached through the kernel's mapping of RAM.
+//!
+//! Larger allocations are rounded up to whole pages and come from a heap which
+//! starts small at the base of the KernelHeap range and grows through it as
+//! allocations need room. The range is mapped on demand, so a page is only
+//! backed by a frame once the heap touches it. Before the heap grows, it sets
+//! aside enough free or zeroed frames in the frame table to back the growth, so an
+//! allocation fails cleanly instead of taking a fault that can't be satisfied.
+//!
+//! The heap must not be used with the frame table locked, because setting frames
+//! aside and backing slabs both lock it. It may be used with the page directory
+//! locked. It never evicts, because writing out a page needs both locks, so cold
+//! user pages are evicted ahead of need by `evict_cold_pages` instead.
+//!
+//! `Box::new` and friends still end in the alloc_error_handler when they fail.
+//! Code which can degrade should use `Box::try_new` or `Vec::try_reserve`.
+
+mod slab;
+
+pub use slab::SlabStats;
+
+use crate::pager::{frames, mem_translation, Addr, AddrRange, VirtAddrRange, PAGESIZE_BYTES};
+use crate::util::locked::Locked;
+use crate::{Error, Result};
+
+use alloc::alloc::{GlobalAlloc, Layout};
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use linked_list_allocator::Heap;
+
+/// Bytes the heap starts with, and the least it grows by.
+const GROWTH_BYTES: usize = 16 * PAGESIZE_BYTES;
+
+/// Usage of the kernel heap.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct HeapStats {
+    /// Bytes allocated.
+    pub used: usize,
+    /// Bytes which can be allocated without growing.
+    pub free: usize,
+    /// Most bytes allocated at once since start-up.
+    pub high_water: usize,
+    /// Bytes the heap has grown to.
+    pub size: usize,
+    /// Bytes the heap can grow to.
+    pub limit: usize,
+}
+
+struct HeapInner {
+    heap: Heap,
+    limit: usize, // top of the heap range
+    high_water: usize,
+    spare: usize, // bytes with frames set aside, for a growth another core made first
+}
+
+impl HeapInner {
+    /// Bytes to grow by to fit an allocation, or OutOfMemory if the range is exhausted.
+    fn growth(&self, layout: Layout) -> Result<usize> {
+        if self.heap.size() == 0 {
+            return Err(Error::UnInitialised);
+        }
+        let needed = layout.size() + layout.align();
+        let growth = (needed + GROWTH_BYTES - 1) / GROWTH_BYTES * GROWTH_BYTES;
+        let growth = growth.min(self.limit - self.heap.top());
+        if growth < needed {
+            return Err(Error::OutOfMemory);
+        }
+        Ok(growth)
+    }
+}
+
+/// Heap which grows through a virtual address range.
+pub struct KernelHeap(Locked<HeapInner>);
+
+impl KernelHeap {
+    /// An empty heap, which can't allocate until it is initialised.
+    pub const fn new() -> Self {
+        Self(Locked::new(HeapInner {
+            heap: Heap::empty(),
+            limit: 0,
+            high_water: 0,
+            spare: 0,
+        }))
+    }
+
+    /// Start the heap at the base of a range it can grow through.
+    ///
+    /// Note: The range must be accessible, and not used for anything else.
+    pub unsafe fn init(&self, range: VirtAddrRange) {
+        let mut inner = self.0.lock();
+        inner
+            .heap
+            .init(range.base().get(), GROWTH_BYTES.min(range.length()));
+        inner.limit = range.top().get();
+    }
+
+    /// Allocate, growing the heap if necessary.
+    ///
+    /// Before the heap grows, reserve is called with the number of pages it will grow by,
+    /// to set aside frames for them, less any already set aside for a growth which
+    /// failed. The heap is not locked while reserve runs, because it locks the frame
+    /// table.
+    pub fn alloc(
+        &self,
+        layout: Layout,
+        mut reserve: impl FnMut(usize) -> Result<()>,
+    ) -> Result<NonNull<u8>> {
+        loop {
+            let (growth, spare) = {
+                let mut inner = self.0.lock();
+                if let Ok(ptr) = inner.heap.allocate_first_fit(layout) {
+                    inner.high_water = inner.high_water.max(inner.heap.used());
+                    return Ok(ptr);
+                }
+                let growth = inner.growth(layout)?;
+                let spare = inner.spare.min(growth);
+                inner.spare -= spare;
+                (growth, spare)
+            };
+            info!("growing heap by 0x{:x}", growth);
+            let reserved = match growth - spare {
+                0 => Ok(()),
+                bytes => reserve(bytes / PAGESIZE_BYTES),
+            };
+
+            let mut inner = self.0.lock();
+            if let Err(e) = reserved {
+                inner.spare += spare;
+                return Err(e);
+            }
+            // another core may have grown the heap meanwhile, so keep the frames for later
+            if inner.limit - inner.heap.top() < growth {
+                inner.spare += growth;
+                return Err(Error::OutOfMemory);
+            }
+            unsafe {
+                inner.heap.extend(growth);
+            }
+        }
+    }
+
+    /// Return an allocation to the heap.
+    ///
+    /// Note: The pointer must have been allocated from this heap with the same layout.
+    pub unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.0.lock().heap.deallocate(ptr, layout)
+    }
+
+    /// Counters for the use of the heap.
+    pub fn stats(&self) -> HeapStats {
+        let inner = self.0.lock();
+        HeapStats {
+            used: inner.heap.used(),
+            free: inner.heap.free(),
+            high_water: inner.high_water,
+            size: inner.heap.size(),
+            limit: inner.limit - inner.heap.bottom(),
+        }
+    }
+}
+
+/// Set aside frames to back pages which the heap is about to use.
+fn reserve(pages: usize) -> Result<()> {
+    // frames held by this core count towards those available
+    frames::drain_magazine()?;
+    frames::reserve(pages as u32)
+}
+
+/// The layout of a large allocation, in whole pages.
+fn pages_for(layout: Layout) -> Option<Layout> {
+    Layout::from_size_align(layout.size(), layout.align().max(PAGESIZE_BYTES))
+        .ok()
+        .map(|layout| layout.pad_to_align())
+}
+
+/// Slab caches for small allocations, and a heap for the rest.
+struct KernelAllocator {
+    slabs: slab::Slabs,
+    heap: KernelHeap,
+    ready: AtomicBool, // the kernel's mapping of RAM is in place
+}
+
+impl KernelAllocator {
+    fn alloc(&self, layout: Layout) -> Result<NonNull<u8>> {
+        if !self.ready.load(Ordering::Acquire) {
+            return Err(Error::UnInitialised);
+        }
+        match slab::size_class(layout) {
+            Some(class) => self
+                .slabs
+                .alloc(class, frames::allocator(), mem_translation()),
+            None => {
+                let layout = pages_for(layout).ok_or(Error::OutOfMemory)?;
+                self.heap.alloc(layout, reserve)
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) -> Result<()> {
+        match slab::size_class(layout) {
+            Some(class) => self
+                .slabs
+                .dealloc(class, ptr, frames::allocator(), mem_translation()),
+            None => {
+                let layout = pages_for(layout).ok_or(Error::UnexpectedValue)?;
+                self.heap.dealloc(ptr, layout);
+                Ok(())
+            }
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match KernelAllocator::alloc(self, layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(e) => {
+                error!("heap allocation failed: {:?} {:?}", layout, e);
+                core::ptr::null_mut()
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Err(e) = KernelAllocator::dealloc(self, NonNull::new_unchecked(ptr), layout) {
+            error!("heap deallocation failed: {:?} {:?}", layout, e);
+        }
+    }
+}
+
+/// Allocator for kernel heap. Must be initialised.
+#[cfg_attr(not(test), global_allocator)]
+static ALLOCATOR: KernelAllocator = KernelAllocator {
+    slabs: slab::Slabs::new(),
+    heap: KernelHeap::new(),
+    ready: AtomicBool::new(false),
+};
+
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    error!("alloc_error_handler");
+    panic!("allocation error: {:?}", layout)
+}
+
+/// Initialise the heap.
+///
+/// Note: Memory must be accessible.
+#[cfg(not(test))]
+pub fn init() -> Result<()> {
+    use crate::pager::layout::RangeContent;
+
+    major!("init");
+
+    let heap_range = super::layout::get_range(RangeContent::KernelHeap)?;
+    info!("heap_range: {:?}", heap_range);
+
+    unsafe {
+        ALLOCATOR.heap.init(heap_range);
+    }
+    ALLOCATOR.ready.store(true, Ordering::Release);
+    info!("Kernel Heap: {:?}", ALLOCATOR.heap.stats());
+
+    Ok(())
+}
+
+/// Counters for the use of the heap of large allocations.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.heap.stats()
+}
+
+/// Counters for each slab cache of small allocations.
+pub fn slab_stats() -> [SlabStats; slab::SIZE_CLASSES] {
+    ALLOCATOR.slabs.stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::{Page, VirtAddr};
+
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    const PAGES: usize = 64;
+
+    fn heap() -> KernelHeap {
+        let pages = Box::leak(vec![Page::new(); PAGES].into_boxed_slice());
+        let heap = KernelHeap::new();
+        unsafe {
+            heap.init(VirtAddrRange::new(
+                VirtAddr::from(&pages[0]),
+                PAGES * PAGESIZE_BYTES,
+            ));
+        }
+        heap
+    }
+
+    #[test]
+    fn grows() {
+        let heap = heap();
+        assert_eq!(GROWTH_BYTES, heap.stats().size);
+        assert_eq!(PAGES * PAGESIZE_BYTES, heap.stats().limit);
+
+        let mut reserved = 0;
+        let layout = Layout::from_size_align(5 * GROWTH_BYTES / 2, 8).unwrap();
+        let ptr = heap
+            .alloc(layout, |pages| {
+                reserved += pages;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(3 * GROWTH_BYTES / PAGESIZE_BYTES, reserved);
+        let stats = heap.stats();
+        assert_eq!(4 * GROWTH_BYTES, stats.size);
+        assert!(stats.used >= layout.size());
+
+        unsafe {
+            heap.dealloc(ptr, layout);
+        }
+        let stats = heap.stats();
+        assert_eq!(0, stats.used);
+        assert!(stats.high_water >= layout.size());
+    }
+
+    #[test]
+    fn fails_cleanly() {
+        let heap = heap();
+        let small = Layout::from_size_align(64, 8).unwrap();
+        let large = Layout::from_size_align(2 * GROWTH_BYTES, 8).unwrap();
+        let huge = Layout::from_size_align(PAGES * PAGESIZE_BYTES, 8).unwrap();
+
+        assert_eq!(
+            Err(Error::OutOfPages),
+            heap.alloc(large, |_| Err(Error::OutOfPages))
+        );
+        assert_eq!(Err(Error::OutOfMemory), heap.alloc(huge, |_| Ok(())));
+        assert_eq!(GROWTH_BYTES, heap.stats().size);
+        assert_ok!(heap.alloc(small, |_| Err(Error::OutOfPages)));
+
+        assert_eq!(
+            Err(Error::UnInitialised),
+            KernelHeap::new().alloc(small, |_| Ok(()))
+        );
+    }
+}
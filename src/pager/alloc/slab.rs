@@ -0,0 +1,323 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Caches of small objects, by size class.
+//!
+//! Each cache carves frames into objects of one size, and has its own lock. A
+//! frame is reached through the kernel's mapping of RAM, and starts with a header
+//! holding its free objects, so an object finds its header from its address.
+//! Frames with free objects are kept on a list, and a frame is given back to the
+//! frame table when its last object is freed, unless it is the cache's only one.
+
+use crate::pager::{Addr, FrameAllocator, FramePurpose, Translate, VirtAddr, PAGESIZE_BYTES};
+use crate::util::locked::Locked;
+use crate::Result;
+
+use alloc::alloc::Layout;
+
+use core::mem::size_of;
+use core::ptr::NonNull;
+
+/// Size of the smallest class, as a power of two, which must hold a free object.
+const MIN_SIZE_SHIFT: usize = 4;
+
+/// Number of size classes, from 16 bytes doubling to 1k.
+pub const SIZE_CLASSES: usize = 7;
+
+/// The size class which can hold an allocation, if it is small enough.
+///
+/// Objects are aligned to their size, which satisfies any smaller alignment.
+pub fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(1 << MIN_SIZE_SHIFT);
+    let class = size.next_power_of_two().trailing_zeros() as usize - MIN_SIZE_SHIFT;
+    if class < SIZE_CLASSES {
+        Some(class)
+    } else {
+        None
+    }
+}
+
+const fn class_size(class: usize) -> usize {
+    1 << (class + MIN_SIZE_SHIFT)
+}
+
+/// Objects before the first which doesn't overlap the header of a frame.
+const fn first_object(class: usize) -> usize {
+    (size_of::<SlabHeader>() + class_size(class) - 1) / class_size(class)
+}
+
+/// Objects in each frame of a size class.
+const fn objects_per_page(class: usize) -> usize {
+    PAGESIZE_BYTES / class_size(class) - first_object(class)
+}
+
+/// Usage of one size class.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SlabStats {
+    /// Bytes in each object.
+    pub size: usize,
+    /// Frames held by the cache.
+    pub pages: usize,
+    /// Objects the frames can hold.
+    pub capacity: usize,
+    /// Objects allocated and not yet freed.
+    pub in_use: usize,
+    /// Objects allocated since start-up.
+    pub allocs: u64,
+    /// Objects freed since start-up.
+    pub frees: u64,
+}
+
+/// An unallocated object, linking to the next in its frame.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Start of each frame of a cache.
+struct SlabHeader {
+    next: Option<NonNull<SlabHeader>>, // next frame with free objects
+    free: Option<NonNull<FreeObject>>,
+    in_use: usize,
+}
+
+impl SlabHeader {
+    /// The header of the frame holding an object.
+    fn of(object: NonNull<u8>) -> NonNull<SlabHeader> {
+        let page_base = object.as_ptr() as usize & !(PAGESIZE_BYTES - 1);
+        unsafe { NonNull::new_unchecked(page_base as *mut SlabHeader) }
+    }
+}
+
+/// The frames and counters of one size class.
+struct Cache {
+    class: usize,
+    partial: Option<NonNull<SlabHeader>>, // frames with free objects
+    stats: SlabStats,
+}
+
+// frames are only reached through the cache's lock
+unsafe impl Send for Cache {}
+
+impl Cache {
+    const fn new(class: usize) -> Self {
+        Self {
+            class,
+            partial: None,
+            stats: SlabStats {
+                size: class_size(class),
+                pages: 0,
+                capacity: 0,
+                in_use: 0,
+                allocs: 0,
+                frees: 0,
+            },
+        }
+    }
+
+    /// Take a free object, if any frame has one.
+    fn take(&mut self) -> Option<NonNull<u8>> {
+        let mut header_ptr = self.partial?;
+        let header = unsafe { header_ptr.as_mut() };
+        let object = header.free.expect("frame with free objects");
+        header.free = unsafe { object.as_ref().next };
+        header.in_use += 1;
+        if header.free.is_none() {
+            self.partial = header.next.take();
+        }
+        self.stats.in_use += 1;
+        self.stats.allocs += 1;
+        Some(object.cast())
+    }
+
+    /// Carve a frame into free objects.
+    ///
+    /// Note: The frame must be accessible, and not used for anything else.
+    unsafe fn add(&mut self, page: NonNull<u8>) {
+        let size = class_size(self.class);
+        let first = first_object(self.class);
+        let mut free = None;
+        for i in (first..PAGESIZE_BYTES / size).rev() {
+            let object = page.as_ptr().add(i * size) as *mut FreeObject;
+            object.write(FreeObject { next: free });
+            free = Some(NonNull::new_unchecked(object));
+        }
+        let header = page.cast::<SlabHeader>();
+        header.as_ptr().write(SlabHeader {
+            next: self.partial,
+            free,
+            in_use: 0,
+        });
+        self.partial = Some(header);
+        self.stats.pages += 1;
+        self.stats.capacity += objects_per_page(self.class);
+    }
+
+    /// Return an object, and its frame if that is now empty and can be given back.
+    ///
+    /// Note: The object must have been taken from this cache.
+    unsafe fn give(&mut self, object: NonNull<u8>) -> Option<NonNull<u8>> {
+        let mut header_ptr = SlabHeader::of(object);
+        let header = header_ptr.as_mut();
+        let was_full = header.free.is_none();
+        let object = object.cast::<FreeObject>();
+        object.as_ptr().write(FreeObject { next: header.free });
+        header.free = Some(object);
+        header.in_use -= 1;
+        self.stats.in_use -= 1;
+        self.stats.frees += 1;
+        if was_full {
+            header.next = self.partial;
+            self.partial = Some(header_ptr);
+        }
+        if header.in_use > 0 || self.stats.pages == 1 {
+            return None;
+        }
+        self.unlink(header_ptr);
+        self.stats.pages -= 1;
+        self.stats.capacity -= objects_per_page(self.class);
+        Some(header_ptr.cast())
+    }
+
+    /// Remove a frame from the list of frames with free objects.
+    fn unlink(&mut self, header: NonNull<SlabHeader>) {
+        let mut link = &mut self.partial;
+        while let Some(mut current) = *link {
+            if current == header {
+                *link = unsafe { current.as_ref().next };
+                return;
+            }
+            link = unsafe { &mut current.as_mut().next };
+        }
+    }
+}
+
+/// A cache for each size class.
+pub struct Slabs([Locked<Cache>; SIZE_CLASSES]);
+
+impl Slabs {
+    /// Caches without any frames.
+    pub const fn new() -> Self {
+        Self([
+            Locked::new(Cache::new(0)),
+            Locked::new(Cache::new(1)),
+            Locked::new(Cache::new(2)),
+            Locked::new(Cache::new(3)),
+            Locked::new(Cache::new(4)),
+            Locked::new(Cache::new(5)),
+            Locked::new(Cache::new(6)),
+        ])
+    }
+
+    /// Allocate an object of a size class, adding a frame to its cache if it is full.
+    ///
+    /// The frame is taken without evicting, so fails if none is free.
+    pub fn alloc(
+        &self,
+        class: usize,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &impl Translate,
+    ) -> Result<NonNull<u8>> {
+        loop {
+            if let Some(object) = self.0[class].lock().take() {
+                return Ok(object);
+            }
+            // never evicts, which would allocate with the frame table locked
+            let phys_addr = {
+                let mut allocator = allocator.lock();
+                let phys_addr = allocator.alloc_for_overwrite(FramePurpose::Kernel)?;
+                // counts the kernel's mapping of RAM
+                allocator.increment_map_count(phys_addr)?;
+                phys_addr
+            };
+            let page: *mut u8 = mem_access_translation.translate_phys(phys_addr)?.into();
+            unsafe {
+                self.0[class].lock().add(NonNull::new_unchecked(page));
+            }
+        }
+    }
+
+    /// Free an object, giving its frame back to the frame table if it is empty.
+    ///
+    /// Note: The object must have been allocated from the same size class.
+    pub unsafe fn dealloc(
+        &self,
+        class: usize,
+        object: NonNull<u8>,
+        allocator: &Locked<impl FrameAllocator>,
+        mem_access_translation: &impl Translate,
+    ) -> Result<()> {
+        let empty = self.0[class].lock().give(object);
+        if let Some(page) = empty {
+            let phys_addr = mem_access_translation.translate(VirtAddr::at(page.as_ptr() as usize));
+            allocator.lock().free(phys_addr)?;
+        }
+        Ok(())
+    }
+
+    /// Counters for each size class.
+    pub fn stats(&self) -> [SlabStats; SIZE_CLASSES] {
+        let mut result = [SlabStats::default(); SIZE_CLASSES];
+        for (stats, cache) in result.iter_mut().zip(self.0.iter()) {
+            *stats = cache.lock().stats;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archs::arch::PhysicalMemory;
+
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn size_classes() {
+        assert_some_eq!(size_class(layout(1, 1)), 0);
+        assert_some_eq!(size_class(layout(16, 8)), 0);
+        assert_some_eq!(size_class(layout(17, 8)), 1);
+        assert_some_eq!(size_class(layout(24, 64)), 2);
+        assert_some_eq!(size_class(layout(1024, 8)), 6);
+        assert_none!(size_class(layout(1025, 8)));
+        assert_none!(size_class(layout(8, PAGESIZE_BYTES)));
+        assert_eq!(63, objects_per_page(2));
+        assert_eq!(3, objects_per_page(6));
+    }
+
+    #[test]
+    fn alloc_and_free() {
+        let memory = PhysicalMemory::new(4);
+        let mem = memory.translation();
+        let allocator: &'static Locked<PhysicalMemory> = Box::leak(Box::new(Locked::new(memory)));
+        let slabs = Slabs::new();
+
+        let objects = (0..objects_per_page(2) + 1)
+            .map(|_| slabs.alloc(2, allocator, &mem).unwrap())
+            .collect::<Vec<_>>();
+        for (i, object) in objects.iter().enumerate() {
+            assert_eq!(0, object.as_ptr() as usize % 64);
+            unsafe { object.as_ptr().write_bytes(i as u8, 64) };
+        }
+        assert_eq!(2, allocator.lock().allocated());
+        let stats = slabs.stats()[2];
+        assert_eq!((64, 2, 126), (stats.size, stats.pages, stats.capacity));
+        assert_eq!(objects_per_page(2) + 1, stats.in_use);
+        assert_eq!(0, slabs.stats()[0].pages);
+
+        for object in objects.iter().rev() {
+            assert_ok!(unsafe { slabs.dealloc(2, *object, allocator, &mem) });
+        }
+        let stats = slabs.stats()[2];
+        assert_eq!((1, 0), (stats.pages, stats.in_use));
+        assert_eq!(stats.allocs, stats.frees);
+        assert_eq!(1, allocator.lock().allocated());
+
+        // the remaining frame is reused
+        assert_ok!(slabs.alloc(2, allocator, &mem));
+        assert_eq!(1, allocator.lock().allocated());
+    }
+}
@@ -0,0 +1,218 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Allocate address space identifiers.
+//!
+//! TLB entries for non-global pages are tagged with an ASID, so switching
+//! between address spaces does not need a TLB flush. ASIDs are handed out in
+//! order and never reused within a generation. When they run out the generation
+//! advances, and each address space takes a fresh ASID the next time it is
+//! switched to.
+//!
+//! Other cores may still be running with ASIDs from the previous generation, so
+//! the ASID active on each core is reserved across the rollover. Address spaces
+//! holding a reserved ASID keep it, and it is not handed out again. Each core
+//! flushes its TLB the next time it switches address space, before it can load
+//! an ASID that has been reused.
+
+use super::MAX_CORES;
+
+/// Number of ASIDs distinguished by the TLB.
+pub const ASID_LIMIT: u32 = 1 << 16;
+
+/// An ASID, and the generation it was allocated in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Asid {
+    generation: u64,
+    value: u16,
+}
+
+impl Asid {
+    /// Reserved for the kernel directory, whose pages are all global.
+    pub const KERNEL: Asid = Asid {
+        generation: 0,
+        value: 0,
+    };
+
+    /// Identifier to load into the translation table base register.
+    pub fn value(&self) -> u16 {
+        self.value
+    }
+}
+
+/// Hands out ASIDs, starting a new generation when they run out.
+#[derive(Debug)]
+pub struct AsidAllocator {
+    generation: u64,
+    next: u32,
+    limit: u32,
+    active: [Option<Asid>; MAX_CORES], // loaded on each core, None since a rollover or release
+    reserved: [Option<Asid>; MAX_CORES], // still in use by a core at the last rollover
+    flush_pending: [bool; MAX_CORES],  // core has not flushed its TLB since a rollover
+}
+
+impl AsidAllocator {
+    /// Allocator of ASIDs below a limit.
+    pub const fn new(limit: u32) -> Self {
+        Self {
+            generation: 1,
+            next: 1,
+            limit,
+            active: [None; MAX_CORES],
+            reserved: [None; MAX_CORES],
+            flush_pending: [false; MAX_CORES],
+        }
+    }
+
+    /// Return the ASID an address space should use on a core, and whether the core's TLB
+    /// must be flushed first.
+    ///
+    /// An ASID from an earlier generation may have been given to another address
+    /// space since, so it is replaced unless it was reserved.
+    pub fn refresh(&mut self, core: usize, asid: Option<Asid>) -> (Asid, bool) {
+        let result = match asid {
+            Some(asid) if asid.generation == self.generation => asid,
+            Some(asid) if self.is_reserved(asid) => self.keep_reserved(asid),
+            _ => self.alloc(),
+        };
+        self.active[core] = Some(result);
+        let flush = self.flush_pending[core];
+        self.flush_pending[core] = false;
+        (result, flush)
+    }
+
+    /// A core has loaded the kernel's directory, so no longer runs its user ASID.
+    pub fn release(&mut self, core: usize) {
+        self.active[core] = None;
+        self.reserved[core] = None;
+    }
+
+    /// Whether an ASID may still be loaded on some core.
+    pub fn is_loaded(&self, asid: Asid) -> bool {
+        (0..MAX_CORES).any(|core| self.active[core].or(self.reserved[core]) == Some(asid))
+    }
+
+    /// Whether an ASID was in use on some core at the last rollover.
+    fn is_reserved(&self, asid: Asid) -> bool {
+        self.reserved
+            .iter()
+            .flatten()
+            .any(|reserved| *reserved == asid)
+    }
+
+    /// Carry a reserved ASID into the current generation.
+    fn keep_reserved(&mut self, asid: Asid) -> Asid {
+        let result = Asid {
+            generation: self.generation,
+            value: asid.value,
+        };
+        for reserved in self.reserved.iter_mut() {
+            if *reserved == Some(asid) {
+                *reserved = Some(result);
+            }
+        }
+        result
+    }
+
+    /// Take the next ASID which is not reserved, starting a new generation if there are none.
+    fn alloc(&mut self) -> Asid {
+        loop {
+            if self.next == self.limit {
+                self.rollover();
+            }
+            let value = self.next as u16;
+            self.next += 1;
+            let mut reserved = self.reserved.iter().flatten();
+            if !reserved.any(|reserved| reserved.value == value) {
+                return Asid {
+                    generation: self.generation,
+                    value,
+                };
+            }
+        }
+    }
+
+    /// Start a new generation, reserving the ASIDs which are active on each core.
+    fn rollover(&mut self) {
+        info!("rollover: {}", self.generation);
+        self.generation += 1;
+        self.next = 1;
+        for core in 0..MAX_CORES {
+            // a core which hasn't switched since the last rollover still runs its reserved ASID
+            if let Some(active) = self.active[core].take() {
+                self.reserved[core] = Some(active);
+            }
+            self.flush_pending[core] = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollover() {
+        let mut allocator = AsidAllocator::new(3);
+        let (a, flush) = allocator.refresh(0, None);
+        assert_eq!(1, a.value());
+        assert!(!flush);
+        let (b, flush) = allocator.refresh(0, None);
+        assert_eq!(2, b.value());
+        assert!(!flush);
+        assert_eq!((a, false), allocator.refresh(0, Some(a)));
+
+        // out of ASIDs, so c starts the next generation, avoiding a which is still active
+        let (c, flush) = allocator.refresh(0, None);
+        assert_eq!(2, c.value());
+        assert!(flush);
+        let (a2, flush) = allocator.refresh(0, Some(a));
+        assert_eq!(1, a2.value());
+        assert!(!flush);
+        assert_ne!(a, a2);
+        assert_eq!((a2, false), allocator.refresh(0, Some(a2)));
+        assert_eq!((c, false), allocator.refresh(0, Some(c)));
+        assert_ne!(Asid::KERNEL.value(), c.value());
+    }
+
+    #[test]
+    fn reserved_across_cores() {
+        let mut allocator = AsidAllocator::new(5);
+        let (a, _) = allocator.refresh(0, None);
+        let (b, _) = allocator.refresh(1, None);
+        allocator.refresh(0, None);
+        let (d, _) = allocator.refresh(0, None);
+        assert_eq!(4, d.value());
+
+        // b and d are still running, so their values are not reused
+        let (e, flush) = allocator.refresh(0, None);
+        assert_eq!(1, e.value());
+        assert!(flush);
+        let (a2, flush) = allocator.refresh(0, Some(a));
+        assert_eq!(3, a2.value());
+        assert!(!flush);
+
+        // b keeps its value, and core 1 flushes once after the rollover
+        let (b2, flush) = allocator.refresh(1, Some(b));
+        assert_eq!(b.value(), b2.value());
+        assert_ne!(b, b2);
+        assert!(flush);
+        assert_eq!((b2, false), allocator.refresh(1, Some(b2)));
+    }
+
+    #[test]
+    fn loaded() {
+        let mut allocator = AsidAllocator::new(4);
+        let (a, _) = allocator.refresh(1, None);
+        let (b, _) = allocator.refresh(0, None);
+        assert!(allocator.is_loaded(a) && allocator.is_loaded(b));
+        allocator.refresh(0, None);
+        assert!(!allocator.is_loaded(b));
+
+        // a is still loaded on core 1 after a rollover, until it is released
+        let (_, flush) = allocator.refresh(0, None);
+        assert!(flush);
+        assert!(allocator.is_loaded(a));
+        allocator.release(1);
+        assert!(!allocator.is_loaded(a));
+    }
+}
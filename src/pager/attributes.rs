@@ -1,6 +1,11 @@
 // SPDX-License-Identifier: Unlicense
 
+use crate::archs::Mapping;
+use crate::pager::{AddrRange, PhysAddrRange};
+use crate::{Error, Result};
+
 use core::fmt::{Debug, Formatter};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 /// Flags for page attributes.
 #[derive(Copy, Clone)]
@@ -19,9 +24,9 @@ pub enum AttributeField {
     KernelExec,
     /// Strongly-ordered memory consistency
     Device,
-    /// Don't allocate cache on writes
+    /// Don't allocate cache on writes, or for devices, acknowledge writes early
     StreamOut,
-    /// Don't allocate cache on reads
+    /// Don't cache at all, or for devices, allow reordering, and gathering with StreamOut
     StreamIn,
     /// Allocate memory in blocks because pages can't fault individually
     Block,
@@ -34,10 +39,12 @@ pub enum AttributeField {
     /// Specifically, the RAM mapping at the bottom of kernel memory bypasses the frame table and
     /// device memory is not backed by physical ram.
     SuppressMapCount,
+    /// Frame is shared read-only, and must be copied to a private frame when written
+    CopyOnWrite,
 }
 
 /// Bit flags for page attributes.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub struct Attributes(u64);
 
 impl Debug for Attributes {
@@ -55,6 +62,7 @@ impl Debug for Attributes {
             (UserWrite, "W"),
             (UserExec, "X"),
             (Accessed, " A"),
+            (CopyOnWrite, " CoW"),
         ];
 
         write!(f, "Attributes(").unwrap();
@@ -91,6 +99,11 @@ impl Attributes {
     pub const fn set(self, field: AttributeField) -> Self {
         Self(self.0 | (1 << (field as u64)))
     }
+
+    /// Clear a specific attribute flag so it will be read as absent.
+    pub const fn clear(self, field: AttributeField) -> Self {
+        Self(self.0 & !(1 << (field as u64)))
+    }
 }
 
 impl core::ops::BitOr<AttributeField> for Attributes {
@@ -119,7 +132,13 @@ impl Attributes {
         .set(KernelRead)
         .set(KernelWrite)
         .set(OnDemand);
-    /// For kernel identity map on init, and Branch tables
+    /// For the shared zero frame, until an on-demand page is first written
+    pub const KERNEL_ZERO: Attributes = Attributes::new()
+        .set(KernelRead)
+        .set(Accessed)
+        .set(SuppressMapCount)
+        .set(CopyOnWrite);
+    /// For kernel identity map on init, and Branch tables, but forbidden for pages after boot
     pub const KERNEL_RWX: Attributes = Attributes::new()
         .set(KernelRead)
         .set(KernelWrite)
@@ -132,6 +151,16 @@ impl Attributes {
         .set(Device)
         .set(Block)
         .set(SuppressMapCount);
+    /// For DMA buffers, counted so the frames return to the pool when unmapped
+    ///
+    /// Not cached, so devices and the kernel see the same contents.
+    pub const DMA: Attributes = Attributes::new()
+        .set(KernelRead)
+        .set(KernelWrite)
+        .set(StreamIn)
+        .set(StreamOut)
+        .set(Block)
+        .set(Accessed);
     /// For user-space branch tables
     pub const USER_RWX: Attributes = Attributes::new().set(UserRead).set(UserWrite).set(UserExec);
     /// For user process code
@@ -141,3 +170,133 @@ impl Attributes {
     /// For user process data
     pub const USER_DATA: Attributes = Attributes::new().set(UserRead).set(UserWrite);
 }
+
+/// Whether new mappings are checked against the policy, which boot relaxes.
+static POLICY_ENFORCED: AtomicBool = AtomicBool::new(false);
+
+/// Reject new mappings which break the policy, from the end of boot.
+pub fn enforce_policy() {
+    major!("enforce_policy");
+    POLICY_ENFORCED.store(true, Ordering::Release);
+}
+
+impl Attributes {
+    /// Whether any thread may write through the mapping.
+    pub const fn is_writable(self) -> bool {
+        self.is_set(KernelWrite) || self.is_set(UserWrite)
+    }
+
+    /// Whether any thread may execute through the mapping.
+    pub const fn is_executable(self) -> bool {
+        self.is_set(KernelExec) || self.is_set(UserExec)
+    }
+
+    /// Check the attributes against the policy for any mapping.
+    ///
+    /// No page may be both writable and executable, and device memory may not be
+    /// executed or reached from user space.
+    pub fn check_policy(self) -> Result<()> {
+        let user = self.is_set(UserRead) || self.is_set(UserWrite) || self.is_set(UserExec);
+        if (self.is_writable() && self.is_executable())
+            || (self.is_set(Device) && (user || self.is_executable()))
+        {
+            return Err(Error::ForbiddenAttributes);
+        }
+        Ok(())
+    }
+
+    /// Check the attributes of a new mapping, once the policy is enforced.
+    pub fn validate(self) -> Result<()> {
+        if POLICY_ENFORCED.load(Ordering::Acquire) {
+            self.check_policy()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Check existing mappings against the policy.
+///
+/// Pages of a never-writable range, like the kernel text and statics, must also
+/// not be writable, and neither may any other page mapping the same frames.
+pub fn audit_mappings(
+    mappings: impl Iterator<Item = Mapping>,
+    never_writable: bool,
+    never_writable_frames: &[PhysAddrRange],
+) -> Result<()> {
+    for (virt_addr_range, phys_addr_range, attributes, _) in mappings {
+        let is_alias = phys_addr_range.map_or(false, |phys_addr_range| {
+            never_writable_frames.iter().any(|frames| {
+                frames
+                    .intersection(&phys_addr_range)
+                    .map_or(false, |overlap| overlap.length() > 0)
+            })
+        });
+        let result = if (never_writable || is_alias) && attributes.is_writable() {
+            Err(Error::ForbiddenAttributes)
+        } else {
+            attributes.check_policy()
+        };
+        if let Err(e) = result {
+            error!("audit_mappings: {:?} {:?}", virt_addr_range, attributes);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::{PhysAddr, VirtAddr, VirtAddrRange};
+
+    #[test]
+    fn policy() {
+        assert_ok!(Attributes::KERNEL_EXEC.check_policy());
+        assert_ok!(Attributes::KERNEL_DATA.check_policy());
+        assert_ok!(Attributes::DEVICE.check_policy());
+        assert_ok!(Attributes::DMA.check_policy());
+        assert_ok!(Attributes::USER_DATA.check_policy());
+        assert_err!(Attributes::KERNEL_RWX.check_policy());
+        assert_err!(Attributes::USER_RWX.check_policy());
+        assert_err!((Attributes::USER_EXEC | KernelWrite).check_policy());
+        assert_err!((Attributes::DEVICE | UserRead).check_policy());
+        assert_err!((Attributes::DEVICE | KernelExec).check_policy());
+    }
+
+    fn mapping(attributes: Attributes) -> Mapping {
+        (
+            VirtAddrRange::new(VirtAddr::at(0x1000), 0x1000),
+            None,
+            attributes,
+            3,
+        )
+    }
+
+    #[test]
+    fn audit() {
+        let text = [mapping(Attributes::KERNEL_EXEC)];
+        assert_ok!(audit_mappings(text.iter().copied(), true, &[]));
+        let data = [mapping(Attributes::KERNEL_DATA)];
+        assert_ok!(audit_mappings(data.iter().copied(), false, &[]));
+        assert_err!(audit_mappings(data.iter().copied(), true, &[]));
+        let rwx = [mapping(Attributes::KERNEL_DATA), mapping(Attributes::KERNEL_RWX)];
+        assert_err!(audit_mappings(rwx.iter().copied(), false, &[]));
+    }
+
+    #[test]
+    fn audit_aliases() {
+        let image = [PhysAddrRange::new(PhysAddr::at(0x4000), 0x2000)];
+        let alias = |base, attributes| {
+            let phys_addr_range = PhysAddrRange::new(PhysAddr::at(base), 0x1000);
+            let (virt_addr_range, _, _, level) = mapping(attributes);
+            [(virt_addr_range, Some(phys_addr_range), attributes, level)]
+        };
+        let aliased = alias(0x5000, Attributes::RAM);
+        assert_err!(audit_mappings(aliased.iter().copied(), false, &image));
+        let beyond = alias(0x6000, Attributes::RAM);
+        assert_ok!(audit_mappings(beyond.iter().copied(), false, &image));
+        let read_only = alias(0x5000, Attributes::RAM.clear(KernelWrite));
+        assert_ok!(audit_mappings(read_only.iter().copied(), false, &image));
+    }
+}
@@ -0,0 +1,235 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Ranges of the address space backed by sectors of a block device.
+//!
+//! Pages are read from the device the first time they are touched. Pages which
+//! have been written are written back when the mapping is flushed, or when their
+//! frame is evicted. Evicted pages are read from the device again on next access.
+
+use super::{
+    frames, layout, mem_fixed_offset, mem_translation, swap, swap::wait, swap::SECTORS_PER_PAGE,
+    Addr, AddrRange, AttributeField, Attributes, FixedOffset, FramePurpose, PhysAddr, RangeContent,
+    VirtAddr, VirtAddrRange, KERNEL_PAGE_DIRECTORY, PAGESIZE_BYTES,
+};
+
+use crate::archs::PageDirectory;
+use crate::device::{Block, Sector, BLOCK_DEVICES};
+use crate::util::locked::Locked;
+use crate::{Error, Result};
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+
+type Disk = Arc<Locked<Box<dyn Block + Send>>>;
+
+/// A range of pages mapped to consecutive sectors of a device.
+struct Region {
+    virt_addr_range: VirtAddrRange,
+    disk: Disk,
+    start_sector: u64,
+    attributes: Attributes,
+}
+
+impl Region {
+    /// First sector of the page containing an address.
+    fn sector(&self, virt_addr: VirtAddr) -> Sector {
+        let page = (virt_addr
+            .page_base()
+            .offset_above(self.virt_addr_range.base())
+            / PAGESIZE_BYTES) as u64;
+        Sector(self.start_sector + page * SECTORS_PER_PAGE)
+    }
+
+    fn read_page(&self, phys_addr: PhysAddr, virt_addr: VirtAddr) -> Result<()> {
+        debug!("read_page: {:?} <- {:?}", phys_addr, virt_addr);
+        let mut disk = self.disk.lock();
+        let id = disk.read(&[phys_addr], self.sector(virt_addr))?;
+        wait(&mut disk, id)
+    }
+
+    fn write_page(&self, phys_addr: PhysAddr, virt_addr: VirtAddr) -> Result<()> {
+        debug!("write_page: {:?} -> {:?}", phys_addr, virt_addr);
+        let mut disk = self.disk.lock();
+        let id = disk.write(&[phys_addr], self.sector(virt_addr))?;
+        wait(&mut disk, id)
+    }
+}
+
+/// Regions currently mapped.
+static REGIONS: Locked<Vec<Arc<Region>>> = Locked::new(Vec::new());
+
+fn region_containing(virt_addr: VirtAddr) -> Result<Arc<Region>> {
+    REGIONS
+        .lock()
+        .iter()
+        .find(|region| region.virt_addr_range.contains(virt_addr))
+        .cloned()
+        .ok_or(Error::SegmentFault)
+}
+
+/// A range of kernel addresses backed by a block device.
+///
+/// Dirty pages are written back and the range is unmapped on Drop.
+pub struct BlockMapping {
+    region: Arc<Region>,
+}
+
+impl BlockMapping {
+    /// Get the range.
+    pub fn range(&self) -> VirtAddrRange {
+        self.region.virt_addr_range
+    }
+
+    /// Get the base address of the range.
+    pub fn base(&self) -> VirtAddr {
+        self.region.virt_addr_range.base()
+    }
+
+    /// Write pages which have changed back to the device.
+    pub fn flush(&self) -> Result<()> {
+        info!("flush: {:?}", self.region.virt_addr_range);
+        let mut page = self.region.virt_addr_range.resize(PAGESIZE_BYTES);
+        for _ in 0..self.region.virt_addr_range.length_in_pages() {
+            // a page being evicted is written back by the eviction
+            frames::wait_for_paging_out(page.base())?;
+            let maps_to = KERNEL_PAGE_DIRECTORY
+                .lock()
+                .maps_to(page.base(), mem_fixed_offset());
+            if let Ok(phys_addr) = maps_to {
+                if frames::allocator().lock().is_dirty(phys_addr)? {
+                    // FIXME: Race condition - a write between these steps is lost
+                    self.region.write_page(phys_addr, page.base())?;
+                    frames::allocator().lock().clear_dirty(phys_addr)?;
+                }
+            }
+            page = page.step();
+        }
+        let mut disk = self.region.disk.lock();
+        let id = disk.flush()?;
+        wait(&mut disk, id)
+    }
+}
+
+impl Drop for BlockMapping {
+    fn drop(&mut self) {
+        major!("drop: {:?}", self.region.virt_addr_range);
+        if let Err(e) = self.flush() {
+            error!("BlockMapping::flush: {:?}", e);
+        }
+        REGIONS
+            .lock()
+            .retain(|region| !Arc::ptr_eq(region, &self.region));
+
+        // only the pages which were touched are mapped
+        let mut page = self.region.virt_addr_range.resize(PAGESIZE_BYTES);
+        for _ in 0..self.region.virt_addr_range.length_in_pages() {
+            // an eviction which fails maps its page again
+            frames::wait_for_paging_out(page.base()).expect("frames::wait_for_paging_out");
+            let mut page_directory = KERNEL_PAGE_DIRECTORY.lock();
+            if page_directory
+                .maps_to(page.base(), mem_fixed_offset())
+                .is_ok()
+            {
+                page_directory
+                    .unmap(page, frames::allocator(), mem_fixed_offset())
+                    .expect("PageDirectory::unmap");
+            }
+            page = page.step();
+        }
+    }
+}
+
+/// Map a range of the block-mapped area to sectors of a named block device.
+///
+/// Nothing is read until the pages are touched.
+pub(in crate::pager) fn map(
+    virt_addr_range: VirtAddrRange,
+    device_name: &str,
+    start_sector: u64,
+    attributes: Attributes,
+) -> Result<BlockMapping> {
+    major!(
+        "map: {:?} {} {}",
+        virt_addr_range,
+        device_name,
+        start_sector
+    );
+    if virt_addr_range.length() == 0
+        || !virt_addr_range.is_aligned(PAGESIZE_BYTES)
+        || !layout::get_range(RangeContent::BlockMapped)?.covers(&virt_addr_range)
+    {
+        return Err(Error::SegmentFault);
+    }
+
+    let disk = BLOCK_DEVICES
+        .lock()
+        .get(device_name)
+        .cloned()
+        .ok_or(Error::UnInitialised)?;
+    // every sector of the swap device belongs to swap
+    if swap::is_swap_disk(&disk) {
+        return Err(Error::SegmentFault);
+    }
+    let sectors = virt_addr_range.length_in_pages() as u64 * SECTORS_PER_PAGE;
+    if start_sector + sectors > disk.lock().capacity().0 {
+        return Err(Error::DeviceAtCapacity);
+    }
+
+    let region = Arc::new(Region {
+        virt_addr_range,
+        disk,
+        start_sector,
+        attributes,
+    });
+    let mut regions = REGIONS.lock();
+    let overlaps = regions.iter().any(|other| {
+        other
+            .virt_addr_range
+            .intersection(&virt_addr_range)
+            .map_or(false, |overlap| overlap.length() > 0)
+    });
+    if overlaps {
+        return Err(Error::SegmentFault);
+    }
+    regions.push(region.clone());
+    Ok(BlockMapping { region })
+}
+
+/// Read the page containing a faulting address from its device and map it.
+///
+/// A page read for a write is mapped dirty, otherwise clean so that a later
+/// write is noticed.
+pub(in crate::pager) fn fault(virt_addr: VirtAddr, is_write: bool) -> Result<()> {
+    info!("fault: {:?} {}", virt_addr, is_write);
+    let region = region_containing(virt_addr)?;
+    let page = VirtAddrRange::page_containing(virt_addr);
+    let phys_addr = frames::alloc_for_overwrite(FramePurpose::User)?;
+    // FIXME: Race condition - another core may read the same page
+    let result = region.read_page(phys_addr, page.base()).and_then(|_| {
+        KERNEL_PAGE_DIRECTORY.lock().map_translation(
+            page,
+            FixedOffset::new(phys_addr, page.base()),
+            region.attributes.set(AttributeField::Accessed),
+            frames::allocator(),
+            mem_translation(),
+        )
+    });
+    if let Err(e) = result {
+        // the frame was never mapped
+        frames::allocator().lock().discard(phys_addr)?;
+        return Err(e);
+    }
+    let mut allocator = frames::allocator().lock();
+    allocator.set_block_mapped(phys_addr)?;
+    if !is_write {
+        allocator.clear_dirty(phys_addr)?;
+    }
+    Ok(())
+}
+
+/// Write an evicted page back to its device.
+///
+/// Called with the page already unmapped, and the frame table not locked.
+pub(in crate::pager) fn write_back(virt_addr: VirtAddr, phys_addr: PhysAddr) -> Result<()> {
+    info!("write_back: {:?} {:?}", virt_addr, phys_addr);
+    region_containing(virt_addr)?.write_page(phys_addr, virt_addr.page_base())
+}
@@ -0,0 +1,256 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Checkpoints of the persistent area to the swap device, so it survives a restart.
+//!
+//! A checkpoint writes each page of the persistent area which has changed to its
+//! swap slot, then an index of the slot holding each page. The header in the
+//! reserved first slot is written last, so an interrupted checkpoint leaves the
+//! previous one in place. At boot the pages of the last checkpoint are mapped as
+//! swapped out, and read back when they are touched.
+//!
+//! The index records pages by their offset in the persistent area, not by address.
+//!
+//! The slots of the last checkpoint are never written. A page which has changed
+//! since is written to a fresh slot, whether by the next checkpoint or when it is
+//! evicted, and the slots which are no longer needed are released once the next
+//! checkpoint has been written.
+
+use super::{
+    frames, layout, mem_fixed_offset, mem_translation, swap, swap::SECTORS_PER_PAGE, Addr,
+    AddrRange, Attributes, FramePurpose, PhysAddr, RangeContent, Translate, KERNEL_PAGE_DIRECTORY,
+    PAGESIZE_BYTES,
+};
+
+use crate::archs::PageDirectory;
+use crate::{Error, Result};
+
+use alloc::vec::Vec;
+
+use core::num::NonZeroU64;
+
+/// Identifies the header of a checkpoint.
+const MAGIC: u64 = 0x5452_4e50_4b43_4843;
+
+const WORDS_PER_PAGE: usize = PAGESIZE_BYTES / 8;
+
+/// Magic, number of pages, and number of index slots, followed by the index slots.
+const HEADER_WORDS: usize = 3;
+
+/// Most index slots which can be listed in the header, before the swap signature.
+const MAX_INDEX_SLOTS: usize = swap::HEADER_BYTES / 8 - HEADER_WORDS;
+
+type Words = [u64; WORDS_PER_PAGE];
+
+/// An index entry: a page's number within the persistent area, and its swap slot.
+fn encode(page: usize, sector: NonZeroU64) -> u64 {
+    (page as u64) << 32 | sector.get() / SECTORS_PER_PAGE
+}
+
+fn decode(entry: u64) -> Result<(usize, NonZeroU64)> {
+    let sector = (entry & 0xffff_ffff) * SECTORS_PER_PAGE;
+    let sector = NonZeroU64::new(sector).ok_or(Error::UnexpectedValue)?;
+    Ok(((entry >> 32) as usize, sector))
+}
+
+/// A frame to build or read a header or index page in, freed on Drop.
+struct Scratch(PhysAddr);
+
+impl Scratch {
+    fn new() -> Result<Self> {
+        let phys_addr = frames::alloc_zeroed(FramePurpose::Kernel)?;
+        Ok(Self(phys_addr))
+    }
+
+    fn words(&mut self) -> Result<&mut Words> {
+        unsafe { Ok(mem_translation().translate_phys(self.0)?.as_mut_ref()) }
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        frames::allocator()
+            .lock()
+            .discard(self.0)
+            .expect("FrameTable::discard");
+    }
+}
+
+/// Where a page of the persistent area is held.
+enum Location {
+    Resident(PhysAddr),
+    Swapped(NonZeroU64),
+}
+
+/// The number of each page in the persistent area which has been touched, and where it is.
+fn persistent_pages() -> Result<Vec<(usize, Location)>> {
+    let persistent = layout::get_range(RangeContent::Persistent)?;
+    let page_directory = KERNEL_PAGE_DIRECTORY.lock();
+    let mut result = Vec::new();
+    for (virt_addr_range, phys_addr_range, _, _) in
+        page_directory.mappings(persistent, mem_fixed_offset())?
+    {
+        let mut page = virt_addr_range.resize(PAGESIZE_BYTES);
+        for n in 0..virt_addr_range.length_in_pages() {
+            let i = page.base().offset_above(persistent.base()) / PAGESIZE_BYTES;
+            let location = match phys_addr_range {
+                Some(phys_addr_range) => {
+                    Location::Resident(phys_addr_range.base().increment(n * PAGESIZE_BYTES))
+                }
+                None => Location::Swapped(
+                    page_directory
+                        .swapped_out(page.base(), mem_fixed_offset())?
+                        .ok_or(Error::UnexpectedValue)?,
+                ),
+            };
+            result.push((i, location));
+            page = page.step();
+        }
+    }
+    Ok(result)
+}
+
+/// Write the persistent area to the swap device.
+pub(in crate::pager) fn checkpoint() -> Result<()> {
+    major!("checkpoint");
+    let persistent = layout::get_range(RangeContent::Persistent)?;
+
+    // no disk I/O while the page directory is locked
+    let mut entries = Vec::new();
+    for (i, location) in persistent_pages()? {
+        let sector = match location {
+            Location::Resident(phys_addr) => {
+                let persisted = frames::allocator().lock().persisted(phys_addr)?;
+                let dirty =
+                    persisted.is_none() || frames::allocator().lock().is_dirty(phys_addr)?;
+                let sector = match persisted {
+                    Some(sector) if !dirty || !swap::is_checkpointed(sector) => sector,
+                    _ => {
+                        // the last checkpoint keeps its copy until this one is written
+                        let sector = swap::alloc_sector()?;
+                        frames::allocator()
+                            .lock()
+                            .set_persisted(phys_addr, sector)?;
+                        if let Some(previous) = persisted {
+                            swap::release(previous)?;
+                        }
+                        sector
+                    }
+                };
+                if dirty {
+                    // FIXME: Race condition - a write between these steps is lost
+                    swap::write_page(phys_addr, sector)?;
+                    frames::allocator().lock().clear_dirty(phys_addr)?;
+                }
+                sector
+            }
+            Location::Swapped(sector) => {
+                // the index can't refer to a slot until its page has been written
+                frames::wait_for_paging_out(persistent.base().increment(i * PAGESIZE_BYTES))?;
+                sector
+            }
+        };
+        entries.push(encode(i, sector));
+    }
+    info!("{} pages", entries.len());
+
+    let chunks = entries.chunks(WORDS_PER_PAGE);
+    if chunks.len() > MAX_INDEX_SLOTS {
+        return Err(Error::DeviceAtCapacity);
+    }
+    let mut index_sectors = Vec::new();
+    let mut scratch = Scratch::new()?;
+    for chunk in chunks {
+        let words = scratch.words()?;
+        words.fill(0);
+        words[..chunk.len()].copy_from_slice(chunk);
+        let sector = swap::alloc_sector()?;
+        index_sectors.push(sector);
+        swap::write_page(scratch.0, sector)?;
+    }
+    swap::flush()?;
+
+    let words = scratch.words()?;
+    words.fill(0);
+    words[0] = MAGIC;
+    words[1] = entries.len() as u64;
+    words[2] = index_sectors.len() as u64;
+    for (word, sector) in words[HEADER_WORDS..].iter_mut().zip(index_sectors.iter()) {
+        *word = sector.get();
+    }
+    swap::write_header(scratch.0)?;
+    swap::flush()?;
+
+    // the index slots belong to the checkpoint alone
+    let mut sectors: Vec<NonZeroU64> = entries
+        .iter()
+        .map(|entry| decode(*entry).map(|(_, sector)| sector))
+        .collect::<Result<_>>()?;
+    sectors.extend(index_sectors.iter());
+    swap::set_checkpoint(&sectors)?;
+    for sector in index_sectors {
+        swap::release(sector)?;
+    }
+    Ok(())
+}
+
+/// Map the pages of the last checkpoint, if there is one, to be reloaded on demand.
+pub(in crate::pager) fn restore() -> Result<()> {
+    major!("restore");
+    let persistent = layout::get_range(RangeContent::Persistent)?;
+
+    let mut scratch = Scratch::new()?;
+    swap::read_header(scratch.0)?;
+    let words = scratch.words()?;
+    if words[0] != MAGIC {
+        info!("no checkpoint");
+        return Ok(());
+    }
+    let pages = words[1] as usize;
+    let index_slots = words[2] as usize;
+    if index_slots > MAX_INDEX_SLOTS || pages > index_slots * WORDS_PER_PAGE {
+        return Err(Error::UnexpectedValue);
+    }
+    let index_sectors = words[HEADER_WORDS..HEADER_WORDS + index_slots]
+        .iter()
+        .map(|sector| NonZeroU64::new(*sector).ok_or(Error::UnexpectedValue))
+        .collect::<Result<Vec<NonZeroU64>>>()?;
+    info!("{} pages", pages);
+
+    let mut sectors = index_sectors.clone();
+    let mut remaining = pages;
+    for index_sector in index_sectors.iter() {
+        swap::read_page(scratch.0, *index_sector)?;
+        let count = core::cmp::min(remaining, WORDS_PER_PAGE);
+        for entry in scratch.words()?[..count].iter() {
+            let (i, sector) = decode(*entry)?;
+            let virt_addr = persistent.base().increment(i * PAGESIZE_BYTES);
+            if !persistent.contains(virt_addr) {
+                return Err(Error::UnexpectedValue);
+            }
+            swap::reserve(sector)?;
+            sectors.push(sector);
+            KERNEL_PAGE_DIRECTORY.lock().map_swapped(
+                virt_addr,
+                sector,
+                Attributes::KERNEL_DATA,
+                frames::allocator(),
+                mem_fixed_offset(),
+            )?;
+        }
+        remaining -= count;
+    }
+    swap::set_checkpoint(&sectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_entry() {
+        let sector = NonZeroU64::new(3 * SECTORS_PER_PAGE).unwrap();
+        let entry = encode(5, sector);
+        assert_ok_eq!(decode(entry), (5, sector));
+        assert_err!(decode(5 << 32));
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Physical segments of kernel buffers, for devices to transfer to or from directly.
+//!
+//! A device may write to any of the pages, so they must be privately writable, and
+//! are marked dirty. Their frames are pinned by counting an extra mapping of each,
+//! so they are neither evicted nor freed until the segments are released.
+
+use super::{
+    Addr, AddrRange, AttributeField, PhysAddr, PhysAddrRange, VirtAddrRange, PAGESIZE_BYTES,
+};
+
+use crate::archs::Mapping;
+use crate::{Error, Result};
+
+use alloc::vec::Vec;
+
+/// Merge the mappings of a range into the physical segments backing it, in order.
+///
+/// Fails if any part of the range is not resident, or not writable through a
+/// counted mapping of its own frame.
+pub(in crate::pager) fn segments(
+    virt_addr_range: VirtAddrRange,
+    mappings: impl Iterator<Item = Mapping>,
+) -> Result<Vec<PhysAddrRange>> {
+    use AttributeField::*;
+
+    let mut result: Vec<PhysAddrRange> = Vec::new();
+    let mut covered = virt_addr_range.base();
+    for (mapped, phys_addr_range, attributes, _) in mappings {
+        // a gap, or a swapped-out page
+        let phys_addr_range = match phys_addr_range {
+            Some(phys_addr_range) if mapped.base() == covered => phys_addr_range,
+            _ => return Err(Error::SegmentFault),
+        };
+        if !attributes.is_set(KernelWrite)
+            || attributes.is_set(CopyOnWrite)
+            || attributes.is_set(SuppressMapCount)
+        {
+            return Err(Error::SegmentFault);
+        }
+        covered = mapped.top();
+        match result.last_mut() {
+            Some(last) if last.top() == phys_addr_range.base() => {
+                *last = last.resize(last.length() + phys_addr_range.length());
+            }
+            _ => result.push(phys_addr_range),
+        }
+    }
+    if covered != virt_addr_range.top() {
+        return Err(Error::SegmentFault);
+    }
+    Ok(result)
+}
+
+/// Each frame holding part of some segments.
+pub(in crate::pager) fn frames(segments: &[PhysAddrRange]) -> impl Iterator<Item = PhysAddr> + '_ {
+    segments.iter().flat_map(|segment| {
+        PhysAddrRange::between(
+            segment.base().align_down(PAGESIZE_BYTES),
+            segment.top().align_up(PAGESIZE_BYTES),
+        )
+        .chunks(PAGESIZE_BYTES)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::{Attributes, VirtAddr};
+
+    fn mapping(virt_addr: usize, phys_addr: Option<usize>, length: usize) -> Mapping {
+        (
+            VirtAddrRange::new(VirtAddr::at(virt_addr), length),
+            phys_addr.map(|phys_addr| PhysAddrRange::new(PhysAddr::at(phys_addr), length)),
+            Attributes::KERNEL_DATA,
+            3,
+        )
+    }
+
+    #[test]
+    fn merge() {
+        let range = VirtAddrRange::new(VirtAddr::at(0x1800), 0x2000);
+        let mappings = [
+            mapping(0x1800, Some(0x5800), 0x800),
+            mapping(0x2000, Some(0x6000), 0x1000),
+            mapping(0x3000, Some(0x9000), 0x800),
+        ];
+        let segments = segments(range, mappings.iter().copied()).unwrap();
+        assert_eq!(2, segments.len());
+        assert_eq!(PhysAddrRange::new(PhysAddr::at(0x5800), 0x1800), segments[0]);
+        assert_eq!(PhysAddrRange::new(PhysAddr::at(0x9000), 0x800), segments[1]);
+    }
+
+    #[test]
+    fn not_resident() {
+        let range = VirtAddrRange::new(VirtAddr::at(0x1000), 0x2000);
+        let gap = [mapping(0x2000, Some(0x6000), 0x1000)];
+        assert_err!(segments(range, gap.iter().copied()));
+        let swapped = [
+            mapping(0x1000, Some(0x5000), 0x1000),
+            mapping(0x2000, None, 0x1000),
+        ];
+        assert_err!(segments(range, swapped.iter().copied()));
+        let short = [mapping(0x1000, Some(0x5000), 0x1000)];
+        assert_err!(segments(range, short.iter().copied()));
+    }
+
+    #[test]
+    fn not_writable() {
+        let range = VirtAddrRange::new(VirtAddr::at(0x1000), 0x1000);
+        for attributes in &[
+            Attributes::KERNEL_RO_DATA,
+            Attributes::KERNEL_ZERO,
+            Attributes::KERNEL_DATA | AttributeField::CopyOnWrite,
+            Attributes::RAM,
+        ] {
+            let (virt_addr_range, phys_addr_range, _, level) =
+                mapping(0x1000, Some(0x5000), 0x1000);
+            let mappings = [(virt_addr_range, phys_addr_range, *attributes, level)];
+            assert_err!(segments(range, mappings.iter().copied()));
+        }
+    }
+
+    #[test]
+    fn segment_frames() {
+        let segments = [
+            PhysAddrRange::new(PhysAddr::at(0x5800), 0x1800),
+            PhysAddrRange::new(PhysAddr::at(0x9000), 0x800),
+        ];
+        let expected = [0x5000, 0x6000, 0x9000].iter().map(|a| PhysAddr::at(*a));
+        assert!(frames(&segments).eq(expected));
+    }
+}
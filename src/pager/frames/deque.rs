@@ -240,6 +240,20 @@ impl<N: Default + Debug, Q: Copy + Into<u8> + From<u8> + Debug> Deque<N, Q> {
         self.remove_seq_to(i as u32, j, q_to)
     }
 
+    /// Indices on a deque, from head to tail.
+    pub fn iter(&self, q: Q) -> impl Iterator<Item = u32> + '_ {
+        let q = self.queue_entry(q);
+        let mut i = self.table[q].next as usize;
+        core::iter::from_fn(move || {
+            if i == q {
+                return None;
+            }
+            let item = i as u32;
+            i = self.table[i].next as usize;
+            Some(item)
+        })
+    }
+
     pub fn clear_to(&mut self, q_from: Q, q_to: Q) -> Result<(u32, u32)> {
         let (i, j) = self.seq(q_from);
         let q_from = self.queue_entry(q_from);
@@ -390,6 +404,7 @@ mod tests {
             d.remove_to(7, Q::UserHot).unwrap();
             let result = d.clear_to(Q::UserHot, Q::UserWarm).unwrap();
             assert_eq!(result, (7, 1));
+            assert!(d.iter(Q::UserWarm).eq([7, 1, 8, 0].iter().copied()));
             d.drip_to(Q::UserWarm, Q::UserCold).unwrap();
             d.drip_to(Q::UserWarm, Q::UserCold).unwrap();
             d.drip_n_to(Q::UserCold, 2, Q::Free).unwrap();
@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Pool of physically contiguous frames for DMA.
+//!
+//! A fixed run of frames at the top of RAM is set aside on the DirectMemoryAccess
+//! queue at start-up, and a bitmap records which of them are in use.
+//!
+//! Frames are zeroed through the cacheable RAM mapping once the frame table is
+//! unlocked, so the zeros are cleaned to memory and the lines discarded before a
+//! device or a non-cacheable mapping uses them.
+
+use super::{allocator, zeroing, FrameTable, FrameTableInner};
+
+use crate::archs::{arch::Arch, PagerTrait};
+use crate::pager::{
+    mem_translation, AddrRange, PhysAddr, PhysAddrRange, Translate, VirtAddrRange, PAGESIZE_BYTES,
+};
+use crate::{Error, Result};
+
+const WORDS: usize = 2;
+
+/// Frames reserved for contiguous DMA allocations.
+pub const DMA_POOL_PAGES: u32 = (WORDS * 64) as u32;
+
+/// Bitmap of the frames in use in the DMA pool.
+#[derive(Debug)]
+pub(super) struct DmaPool {
+    first: u32, // frame table index of the first frame in the pool
+    bitmap: [u64; WORDS],
+}
+
+impl DmaPool {
+    pub(super) fn new(first: u32) -> Self {
+        Self {
+            first,
+            bitmap: [0; WORDS],
+        }
+    }
+
+    fn is_used(&self, n: u32) -> bool {
+        self.bitmap[(n / 64) as usize] & (1 << (n % 64)) != 0
+    }
+
+    fn set(&mut self, n: u32, used: bool) {
+        let (word, bit) = ((n / 64) as usize, n % 64);
+        if used {
+            self.bitmap[word] |= 1 << bit;
+        } else {
+            self.bitmap[word] &= !(1 << bit);
+        }
+    }
+
+    /// True iff the frame table index is one of the frames in the pool.
+    pub(super) fn contains(&self, i: u32) -> bool {
+        (self.first..self.first + DMA_POOL_PAGES).contains(&i)
+    }
+
+    /// Reserve the first run of free frames long enough, and return the index of the first.
+    fn alloc(&mut self, pages: u32) -> Result<u32> {
+        if pages == 0 || pages > DMA_POOL_PAGES {
+            return Err(Error::OutOfPages);
+        }
+        let mut run = 0;
+        for n in 0..DMA_POOL_PAGES {
+            if self.is_used(n) {
+                run = 0;
+                continue;
+            }
+            run += 1;
+            if run == pages {
+                let base = n + 1 - pages;
+                for m in base..=n {
+                    self.set(m, true);
+                }
+                return Ok(self.first + base);
+            }
+        }
+        Err(Error::OutOfPages)
+    }
+
+    /// Never allocate a frame from the pool.
+    pub(super) fn reserve(&mut self, i: u32) {
+        self.set(i - self.first, true);
+    }
+
+    /// Return a frame to the pool.
+    pub(super) fn free(&mut self, i: u32) -> Result<()> {
+        let n = i - self.first;
+        if !self.is_used(n) {
+            return Err(Error::UnexpectedValue);
+        }
+        self.set(n, false);
+        Ok(())
+    }
+}
+
+impl FrameTableInner {
+    fn alloc_contiguous(&mut self, pages: u32) -> Result<PhysAddrRange> {
+        let i = self.dma_pool.alloc(pages)?;
+        self.allocs += pages as u64;
+        Ok(PhysAddrRange::new(
+            PhysAddr::ram_page(i as usize),
+            pages as usize * PAGESIZE_BYTES,
+        ))
+    }
+}
+
+impl FrameTable {
+    /// Reserve a range of physically adjacent frames for DMA, not yet zeroed.
+    fn reserve_contiguous(&mut self, pages: u32) -> Result<PhysAddrRange> {
+        info!("reserve_contiguous: {}", pages);
+        self.inner()?.alloc_contiguous(pages)
+    }
+}
+
+/// Reserve and return a range of zeroed, physically adjacent frames for DMA.
+///
+/// The frames are zeroed after the frame table is unlocked, and return to the pool
+/// as their mappings are freed.
+pub fn alloc_contiguous(pages: u32) -> Result<PhysAddrRange> {
+    let phys_addr_range = allocator().lock().reserve_contiguous(pages)?;
+    let zeroed = zero_contiguous(phys_addr_range);
+    if zeroed.is_err() {
+        let mut allocator = allocator().lock();
+        for phys_addr in phys_addr_range.chunks(PAGESIZE_BYTES) {
+            allocator.discard(phys_addr)?;
+        }
+    }
+    zeroed.map(|_| phys_addr_range)
+}
+
+fn zero_contiguous(phys_addr_range: PhysAddrRange) -> Result<()> {
+    for phys_addr in phys_addr_range.chunks(PAGESIZE_BYTES) {
+        zeroing::zero(phys_addr)?;
+    }
+    let virt_addr = mem_translation().translate_phys(phys_addr_range.base())?;
+    Arch::clean_invalidate_dcache(VirtAddrRange::new(virt_addr, phys_addr_range.length()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool() {
+        let mut pool = DmaPool::new(100);
+        assert_ok_eq!(pool.alloc(1), 100);
+        assert_ok_eq!(pool.alloc(4), 101);
+        assert_ok!(pool.free(100));
+        assert_err!(pool.free(100));
+        assert_ok_eq!(pool.alloc(2), 105);
+        assert_ok_eq!(pool.alloc(1), 100);
+        assert_err!(pool.alloc(DMA_POOL_PAGES));
+        assert_err!(pool.alloc(0));
+        assert!(pool.contains(100 + DMA_POOL_PAGES - 1));
+        assert!(!pool.contains(100 + DMA_POOL_PAGES));
+
+        assert_ok!(pool.free(102));
+        pool.reserve(102);
+        assert_ok_eq!(pool.alloc(1), 107);
+    }
+}
@@ -0,0 +1,478 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Evicting user pages to free their frames.
+//!
+//! The least recently used page is unmapped while the frame table is locked, and
+//! its frame moves from Cold to PagingOut. The page is written to swap, or back to
+//! its block device, without the lock, because submitting the request allocates
+//! and translates addresses. Then the frame moves on to Free.
+//!
+//! A clean page of a block mapping which is shared is not written at all. It is
+//! unmapped everywhere and its frame freed, and each mapping reads the page from
+//! the device again on next access. Other shared pages are made warm again.
+//!
+//! Each core writes out one page at a time, so an allocation made while a core is
+//! writing out a page does not evict another. A fault on a page which is being
+//! written out waits for the write to finish before reading the page back.
+//!
+//! Page tables are allocated while the page directory is locked, and the swap
+//! device needs it to translate its requests, so only callers which hold neither
+//! lock may evict.
+//!
+//! Frames set aside for pages the heap has grown into are not counted as free, so
+//! no other allocation takes them, and eviction keeps enough free beyond them.
+
+use super::{allocator, Allocator, FrameTable, FrameTableInner, FrameUse, Purpose};
+
+use crate::archs::{arch, arch::Arch, arch::PageBlockDescriptor, PagerTrait};
+use crate::handler;
+use crate::pager::{block, swap, PhysAddr, VirtAddr};
+use crate::{Error, Result};
+
+use core::num::NonZeroU64;
+use core::ptr::NonNull;
+
+/// Frames to keep free or zeroed by evicting when a core is idle.
+pub const FREE_TARGET: u32 = 32;
+
+/// A page being written out by a core.
+#[derive(Copy, Clone, Debug)]
+pub(super) struct PagingOut {
+    i: u32,
+    virt_addr: VirtAddr,
+    page_block_descriptor: NonNull<PageBlockDescriptor>,
+    saved: PageBlockDescriptor, // the mapping, restored if the write fails
+    sector: Option<NonZeroU64>, // swap slot, or None for a page of a block mapping
+    dirty: bool,
+    released: bool, // the entry was unmapped meanwhile, so the slot is released after the write
+}
+
+/// What became of the coldest user page.
+#[derive(Copy, Clone, Debug)]
+pub(super) enum Evicted {
+    /// Unmapped, and to be written out before its frame is freed.
+    PagingOut(PagingOut),
+    /// Unmapped everywhere, and its frame freed.
+    Dropped,
+}
+
+impl FrameTableInner {
+    /// Take the coldest user page mapped by a single known entry, and unmap it.
+    ///
+    /// Clean shared pages of block mappings are dropped, and other shared pages are
+    /// made warm again. Returns None if there are no cold pages, swap has no room,
+    /// or the calling core is already writing out a page.
+    pub(super) fn start_paging_out(&mut self) -> Result<Option<Evicted>> {
+        let core = arch::core_id() as usize;
+        if self
+            .paging_out
+            .get(core)
+            .ok_or(Error::UnexpectedValue)?
+            .is_some()
+        {
+            return Ok(None);
+        }
+        loop {
+            let i = match self.drip(FrameUse::UserCold, FrameUse::PagingOut) {
+                Ok(i) => i,
+                Err(_) => return Ok(None),
+            };
+            let page_block_descriptor = match self.sole_mapping(i) {
+                Some(page_block_descriptor) => page_block_descriptor,
+                None if self.is_droppable(i) => {
+                    debug!("start_paging_out: dropping {:?}", self.table[i].virt_addr);
+                    // the last mapping frees the frame
+                    self.unmap_everywhere(i)?;
+                    return Ok(Some(Evicted::Dropped));
+                }
+                None => {
+                    self.move_to(i, FrameUse::PagingOut, FrameUse::UserWarm)?;
+                    self.table[i].warm_epoch = self.warm_epoch;
+                    self.user_warm_count += 1;
+                    continue;
+                }
+            };
+            let dirty = self.is_dirty(i);
+            let entry = &self.table[i];
+            let virt_addr = entry.virt_addr;
+            let persisted = entry.persisted;
+            let sector = match (entry.block_mapped, persisted) {
+                (true, _) => None,
+                // the last checkpoint's copy is kept, so a changed page goes to a fresh slot
+                (false, Some(sector)) if !dirty || !swap::is_checkpointed(sector) => Some(sector),
+                (false, _) => match swap::alloc_sector() {
+                    Ok(sector) => Some(sector),
+                    Err(e) => {
+                        info!("no swap slot: {:?}", e);
+                        self.move_to(i, FrameUse::PagingOut, FrameUse::UserCold)?;
+                        return Ok(None);
+                    }
+                },
+            };
+            match persisted {
+                Some(previous) if persisted != sector => swap::release(previous)?,
+                _ => {}
+            }
+            // slot belongs to the frame until the entry takes it over
+            self.table[i].persisted = sector;
+
+            debug!("start_paging_out: {:?} {:?}", virt_addr, sector);
+            let saved = unsafe { *page_block_descriptor.as_ptr() };
+            unsafe {
+                match sector {
+                    Some(sector) => (*page_block_descriptor.as_ptr()).swap_out(sector),
+                    // the next access reads the page from the device again
+                    None => (*page_block_descriptor.as_ptr()).unmap(),
+                }
+            }
+            Arch::invalidate_tlb(virt_addr)?;
+
+            let paging_out = PagingOut {
+                i,
+                virt_addr,
+                page_block_descriptor,
+                saved,
+                sector,
+                dirty,
+                released: false,
+            };
+            let slot = self
+                .paging_out
+                .get_mut(core)
+                .ok_or(Error::UnexpectedValue)?;
+            *slot = Some(paging_out);
+            return Ok(Some(Evicted::PagingOut(paging_out)));
+        }
+    }
+
+    /// Whether a shared frame can be freed without writing it out.
+    ///
+    /// It must hold a page of a block mapping, every mapping of it must be known,
+    /// and none may have written to it.
+    fn is_droppable(&self, i: u32) -> bool {
+        let entry = &self.table[i];
+        entry.block_mapped
+            && self.mappings(i).count() == entry.map_count as usize
+            && !self
+                .mappings(i)
+                .any(|rmap| unsafe { rmap.page_block_descriptor.as_ref().is_dirty() })
+    }
+
+    /// Free the frame of a page which has been written out by the calling core.
+    ///
+    /// If the write failed, the page is mapped again and goes back to Cold.
+    pub(super) fn finish_paging_out(&mut self, result: Result<()>) -> Result<()> {
+        let core = arch::core_id() as usize;
+        let paging_out = self
+            .paging_out
+            .get_mut(core)
+            .and_then(Option::take)
+            .ok_or(Error::UnexpectedValue)?;
+        let i = paging_out.i;
+        if result.is_err() && !paging_out.released {
+            unsafe {
+                *paging_out.page_block_descriptor.as_ptr() = paging_out.saved;
+            }
+            self.move_to(i, FrameUse::PagingOut, FrameUse::UserCold)?;
+            return result;
+        }
+        if let (true, Some(sector)) = (paging_out.released, paging_out.sector) {
+            swap::release(sector)?;
+        }
+
+        self.user_count -= 1;
+        self.frees += 1;
+        let entry = &mut self.table[i];
+        entry.purpose = None;
+        entry.persisted = None;
+        entry.block_mapped = false;
+        entry.page_block_descriptor = None;
+        entry.map_count = 0;
+        // idle cores may zero it
+        handler::send_event();
+        self.move_to(i, FrameUse::PagingOut, FrameUse::Free)?;
+        result
+    }
+
+    /// Note that a swapped-out entry has been unmapped, if its page is still being written.
+    ///
+    /// Returns false if no core is writing to the slot.
+    pub(super) fn release_paging_out(&mut self, sector: NonZeroU64) -> bool {
+        let paging_out = self
+            .paging_out
+            .iter_mut()
+            .flatten()
+            .find(|paging_out| paging_out.sector == Some(sector));
+        match paging_out {
+            Some(paging_out) => {
+                paging_out.released = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether a page is being written out by any core.
+    pub(super) fn is_paging_out(&self, virt_addr: VirtAddr) -> bool {
+        self.paging_out
+            .iter()
+            .flatten()
+            .any(|paging_out| paging_out.virt_addr == virt_addr.page_base())
+    }
+
+    /// Frames which can be allocated without evicting.
+    fn available(&self) -> u32 {
+        self.queue_len(FrameUse::Free) + self.queue_len(FrameUse::Zeroed)
+    }
+
+    /// Frames which can be allocated without evicting or taking those set aside for the heap.
+    pub(super) fn unreserved(&self) -> u32 {
+        self.available().saturating_sub(self.heap_reserved)
+    }
+
+    /// Set aside free or zeroed frames for pages the heap is growing into.
+    fn reserve(&mut self, count: u32) -> Result<()> {
+        if self.unreserved() < count {
+            return Err(Error::OutOfPages);
+        }
+        self.heap_reserved += count;
+        Ok(())
+    }
+
+    /// Allocate a zeroed frame for a page of the heap, from those set aside as it grew.
+    ///
+    /// The pages the heap starts with have no frames set aside, so they are
+    /// allocated like any other.
+    fn alloc_reserved(&mut self) -> Result<PhysAddr> {
+        let reserved = self.heap_reserved > 0;
+        if reserved {
+            self.heap_reserved -= 1;
+        }
+        let result = self.alloc_zeroed(Purpose::Kernel);
+        if result.is_err() && reserved {
+            self.heap_reserved += 1;
+        }
+        result
+    }
+}
+
+impl FrameTable {
+    /// Allocate a zeroed frame for a page the heap has grown into.
+    pub(in crate::pager) fn alloc_reserved(&mut self) -> Result<PhysAddr> {
+        info!("alloc_reserved");
+        self.inner()?.alloc_reserved()
+    }
+
+    /// Free a frame for a page of the heap which was never mapped, and set it aside again.
+    pub(in crate::pager) fn discard_reserved(&mut self, phys_addr: PhysAddr) -> Result<()> {
+        self.discard(phys_addr)?;
+        self.inner()?.heap_reserved += 1;
+        Ok(())
+    }
+}
+
+/// Write a page which has been unmapped to swap, or back to its device.
+fn write(paging_out: &PagingOut) -> Result<()> {
+    let phys_addr = PhysAddr::ram_page(paging_out.i as usize);
+    if !paging_out.dirty {
+        debug!("clean: {:?}", phys_addr);
+        return Ok(());
+    }
+    match paging_out.sector {
+        Some(sector) => swap::write_page(phys_addr, sector),
+        None => block::write_back(paging_out.virt_addr, phys_addr),
+    }
+}
+
+/// Write out the least recently used user page, and free its frame.
+///
+/// Returns false if there was no page to evict. Neither the frame table nor the
+/// page directory may be locked by the caller.
+pub fn evict() -> Result<bool> {
+    let paging_out = match allocator().lock().inner()?.start_paging_out()? {
+        Some(Evicted::PagingOut(paging_out)) => paging_out,
+        Some(Evicted::Dropped) => return Ok(true),
+        None => return Ok(false),
+    };
+    info!("evict: {:?}", paging_out.virt_addr);
+    let result = write(&paging_out);
+    allocator().lock().inner()?.finish_paging_out(result)?;
+    Ok(true)
+}
+
+/// Set aside a number of free or zeroed frames for pages the heap is growing into.
+///
+/// Nothing is evicted, so the caller may hold the page directory lock. No other
+/// allocation takes the frames, so faults on the new pages can always be satisfied.
+pub fn reserve(count: u32) -> Result<()> {
+    info!("reserve: {}", count);
+    allocator().lock().inner()?.reserve(count)
+}
+
+/// Evict cold pages until enough frames are free for allocations which can't evict.
+///
+/// Intended to be called when a core has nothing else to do. Returns the number
+/// of pages evicted.
+pub fn evict_cold_pages() -> Result<u32> {
+    let mut evicted = 0;
+    while allocator().lock().inner()?.unreserved() < FREE_TARGET && evict()? {
+        evicted += 1;
+    }
+    if evicted > 0 {
+        debug!("evict_cold_pages: {}", evicted);
+    }
+    Ok(evicted)
+}
+
+/// Wait until no core is writing out a page.
+pub(in crate::pager) fn wait_for_paging_out(virt_addr: VirtAddr) -> Result<()> {
+    while allocator().lock().inner()?.is_paging_out(virt_addr) {
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Allocate with a function, evicting a page each time frames have run out.
+fn evicting(mut alloc: impl FnMut() -> Result<PhysAddr>) -> Result<PhysAddr> {
+    loop {
+        match alloc() {
+            Err(Error::OutOfPages) => {
+                if !evict()? {
+                    return Err(Error::OutOfPages);
+                }
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Allocate a zeroed frame, evicting user pages if frames have run out.
+///
+/// Neither the frame table nor the page directory may be locked by the caller.
+pub fn alloc_zeroed(purpose: Purpose) -> Result<PhysAddr> {
+    evicting(|| allocator().lock().alloc_zeroed(purpose))
+}
+
+/// Allocate a frame to be overwritten, evicting user pages if frames have run out.
+///
+/// Neither the frame table nor the page directory may be locked by the caller.
+pub fn alloc_for_overwrite(purpose: Purpose) -> Result<PhysAddr> {
+    evicting(|| allocator().lock().alloc_for_overwrite(purpose))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::rmap::Rmap;
+    use super::super::tests::{with_inner, PAGES};
+    use super::*;
+
+    use crate::pager::Attributes;
+
+    #[test]
+    fn paging_out() {
+        with_inner(|inner| {
+            let kernel = inner.alloc_for_overwrite(Purpose::Kernel).unwrap();
+            assert_ok!(inner.increment_map_count(kernel));
+            // kernel pages are never evicted
+            assert_none!(inner.start_paging_out().unwrap());
+
+            let user = inner.alloc_for_overwrite(Purpose::User).unwrap();
+            let i = inner.index(user);
+            let mut desc = PageBlockDescriptor::new_entry(Some(user), Attributes::USER_DATA);
+            let virt_addr = VirtAddr::at(0x1000);
+            assert_ok!(inner.increment_map_count(user));
+            assert_ok!(inner.add_mapping(
+                i,
+                Rmap {
+                    page_block_descriptor: NonNull::from(&mut desc),
+                    virt_addr,
+                }
+            ));
+            inner.table[i].block_mapped = true;
+            assert_ok!(inner.demote());
+
+            // the page is unmapped while it is written, and mapped again if the write fails
+            assert_some!(inner.start_paging_out().unwrap());
+            assert!(!desc.is_valid());
+            assert!(inner.is_paging_out(virt_addr));
+            assert_none!(inner.start_paging_out().unwrap());
+            assert_err!(inner.finish_paging_out(Err(Error::IOError)));
+            assert!(desc.is_valid());
+            assert_eq!(1, inner.stats().queue_len(FrameUse::UserCold));
+
+            assert_some!(inner.start_paging_out().unwrap());
+            assert_ok!(inner.finish_paging_out(Ok(())));
+            assert!(!desc.is_valid() && !inner.is_paging_out(virt_addr));
+            let stats = inner.stats();
+            assert_eq!((0, PAGES - 1), (stats.user_count, stats.free));
+            assert_eq!(0, stats.queue_len(FrameUse::PagingOut));
+        });
+    }
+
+    #[test]
+    fn reserving() {
+        with_inner(|inner| {
+            // frames are taken from the Zeroed queue, so nothing needs zeroing
+            while inner.drip(FrameUse::Free, FrameUse::Zeroed).is_ok() {}
+            assert_err!(inner.reserve(PAGES + 1));
+            assert_ok!(inner.reserve(PAGES - 1));
+            assert_eq!(1, inner.unreserved());
+
+            // other allocations leave the frames set aside
+            assert_ok!(inner.alloc_for_overwrite(Purpose::User));
+            assert_eq!(Err(Error::OutOfPages), inner.alloc_zeroed(Purpose::Kernel));
+            let mut frames = [0u32; 1];
+            assert_eq!((0, 0), inner.load(Purpose::LeafPageTable, &mut frames));
+
+            // the heap takes them as its pages are written
+            let phys_addr = inner.alloc_reserved().unwrap();
+            for _ in 1..PAGES - 1 {
+                assert_ok!(inner.alloc_reserved());
+            }
+            assert_eq!(Err(Error::OutOfPages), inner.alloc_reserved());
+            assert_ok!(inner.increment_map_count(phys_addr));
+            assert_ok!(inner.free(phys_addr));
+            assert_ok!(inner.drip(FrameUse::Free, FrameUse::Zeroed));
+            assert_ok!(inner.alloc_reserved());
+        });
+    }
+
+    #[test]
+    fn dropping_shared() {
+        with_inner(|inner| {
+            let user = inner.alloc_for_overwrite(Purpose::User).unwrap();
+            let i = inner.index(user);
+            let mut descs = [PageBlockDescriptor::new_entry(Some(user), Attributes::USER_DATA); 2];
+            descs[0].clear_dirty();
+            for (n, desc) in descs.iter_mut().enumerate() {
+                assert_ok!(inner.increment_map_count(user));
+                assert_ok!(inner.add_mapping(
+                    i,
+                    Rmap {
+                        page_block_descriptor: NonNull::from(desc),
+                        virt_addr: VirtAddr::at(0x1000 * (n + 1)),
+                    }
+                ));
+            }
+            inner.table[i].block_mapped = true;
+            assert_ok!(inner.demote());
+
+            // written through one of its mappings, so made warm again
+            assert_none!(inner.start_paging_out().unwrap());
+            assert_eq!(1, inner.stats().queue_len(FrameUse::UserWarm));
+
+            descs[1].clear_dirty();
+            assert_ok!(inner.demote());
+            assert!(matches!(
+                inner.start_paging_out().unwrap(),
+                Some(Evicted::Dropped)
+            ));
+            assert!(!descs[0].is_valid() && !descs[1].is_valid());
+            let stats = inner.stats();
+            assert_eq!(
+                (0, PAGES, 0),
+                (stats.user_count, stats.free, stats.overflow_mappings)
+            );
+        });
+    }
+}
@@ -0,0 +1,266 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Per-core magazines of zeroed frames.
+//!
+//! A core takes frames for the kernel and for page tables from its own magazine,
+//! without locking the frame table. An empty magazine is refilled with a batch of
+//! frames in one visit to the frame table, which records them against their
+//! purpose as they are loaded, so the queues count frames held in magazines as
+//! allocated. Frames which have been used are freed to the frame table as before.
+
+use super::{allocator, zeroing, Allocator, FrameTable, FrameTableInner, FrameUse, Purpose};
+
+use crate::archs::arch;
+use crate::pager::{PhysAddr, MAX_CORES};
+use crate::util::locked::Locked;
+use crate::{Error, Result};
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Most frames held for each purpose, which is also the size of a refill.
+pub const MAGAZINE_FRAMES: usize = 16;
+
+/// Purposes whose frames are held in magazines.
+///
+/// User frames are not, because they are aged and evicted from the moment they
+/// are allocated.
+const CACHED: [Purpose; 3] = [
+    Purpose::Kernel,
+    Purpose::LeafPageTable,
+    Purpose::BranchPageTable,
+];
+
+fn slot(purpose: Purpose) -> Option<usize> {
+    CACHED.iter().position(|cached| *cached == purpose)
+}
+
+#[derive(Copy, Clone)]
+struct Rounds {
+    frames: [u32; MAGAZINE_FRAMES],
+    len: usize,
+}
+
+/// Zeroed frames held by one core.
+pub(super) struct Magazine {
+    busy: AtomicBool, // in use, so a nested use on the same core goes to the frame table
+    rounds: UnsafeCell<[Rounds; CACHED.len()]>,
+}
+
+// a magazine is only used by its own core, and the busy flag excludes nested use
+unsafe impl Sync for Magazine {}
+
+impl Magazine {
+    pub(super) const fn new() -> Self {
+        const EMPTY: Rounds = Rounds {
+            frames: [0; MAGAZINE_FRAMES],
+            len: 0,
+        };
+        Self {
+            busy: AtomicBool::new(false),
+            rounds: UnsafeCell::new([EMPTY; CACHED.len()]),
+        }
+    }
+
+    fn with<T>(&self, slot: usize, f: impl FnOnce(&mut Rounds) -> T) -> Option<T> {
+        if self.busy.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        let result = f(unsafe { &mut (*self.rounds.get())[slot] });
+        self.busy.store(false, Ordering::Release);
+        Some(result)
+    }
+
+    /// Take a frame, if the magazine holds one.
+    pub(super) fn take(&self, slot: usize) -> Option<u32> {
+        self.with(slot, |rounds| {
+            if rounds.len == 0 {
+                return None;
+            }
+            rounds.len -= 1;
+            Some(rounds.frames[rounds.len])
+        })
+        .flatten()
+    }
+
+    /// Hold as many of some frames as there is room for, returning how many.
+    pub(super) fn put(&self, slot: usize, frames: &[u32]) -> usize {
+        self.with(slot, |rounds| {
+            let count = frames.len().min(MAGAZINE_FRAMES - rounds.len);
+            rounds.frames[rounds.len..rounds.len + count].copy_from_slice(&frames[..count]);
+            rounds.len += count;
+            count
+        })
+        .unwrap_or(0)
+    }
+
+    /// Remove all the frames, returning how many.
+    pub(super) fn remove(&self, slot: usize, frames: &mut [u32; MAGAZINE_FRAMES]) -> usize {
+        self.with(slot, |rounds| {
+            let count = rounds.len;
+            frames[..count].copy_from_slice(&rounds.frames[..count]);
+            rounds.len = 0;
+            count
+        })
+        .unwrap_or(0)
+    }
+}
+
+const EMPTY_MAGAZINE: Magazine = Magazine::new();
+
+static MAGAZINES: [Magazine; MAX_CORES] = [EMPTY_MAGAZINE; MAX_CORES];
+
+impl FrameTableInner {
+    /// Allocate a batch of frames for a magazine, preferring frames already zeroed.
+    ///
+    /// Does not evict, or take frames set aside for the heap. Returns the number of
+    /// frames loaded, and how many of those were taken from the Zeroed queue, which
+    /// come first.
+    pub(super) fn load(&mut self, purpose: Purpose, frames: &mut [u32]) -> (usize, usize) {
+        let mut count = 0;
+        let mut zeroed = 0;
+        for from in [FrameUse::Zeroed, FrameUse::Free] {
+            while count < frames.len() && self.unreserved() > 0 {
+                match self.drip(from, purpose.into()) {
+                    Ok(i) => frames[count] = self.allocated(i, purpose),
+                    Err(_) => break,
+                }
+                count += 1;
+            }
+            if from == FrameUse::Zeroed {
+                zeroed = count;
+            }
+        }
+        self.zeroing_stats.fast += zeroed as u64;
+        self.zeroing_stats.slow += (count - zeroed) as u64;
+        (count, zeroed)
+    }
+
+    /// Return unused frames from a magazine, which are still zeroed.
+    pub(super) fn unload(&mut self, purpose: Purpose, frames: &[u32]) -> Result<()> {
+        for i in frames {
+            self.table[*i].purpose = None;
+            self.frees += 1;
+            self.move_to(*i, purpose.into(), FrameUse::Zeroed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Allocate a zeroed frame from the calling core's magazine, refilling it if it is empty.
+pub(super) fn alloc_zeroed(allocator: &Locked<FrameTable>, purpose: Purpose) -> Result<PhysAddr> {
+    let slot = match slot(purpose) {
+        Some(slot) => slot,
+        None => return allocator.lock().alloc_zeroed(purpose),
+    };
+    // a core without a magazine allocates from the frame table
+    let magazine = match MAGAZINES.get(arch::core_id() as usize) {
+        Some(magazine) => magazine,
+        None => return allocator.lock().alloc_zeroed(purpose),
+    };
+    if let Some(i) = magazine.take(slot) {
+        return Ok(PhysAddr::ram_page(i as usize));
+    }
+
+    let mut frames = [0u32; MAGAZINE_FRAMES];
+    let (count, zeroed) = allocator.lock().inner()?.load(purpose, &mut frames);
+    if count == 0 {
+        // frames are only evicted by callers which hold no locks
+        return Err(Error::OutOfPages);
+    }
+    debug!(
+        "refilled {:?} magazine: {} ({} zeroed)",
+        purpose, count, zeroed
+    );
+    // the frames are allocated, so the frame table need not be locked while they are cleared
+    for i in &frames[zeroed..count] {
+        zeroing::zero(PhysAddr::ram_page(*i as usize))?;
+    }
+    let held = 1 + magazine.put(slot, &frames[1..count]);
+    if held < count {
+        allocator
+            .lock()
+            .inner()?
+            .unload(purpose, &frames[held..count])?;
+    }
+    Ok(PhysAddr::ram_page(frames[0] as usize))
+}
+
+/// Return the frames in the calling core's magazine to the frame table.
+///
+/// Called before the heap grows, so that frames held by the core count as available.
+pub fn drain_magazine() -> Result<()> {
+    let magazine = match MAGAZINES.get(arch::core_id() as usize) {
+        Some(magazine) => magazine,
+        None => return Ok(()),
+    };
+    for (slot, purpose) in CACHED.iter().enumerate() {
+        let mut frames = [0u32; MAGAZINE_FRAMES];
+        let count = magazine.remove(slot, &mut frames);
+        if count > 0 {
+            allocator()
+                .lock()
+                .inner()?
+                .unload(*purpose, &frames[..count])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{page, with_inner, PAGES};
+    use super::*;
+
+    #[test]
+    fn magazine() {
+        let magazine = Magazine::new();
+        assert_none!(magazine.take(0));
+        assert_eq!(3, magazine.put(0, &[1, 2, 3]));
+        assert_some_eq!(magazine.take(0), 3);
+        assert_none!(magazine.take(1));
+
+        let frames = [0u32; MAGAZINE_FRAMES];
+        assert_eq!(MAGAZINE_FRAMES - 2, magazine.put(0, &frames));
+
+        // a nested use finds the magazine busy
+        magazine.with(0, |_| {
+            assert_none!(magazine.take(0));
+            assert_eq!(0, magazine.put(0, &[4]));
+        });
+
+        let mut removed = [0u32; MAGAZINE_FRAMES];
+        assert_eq!(MAGAZINE_FRAMES, magazine.remove(0, &mut removed));
+        assert_eq!([1, 2], removed[..2]);
+        assert_none!(magazine.take(0));
+    }
+
+    #[test]
+    fn load_and_unload() {
+        with_inner(|inner| {
+            let zeroed = inner.drip(FrameUse::Free, FrameUse::Zeroed).unwrap();
+
+            let mut frames = [0u32; MAGAZINE_FRAMES];
+            assert_eq!(
+                (MAGAZINE_FRAMES, 1),
+                inner.load(Purpose::LeafPageTable, &mut frames)
+            );
+            assert_eq!(zeroed, frames[0]);
+            let stats = inner.stats();
+            assert_eq!(
+                MAGAZINE_FRAMES as u32,
+                stats.queue_len(FrameUse::LeafPageTable)
+            );
+            assert_eq!(PAGES - MAGAZINE_FRAMES as u32, stats.free);
+
+            // a frame used from the magazine is freed to the table
+            assert_ok!(inner.increment_map_count(page(frames[0])));
+            assert_ok!(inner.free(page(frames[0])));
+            assert_ok!(inner.unload(Purpose::LeafPageTable, &frames[1..]));
+            let stats = inner.stats();
+            assert_eq!(0, stats.queue_len(FrameUse::LeafPageTable));
+            assert_eq!(MAGAZINE_FRAMES as u32 - 1, stats.zeroed);
+            assert_eq!(stats.allocs, stats.frees);
+        });
+    }
+}
@@ -1,15 +1,30 @@
 // SPDX-License-Identifier: Unlicense
 
 mod deque;
-
-use crate::archs::{arch::Arch, PagerTrait};
+mod dma;
+mod evict;
+mod magazine;
+mod rmap;
+mod stats;
+mod zeroing;
+
+pub use dma::alloc_contiguous;
+pub use evict::{alloc_for_overwrite, alloc_zeroed, evict_cold_pages, reserve};
+pub use magazine::drain_magazine;
+pub use stats::{stats, FrameStats};
+pub use zeroing::{zero_free_frames, ZeroingStats};
+
+pub(in crate::pager) use evict::wait_for_paging_out;
+
+use crate::archs::{arch::Arch, arch::PageBlockDescriptor, PagerTrait};
 use crate::device;
+use crate::handler;
 use crate::util::locked::Locked;
-use crate::{Error, Error::Unimplemented, Result};
+use crate::{Error, Result};
 
 use super::{
-    layout, layout::RangeContent, mem_translation, AddrRange, PhysAddr, PhysAddrRange, Translate,
-    VirtAddr, PAGESIZE_BYTES,
+    layout, layout::RangeContent, swap, Addr, AddrRange, PhysAddr, PhysAddrRange, VirtAddr,
+    MAX_CORES, PAGESIZE_BYTES,
 };
 
 use core::default::Default;
@@ -45,30 +60,88 @@ pub trait Allocator {
         self.alloc_zeroed(purpose)
     }
 
+    /// Reserve and return a zeroed frame for the calling core, without locking if it can.
+    fn alloc_zeroed_local(allocator: &Locked<Self>, purpose: Purpose) -> Result<PhysAddr>
+    where
+        Self: Sized,
+    {
+        allocator.lock().alloc_zeroed(purpose)
+    }
+
     /// A physical page is being mapped, so increase its reference count.
     fn increment_map_count(&mut self, phys_addr: PhysAddr) -> Result<()>;
 
+    /// A page table entry now maps a physical page, so remember where it is.
+    fn set_page_block_descriptor(
+        &mut self,
+        _phys_addr: PhysAddr,
+        _virt_addr: VirtAddr,
+        _page_block_descriptor: NonNull<PageBlockDescriptor>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Fail if another page table entry mapping a physical page could not be remembered.
+    ///
+    /// Called before the entry is written, so that there is nothing to undo.
+    fn check_page_block_descriptor(&mut self, _phys_addr: PhysAddr) -> Result<()> {
+        Ok(())
+    }
+
+    /// A page table entry no longer maps a physical page, so forget it.
+    ///
+    /// Called before the page is freed for the entry.
+    fn clear_page_block_descriptor(
+        &mut self,
+        _phys_addr: PhysAddr,
+        _page_block_descriptor: NonNull<PageBlockDescriptor>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
     /// A physical page is being unmapped, so decrease its reference count and
     /// move to free list if no further references.
     fn free(&mut self, phys_addr: PhysAddr) -> Result<()>;
+
+    /// A swapped-out page is being unmapped, so release its place in swap.
+    fn release_swapped(&mut self, _sector: NonZeroU64) -> Result<()> {
+        Ok(())
+    }
 }
 
-#[derive(Copy, Clone, Debug)]
+/// The queue a frame is on.
+#[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(u8)]
 #[allow(dead_code)]
-enum FrameUse {
-    Zero,               // blank read-only page of zeros for all processes to shared, ie. bss.
-    Zeroed,   // reservoir of zero pages for when processes write to pages for the first time.
-    Zeroing,  // owned by page cleaner while cleaning
-    Free,     // returned after use when no longer needed
-    UserWarm, // recently-accessed user pages
-    UserCold, // less recently-accessed user pages
-    Kernel,   // kernel pages (always resident)
-    Nailed,   // special user pages that must not be evicted, ie. shared with device
-    FrameTable, // holds the frame table
-    LeafPageTable, // the L3 page tables
-    BranchPageTable, // the L0-2 page tables
-    DirectMemoryAccess, // pool to supply contiguous pages of physical memory
+pub enum FrameUse {
+    /// Blank read-only page of zeros for all processes to share, ie. bss.
+    Zero,
+    /// Reservoir of zero pages for when processes write to pages for the first time.
+    Zeroed,
+    /// Owned by page cleaner while cleaning.
+    Zeroing,
+    /// Owned by the pager while written out to swap or its device.
+    PagingOut,
+    /// Returned after use when no longer needed.
+    Free,
+    /// Recently-accessed user pages.
+    UserWarm,
+    /// Less recently-accessed user pages.
+    UserCold,
+    /// Kernel pages (always resident).
+    Kernel,
+    /// Special user pages that must not be evicted, ie. shared with device.
+    Nailed,
+    /// Holds the frame table.
+    FrameTable,
+    /// The L3 page tables.
+    LeafPageTable,
+    /// The L0-2 page tables.
+    BranchPageTable,
+    /// Pool to supply contiguous pages of physical memory.
+    DirectMemoryAccess,
+    /// Holes between banks of RAM and firmware reservations, never allocated.
+    Reserved,
 }
 
 impl From<u8> for FrameUse {
@@ -77,6 +150,7 @@ impl From<u8> for FrameUse {
             i if i == FrameUse::Zero as u8 => FrameUse::Zero,
             i if i == FrameUse::Zeroed as u8 => FrameUse::Zeroed,
             i if i == FrameUse::Zeroing as u8 => FrameUse::Zeroing,
+            i if i == FrameUse::PagingOut as u8 => FrameUse::PagingOut,
             i if i == FrameUse::Free as u8 => FrameUse::Free,
             i if i == FrameUse::UserWarm as u8 => FrameUse::UserWarm,
             i if i == FrameUse::UserCold as u8 => FrameUse::UserCold,
@@ -86,6 +160,7 @@ impl From<u8> for FrameUse {
             i if i == FrameUse::LeafPageTable as u8 => FrameUse::LeafPageTable,
             i if i == FrameUse::BranchPageTable as u8 => FrameUse::BranchPageTable,
             i if i == FrameUse::DirectMemoryAccess as u8 => FrameUse::DirectMemoryAccess,
+            i if i == FrameUse::Reserved as u8 => FrameUse::Reserved,
             _ => unreachable!(),
         }
     }
@@ -99,30 +174,156 @@ impl Into<u8> for FrameUse {
 
 #[derive(Debug)]
 pub struct FrameTableEntry {
-    _persisted: Option<NonZeroU64>,
-    _page_block_descriptor: Option<NonNull<crate::archs::arch::PageBlockDescriptor>>,
+    frame_use: FrameUse,           // queue, unless demoted from Warm since
+    purpose: Option<Purpose>,      // while allocated
+    warm_epoch: u32,               // warm if a user page and equal to the table's epoch
+    persisted: Option<NonZeroU64>, // swap sector holding a copy of the page
+    block_mapped: bool,            // page is backed by a block mapping instead of swap
+    page_block_descriptor: Option<NonNull<PageBlockDescriptor>>, // first known mapping
+    virt_addr: VirtAddr,           // where page_block_descriptor maps the page
+    more_mappings: u32,            // first overflow link of any further known mappings
     map_count: u8,
 }
 
 impl Default for FrameTableEntry {
     fn default() -> Self {
         Self {
-            _persisted: None,
-            _page_block_descriptor: None,
+            frame_use: FrameUse::Free,
+            purpose: None,
+            warm_epoch: 0,
+            persisted: None,
+            block_mapped: false,
+            page_block_descriptor: None,
+            virt_addr: VirtAddr::null(),
+            more_mappings: rmap::NO_LINK,
             map_count: 0,
         }
     }
 }
 
+/// Percentage of user pages to keep in the Cold queue.
+const COLD_PERCENT: u32 = 30;
+
+/// Smallest Warm queue worth demoting.
+const MIN_WARM_TARGET: u32 = 16;
+
 #[derive(Debug)]
 pub struct FrameTableInner {
     table: deque::Deque<FrameTableEntry, FrameUse>,
     user_count: u32,
     user_warm_count: u32,
+    warm_epoch: u32, // incremented each time Warm is demoted to Cold
+    queue_lens: [u32; stats::FRAME_USES],
+    allocs: u64,
+    frees: u64,
+    zeroed_target: u32,
+    zeroing_stats: ZeroingStats,
+    heap_reserved: u32, // free or zeroed frames set aside for pages the heap has grown into
+    dma_pool: dma::DmaPool,
+    overflow: rmap::Overflow, // further mappings of shared frames
+    paging_out: [Option<evict::PagingOut>; MAX_CORES], // page each core is writing out
+    zero_frame: u32,          // shared by read-only on-demand mappings
     ram_range: PhysAddrRange, // range of ram to be managed
 }
 
 impl FrameTableInner {
+    /// Manage a range of RAM whose frames all start on the Free queue.
+    fn new(
+        table: deque::Deque<FrameTableEntry, FrameUse>,
+        ram_range: PhysAddrRange,
+        dma_pool: dma::DmaPool,
+        overflow: rmap::Overflow,
+    ) -> Self {
+        let mut queue_lens = [0; stats::FRAME_USES];
+        queue_lens[FrameUse::Free as usize] = ram_range.length_in_pages() as u32;
+        Self {
+            table,
+            user_count: 0,
+            user_warm_count: 0,
+            warm_epoch: 0,
+            queue_lens,
+            allocs: 0,
+            frees: 0,
+            zeroed_target: zeroing::DEFAULT_ZEROED_TARGET,
+            zeroing_stats: ZeroingStats::default(),
+            heap_reserved: 0,
+            dma_pool,
+            overflow,
+            paging_out: [None; MAX_CORES],
+            zero_frame: 0,
+            ram_range,
+        }
+    }
+
+    fn index(&self, phys_addr: PhysAddr) -> u32 {
+        PhysAddrRange::between(self.ram_range.base(), phys_addr).length_in_pages() as u32
+    }
+
+    /// Record the purpose of a frame just taken from a free queue.
+    fn allocated(&mut self, i: u32, purpose: Purpose) -> u32 {
+        // idle cores may need to evict or zero to restore the reserves
+        handler::send_event();
+        let warm_epoch = self.warm_epoch;
+        self.allocs += 1;
+        let entry = &mut self.table[i];
+        entry.purpose = Some(purpose);
+        if purpose == Purpose::User {
+            entry.warm_epoch = warm_epoch;
+            self.user_count += 1;
+            self.user_warm_count += 1;
+        }
+        i
+    }
+
+    fn is_warm(&self, i: u32) -> bool {
+        let entry = &self.table[i];
+        entry.purpose == Some(Purpose::User) && entry.warm_epoch == self.warm_epoch
+    }
+
+    /// Number of Warm pages which triggers demotion to Cold.
+    fn warm_target(&self) -> u32 {
+        let target = self.user_count - self.user_count * COLD_PERCENT / 100;
+        core::cmp::max(MIN_WARM_TARGET, target)
+    }
+
+    /// Move the whole Warm queue to the front of Cold.
+    ///
+    /// The access flags of the demoted pages are cleared through their reverse mappings,
+    /// and advancing the epoch makes them cold without visiting their entries.
+    fn demote(&mut self) -> Result<()> {
+        info!("demote: {} warm", self.user_warm_count);
+        let cleared = self.clear_warm_accessed()?;
+        debug!("cleared {} access flags", cleared);
+        self.clear(FrameUse::UserWarm, FrameUse::UserCold)?;
+        self.user_warm_count = 0;
+        self.warm_epoch = self.warm_epoch.wrapping_add(1);
+        Ok(())
+    }
+
+    /// A user page has been accessed, so move it to the head of Warm.
+    ///
+    /// Returns true if Warm had reached its target and was demoted first, clearing
+    /// the access flags of the demoted pages.
+    fn touch(&mut self, phys_addr: PhysAddr) -> Result<bool> {
+        if !self.ram_range.contains(phys_addr) {
+            return Ok(false);
+        }
+        let i = self.index(phys_addr);
+        if self.table[i].purpose != Some(Purpose::User) {
+            return Ok(false);
+        }
+        let demoted = self.user_warm_count >= self.warm_target();
+        if demoted {
+            self.demote()?;
+        }
+        let from = self.frame_use(i);
+        self.move_to(i, from, FrameUse::UserWarm)?;
+        if from != FrameUse::UserWarm {
+            self.table[i].warm_epoch = self.warm_epoch;
+            self.user_warm_count += 1;
+        }
+        Ok(demoted)
+    }
     fn move_contiguous_range(
         &mut self,
         phys_addr_range: PhysAddrRange,
@@ -131,75 +332,193 @@ impl FrameTableInner {
         let i = PhysAddrRange::between(Arch::ram_range().base(), phys_addr_range.base())
             .length_in_pages() as u32;
         let j = i + phys_addr_range.length_in_pages() as u32 - 1;
-        self.table.remove_seq_to(i, j, frame_use)?;
+        self.move_seq_to(i, j, frame_use)
+    }
+
+    /// Keep frames which are not usable RAM from ever being allocated.
+    fn reserve(&mut self, phys_addr_range: PhysAddrRange) -> Result<()> {
+        let phys_addr_range = match self.ram_range.intersection(&phys_addr_range) {
+            Some(phys_addr_range) => phys_addr_range,
+            None => return Ok(()),
+        };
+        let phys_addr_range = PhysAddrRange::between(
+            phys_addr_range.base().align_down(PAGESIZE_BYTES),
+            phys_addr_range.top().align_up(PAGESIZE_BYTES),
+        );
+        info!("reserve: {:?}", phys_addr_range);
+        for phys_addr in phys_addr_range.chunks(PAGESIZE_BYTES) {
+            let i = self.index(phys_addr);
+            if self.dma_pool.contains(i) {
+                self.dma_pool.reserve(i);
+            }
+            // ranges may overlap each other, and frames already in use stay where they are
+            let from = self.frame_use(i);
+            if from == FrameUse::Free {
+                self.move_to(i, from, FrameUse::Reserved)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a frame may differ from its copy in swap or on its block device.
+    ///
+    /// Frames without a copy, or without a single known mapping, are always dirty.
+    fn is_dirty(&self, i: u32) -> bool {
+        let entry = &self.table[i];
+        let backed = entry.persisted.is_some() || entry.block_mapped;
+        match (backed, self.sole_mapping(i)) {
+            (true, Some(page_block_descriptor)) => unsafe {
+                page_block_descriptor.as_ref().is_dirty()
+            },
+            _ => true,
+        }
+    }
+
+    /// Record that a frame matches its copy, so the next write marks it dirty.
+    fn clear_dirty(&mut self, i: u32) -> Result<()> {
+        let entry = &self.table[i];
+        let backed = entry.persisted.is_some() || entry.block_mapped;
+        if let (true, Some(page_block_descriptor)) = (backed, self.sole_mapping(i)) {
+            unsafe {
+                (*page_block_descriptor.as_ptr()).clear_dirty();
+            }
+            Arch::invalidate_tlb(entry.virt_addr)?;
+        }
+        Ok(())
+    }
+
+    fn set_persisted(&mut self, phys_addr: PhysAddr, sector: NonZeroU64) -> Result<()> {
+        let i = self.index(phys_addr);
+        self.table[i].persisted = Some(sector);
         Ok(())
     }
 }
 
 impl Allocator for FrameTableInner {
     fn alloc_zeroed(&mut self, purpose: Purpose) -> Result<PhysAddr> {
-        self.table
-            .drip_to(FrameUse::Zeroed, purpose.into())
+        if self.unreserved() == 0 {
+            return Err(Error::OutOfPages);
+        }
+        self.drip(FrameUse::Zeroed, purpose.into())
             .map(|i| {
-                if purpose == Purpose::User {
-                    self.user_count += 1;
-                    self.user_warm_count += 1;
-                }
-                i
+                self.zeroing_stats.fast += 1;
+                self.allocated(i, purpose)
             })
             .map(|i| PhysAddr::ram_page(i as usize))
             .or_else(|_| {
                 self.alloc_for_overwrite(purpose).and_then(|phys_addr| {
-                    let page: *mut u8 = mem_translation().translate_phys(phys_addr)?.into();
-                    unsafe {
-                        core::ptr::write_bytes(page, 0, PAGESIZE_BYTES);
-                    }
+                    self.zeroing_stats.slow += 1;
+                    zeroing::zero(phys_addr)?;
                     Ok(phys_addr)
                 })
             })
     }
 
     fn alloc_for_overwrite(&mut self, purpose: Purpose) -> Result<PhysAddr> {
+        if self.unreserved() == 0 {
+            return Err(Error::OutOfPages);
+        }
         let result = self
-            .table
-            .drip_to(FrameUse::Free, purpose.into())
-            .map(|i| {
-                if purpose == Purpose::User {
-                    self.user_count += 1;
-                }
-                i
-            })
-            .or_else(|_| {
-                // evict
-                Err(Unimplemented)
-            })
-            .map(|i| {
-                if purpose == Purpose::User {
-                    self.user_warm_count += 1;
-                }
-                i
-            })
+            .drip(FrameUse::Free, purpose.into())
+            .or_else(|_| self.drip(FrameUse::Zeroed, purpose.into()))
+            .map(|i| self.allocated(i, purpose))
             .map(|i| PhysAddr::ram_page(i as usize));
         debug!("updated self.table: {:?}", self.table);
         result
     }
 
     fn increment_map_count(&mut self, phys_addr: PhysAddr) -> Result<()> {
-        let i = PhysAddrRange::between(self.ram_range.base(), phys_addr).length_in_pages() as u32;
+        let i = self.index(phys_addr);
         self.table[i].map_count += 1;
         Ok(())
     }
 
+    fn set_page_block_descriptor(
+        &mut self,
+        phys_addr: PhysAddr,
+        virt_addr: VirtAddr,
+        page_block_descriptor: NonNull<PageBlockDescriptor>,
+    ) -> Result<()> {
+        let i = self.index(phys_addr);
+        if i == self.zero_frame {
+            // mappings of the zero frame are not counted
+            return Ok(());
+        }
+        self.add_mapping(
+            i,
+            rmap::Rmap {
+                page_block_descriptor,
+                virt_addr,
+            },
+        )
+    }
+
+    fn check_page_block_descriptor(&mut self, phys_addr: PhysAddr) -> Result<()> {
+        let i = self.index(phys_addr);
+        if i == self.zero_frame || self.can_add_mapping(i) {
+            return Ok(());
+        }
+        Err(Error::OutOfMemory)
+    }
+
+    fn clear_page_block_descriptor(
+        &mut self,
+        phys_addr: PhysAddr,
+        page_block_descriptor: NonNull<PageBlockDescriptor>,
+    ) -> Result<()> {
+        let i = self.index(phys_addr);
+        self.remove_mapping(i, page_block_descriptor);
+        Ok(())
+    }
+
     fn free(&mut self, phys_addr: PhysAddr) -> Result<()> {
         assert!(self.ram_range.contains(phys_addr));
-        let i = PhysAddrRange::between(self.ram_range.base(), phys_addr).length_in_pages() as u32;
-        self.table[i].map_count -= 1;
-        if self.table[i].map_count == 0 {
-            self.table.remove_to(i, FrameUse::Free).and(Ok(()))
+        let i = self.index(phys_addr);
+        if i == self.zero_frame {
+            // mappings of the zero frame are not counted
+            return Ok(());
+        }
+        let is_warm = self.is_warm(i);
+        let from = self.frame_use(i);
+        if from == FrameUse::Reserved {
+            // not managed, eg. a device mapping in a hole between banks
+            return Ok(());
+        }
+        let entry = &mut self.table[i];
+        entry.map_count -= 1;
+        if entry.map_count == 0 {
+            if entry.purpose.take() == Some(Purpose::User) {
+                self.user_count -= 1;
+                if is_warm {
+                    self.user_warm_count -= 1;
+                }
+            }
+            self.frees += 1;
+            self.forget_mappings(i);
+            let entry = &mut self.table[i];
+            entry.block_mapped = false;
+            if let Some(sector) = entry.persisted.take() {
+                swap::release(sector)?;
+            }
+            if self.dma_pool.contains(i) {
+                // stays on the DirectMemoryAccess queue
+                return self.dma_pool.free(i);
+            }
+            // idle cores may zero it
+            handler::send_event();
+            self.move_to(i, from, FrameUse::Free)
         } else {
             Ok(())
         }
     }
+
+    fn release_swapped(&mut self, sector: NonZeroU64) -> Result<()> {
+        if self.release_paging_out(sector) {
+            // released once the write to the slot has finished
+            return Ok(());
+        }
+        swap::release(sector)
+    }
 }
 
 pub struct FrameTable(Option<FrameTableInner>);
@@ -235,6 +554,7 @@ pub fn frame_table_bytes() -> usize {
     let entries = ram_range.length_in_pages();
 
     deque::Deque::<FrameTableEntry, FrameUse>::storage_bytes(entries)
+        + rmap::Overflow::storage_bytes(entries)
 }
 
 /// Initialise the frame table
@@ -250,23 +570,29 @@ pub fn init() -> Result<()> {
 
     let ram_range: PhysAddrRange = Arch::ram_range();
     let len = ram_range.length_in_pages() as u32;
+    let dma_pool_first = len
+        .checked_sub(dma::DMA_POOL_PAGES)
+        .ok_or(Error::OutOfMemory)?;
 
     let mut frame_table = unsafe {
         let frame_table_ptr: *mut u8 = VirtAddr::identity_mapped(frame_table_range.base()).into();
-        FrameTableInner {
-            table: deque::Deque::<FrameTableEntry, FrameUse>::new(
-                frame_table_ptr,
-                len,
-                FrameUse::Free,
-            ),
-            user_count: 0,
-            user_warm_count: 0,
+        let overflow_ptr = frame_table_ptr.add(deque_bytes(len));
+        FrameTableInner::new(
+            deque::Deque::<FrameTableEntry, FrameUse>::new(frame_table_ptr, len, FrameUse::Free),
             ram_range,
-        }
+            dma::DmaPool::new(dma_pool_first),
+            rmap::Overflow::new(overflow_ptr, len as usize),
+        )
     };
 
     frame_table.move_contiguous_range(frame_table_range, FrameUse::FrameTable)?;
 
+    let dma_pool_range = PhysAddrRange::new(
+        PhysAddr::ram_page(dma_pool_first as usize),
+        dma::DMA_POOL_PAGES as usize * PAGESIZE_BYTES,
+    );
+    frame_table.move_contiguous_range(dma_pool_range, FrameUse::DirectMemoryAccess)?;
+
     let reset_stack_range = layout::get_phys_range(RangeContent::ResetStack)?;
     frame_table.move_contiguous_range(reset_stack_range, FrameUse::Kernel)?;
     let text_range: PhysAddrRange = Arch::text_image();
@@ -282,6 +608,23 @@ pub fn init() -> Result<()> {
         }
     }
 
+    // after the runs of frames above, which are moved while still contiguous in Free
+    for hole in Arch::ram_banks().holes() {
+        frame_table.reserve(hole)?;
+    }
+    for reserved in Arch::reserved_ranges().as_slice() {
+        frame_table.reserve(*reserved)?;
+    }
+
+    // paging is not enabled yet, so the frame is identity mapped
+    let zero_frame = frame_table.drip(FrameUse::Free, FrameUse::Zero)?;
+    unsafe {
+        let phys_addr = PhysAddr::ram_page(zero_frame as usize);
+        let page: *mut u8 = VirtAddr::identity_mapped(phys_addr).into();
+        core::ptr::write_bytes(page, 0, PAGESIZE_BYTES);
+    }
+    frame_table.zero_frame = zero_frame;
+
     unsafe {
         let mut lock = ALLOCATOR.lock();
         *lock = FrameTable(Some(frame_table));
@@ -310,15 +653,108 @@ pub(in crate::pager) fn repoint_frame_table() -> Result<()> {
     let frame_table_ptr: *mut u8 = virt_addr.base().into();
     let inner = lock.inner()?;
     let len = inner.ram_range.length_in_pages() as u32;
+    unsafe {
+        let overflow_ptr = frame_table_ptr.add(deque_bytes(len));
+        inner.overflow.repoint(overflow_ptr, len as usize);
+    }
     inner.table.repoint_table(frame_table_ptr, len)
 }
 
+/// Bytes of the frame table before the overflow links of reverse mappings.
+fn deque_bytes(len: u32) -> usize {
+    deque::Deque::<FrameTableEntry, FrameUse>::storage_bytes(len as usize)
+}
+
+impl FrameTable {
+    /// Record that a frame holds a copy of the page in a swap slot.
+    pub(in crate::pager) fn set_persisted(
+        &mut self,
+        phys_addr: PhysAddr,
+        sector: NonZeroU64,
+    ) -> Result<()> {
+        info!("set_persisted: {:?} {}", phys_addr, sector);
+        self.inner()?.set_persisted(phys_addr, sector)
+    }
+
+    /// Unmap a frame from every page table entry known to map it, invalidating the TLB.
+    ///
+    /// Frees the frame when its last mapping goes, unless a mapping was counted for
+    /// the caller first. Returns the number of entries unmapped.
+    pub(in crate::pager) fn unmap_everywhere(&mut self, phys_addr: PhysAddr) -> Result<u32> {
+        info!("unmap_everywhere: {:?}", phys_addr);
+        let inner = self.inner()?;
+        let i = inner.index(phys_addr);
+        inner.unmap_everywhere(i)
+    }
+
+    /// Free a frame which was allocated but never mapped.
+    pub(in crate::pager) fn discard(&mut self, phys_addr: PhysAddr) -> Result<()> {
+        info!("discard: {:?}", phys_addr);
+        let inner = self.inner()?;
+        inner.increment_map_count(phys_addr)?;
+        inner.free(phys_addr)
+    }
+
+    /// The swap sector holding a copy of the page in a frame, if it has one.
+    pub(in crate::pager) fn persisted(
+        &mut self,
+        phys_addr: PhysAddr,
+    ) -> Result<Option<NonZeroU64>> {
+        let inner = self.inner()?;
+        let i = inner.index(phys_addr);
+        Ok(inner.table[i].persisted)
+    }
+
+    /// Record that a frame holds a page of a block mapping, to be written back on eviction.
+    pub(in crate::pager) fn set_block_mapped(&mut self, phys_addr: PhysAddr) -> Result<()> {
+        info!("set_block_mapped: {:?}", phys_addr);
+        let inner = self.inner()?;
+        let i = inner.index(phys_addr);
+        inner.table[i].block_mapped = true;
+        Ok(())
+    }
+
+    /// Whether a frame may have been written since it was last saved to swap or its device.
+    pub fn is_dirty(&mut self, phys_addr: PhysAddr) -> Result<bool> {
+        let inner = self.inner()?;
+        let i = inner.index(phys_addr);
+        Ok(inner.is_dirty(i))
+    }
+
+    /// Record that a frame has been saved to its swap slot or device, so the next write marks it
+    /// dirty.
+    pub fn clear_dirty(&mut self, phys_addr: PhysAddr) -> Result<()> {
+        info!("clear_dirty: {:?}", phys_addr);
+        let inner = self.inner()?;
+        let i = inner.index(phys_addr);
+        inner.clear_dirty(i)
+    }
+
+    /// The frame of zeros shared by on-demand pages which have not been written.
+    pub fn zero_frame(&mut self) -> Result<PhysAddr> {
+        Ok(PhysAddr::ram_page(self.inner()?.zero_frame as usize))
+    }
+
+    /// Record an access to a frame for page replacement.
+    ///
+    /// Returns true if the Warm pages were demoted to Cold.
+    pub(in crate::pager) fn touch(&mut self, phys_addr: PhysAddr) -> Result<bool> {
+        info!("touch: {:?}", phys_addr);
+        self.inner()?.touch(phys_addr)
+    }
+}
+
 impl Allocator for FrameTable {
     fn alloc_zeroed(&mut self, purpose: Purpose) -> Result<PhysAddr> {
         info!("alloc_zeroed: {:?}", purpose);
         self.inner()?.alloc_zeroed(purpose)
     }
 
+    fn alloc_zeroed_local(allocator: &Locked<Self>, purpose: Purpose) -> Result<PhysAddr> {
+        info!("alloc_zeroed_local: {:?}", purpose);
+        magazine::alloc_zeroed(allocator, purpose)
+    }
+
     fn alloc_for_overwrite(&mut self, purpose: Purpose) -> Result<PhysAddr> {
         info!("alloc_zeroed: {:?}", purpose);
         self.inner()?.alloc_for_overwrite(purpose)
@@ -329,10 +765,40 @@ impl Allocator for FrameTable {
         self.inner()?.increment_map_count(phys_addr)
     }
 
+    fn set_page_block_descriptor(
+        &mut self,
+        phys_addr: PhysAddr,
+        virt_addr: VirtAddr,
+        page_block_descriptor: NonNull<PageBlockDescriptor>,
+    ) -> Result<()> {
+        info!("set_page_block_descriptor: {:?} {:?}", phys_addr, virt_addr);
+        self.inner()?
+            .set_page_block_descriptor(phys_addr, virt_addr, page_block_descriptor)
+    }
+
+    fn check_page_block_descriptor(&mut self, phys_addr: PhysAddr) -> Result<()> {
+        self.inner()?.check_page_block_descriptor(phys_addr)
+    }
+
+    fn clear_page_block_descriptor(
+        &mut self,
+        phys_addr: PhysAddr,
+        page_block_descriptor: NonNull<PageBlockDescriptor>,
+    ) -> Result<()> {
+        info!("clear_page_block_descriptor: {:?}", phys_addr);
+        self.inner()?
+            .clear_page_block_descriptor(phys_addr, page_block_descriptor)
+    }
+
     fn free(&mut self, phys_addr: PhysAddr) -> Result<()> {
         info!("free: {:?}", phys_addr);
         self.inner()?.free(phys_addr)
     }
+
+    fn release_swapped(&mut self, sector: NonZeroU64) -> Result<()> {
+        info!("release_swapped: {}", sector);
+        self.inner()?.release_swapped(sector)
+    }
 }
 
 #[cfg(test)]
@@ -345,4 +811,147 @@ mod tests {
         trace!("{:?}", alloc);
         assert_err!(alloc.alloc_zeroed(Purpose::User));
     }
+
+    pub(super) const PAGES: u32 = 40;
+    const RAM_BASE: usize = 0x4000_0000;
+
+    pub(super) fn page(i: u32) -> PhysAddr {
+        PhysAddr::at(RAM_BASE + i as usize * PAGESIZE_BYTES)
+    }
+
+    /// Run a test against a frame table held on the heap.
+    pub(super) fn with_inner(test: impl FnOnce(&mut FrameTableInner)) {
+        use alloc::alloc::{alloc, dealloc, Layout};
+
+        let layout = Layout::from_size_align(
+            deque_bytes(PAGES) + rmap::Overflow::storage_bytes(PAGES as usize),
+            PAGESIZE_BYTES,
+        )
+        .unwrap();
+        unsafe {
+            let ptr = alloc(layout);
+            let mut inner = FrameTableInner::new(
+                deque::Deque::new(ptr, PAGES, FrameUse::Free),
+                PhysAddrRange::new(PhysAddr::at(RAM_BASE), PAGES as usize * PAGESIZE_BYTES),
+                dma::DmaPool::new(PAGES),
+                rmap::Overflow::new(ptr.add(deque_bytes(PAGES)), PAGES as usize),
+            );
+            inner.zeroed_target = 0;
+            inner.zero_frame = PAGES;
+            test(&mut inner);
+            dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn aging() {
+        with_inner(|inner| {
+            let mut pages = [0u32; MIN_WARM_TARGET as usize];
+            for i in pages.iter_mut() {
+                *i = inner.drip(FrameUse::Free, FrameUse::UserWarm).unwrap();
+                inner.allocated(*i, Purpose::User);
+                inner.table[*i].map_count = 1;
+            }
+            assert_eq!(inner.user_warm_count, MIN_WARM_TARGET);
+            assert!(inner.is_warm(pages[0]));
+
+            // Warm has reached its target, so the touch demotes everything first
+            assert_ok_eq!(inner.touch(page(pages[0])), true);
+            assert_eq!(inner.user_warm_count, 1);
+            assert!(inner.is_warm(pages[0]));
+            assert!(!inner.is_warm(pages[1]));
+
+            assert_ok_eq!(inner.touch(page(pages[1])), false);
+            assert_ok_eq!(inner.touch(page(pages[1])), false);
+            assert_eq!(inner.user_warm_count, 2);
+
+            assert_ok!(inner.free(page(pages[1])));
+            assert_eq!(inner.user_warm_count, 1);
+            assert_eq!(inner.user_count, MIN_WARM_TARGET - 1);
+            assert_none!(inner.table[pages[1]].purpose);
+        });
+    }
+
+    #[test]
+    fn counters() {
+        with_inner(|inner| {
+            let stats = inner.stats();
+            assert_eq!(stats.total, PAGES);
+            assert_eq!(stats.free, PAGES);
+
+            let user = inner.alloc_for_overwrite(Purpose::User).unwrap();
+            let kernel = inner.alloc_for_overwrite(Purpose::Kernel).unwrap();
+            assert_ok!(inner.increment_map_count(user));
+            assert_ok!(inner.increment_map_count(kernel));
+            let stats = inner.stats();
+            assert_eq!(stats.free, PAGES - 2);
+            assert_eq!(stats.queue_len(FrameUse::UserWarm), 1);
+            assert_eq!(stats.queue_len(FrameUse::Kernel), 1);
+            assert_eq!((stats.user_count, stats.user_warm_count), (1, 1));
+            assert_eq!(stats.allocs, 2);
+
+            assert_ok!(inner.demote());
+            let stats = inner.stats();
+            assert_eq!(stats.queue_len(FrameUse::UserWarm), 0);
+            assert_eq!(stats.queue_len(FrameUse::UserCold), 1);
+
+            assert_ok!(inner.free(user));
+            assert_ok!(inner.free(kernel));
+            let stats = inner.stats();
+            assert_eq!(stats.free, PAGES);
+            assert_eq!(stats.queue_len(FrameUse::UserCold), 0);
+            assert_eq!(stats.frees, 2);
+            assert_eq!(stats.queue_lens.iter().sum::<u32>(), PAGES);
+        });
+    }
+
+    #[test]
+    fn overwrite_zeroed() {
+        with_inner(|inner| {
+            while inner.drip(FrameUse::Free, FrameUse::Zeroed).is_ok() {}
+
+            // zeroed frames are used before anything is evicted
+            assert_ok!(inner.alloc_for_overwrite(Purpose::Kernel));
+            assert_eq!(inner.stats().zeroed, PAGES - 1);
+        });
+    }
+
+    #[test]
+    fn dirty() {
+        with_inner(|inner| {
+            let user = inner.alloc_for_overwrite(Purpose::User).unwrap();
+            assert_ok!(inner.increment_map_count(user));
+            let i = inner.index(user);
+            assert!(inner.is_dirty(i));
+
+            // without a single known mapping, a saved frame can't be seen to be clean
+            assert_ok!(inner.set_persisted(user, NonZeroU64::new(8).unwrap()));
+            assert_ok!(inner.clear_dirty(i));
+            assert!(inner.is_dirty(i));
+        });
+    }
+
+    #[test]
+    fn reserved() {
+        with_inner(|inner| {
+            // partial pages are reserved whole, and ranges beyond RAM are clipped
+            let hole = PhysAddrRange::between(page(1).increment(8), page(PAGES + 4));
+            assert_ok!(inner.reserve(hole));
+            assert_eq!(inner.stats().queue_len(FrameUse::Reserved), PAGES - 1);
+            // overlapping ranges are reserved once
+            assert_ok!(inner.reserve(PhysAddrRange::new(page(1), 2 * PAGESIZE_BYTES)));
+            assert_eq!(inner.stats().queue_len(FrameUse::Reserved), PAGES - 1);
+
+            assert_ok_eq!(inner.alloc_for_overwrite(Purpose::Kernel), page(0));
+            assert_err!(inner.alloc_for_overwrite(Purpose::Kernel));
+            // frames in use are not reserved
+            assert_ok!(inner.reserve(PhysAddrRange::new(page(0), PAGESIZE_BYTES)));
+            assert_eq!(inner.stats().queue_len(FrameUse::Reserved), PAGES - 1);
+
+            // device mappings in a hole are not returned to Free
+            assert_ok!(inner.increment_map_count(page(2)));
+            assert_ok!(inner.free(page(2)));
+            assert_eq!(inner.stats().free, 0);
+        });
+    }
 }
@@ -0,0 +1,353 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Reverse mappings, from frames to the leaf entries which map them.
+//!
+//! A frame's entry in the frame table holds its first mapping. Further mappings, of
+//! frames shared between address spaces, are held in overflow links chained from the
+//! entry. The links are taken from a pool which follows the frame table in memory,
+//! so recording a mapping never allocates.
+
+use super::{Allocator, FrameTableInner, FrameUse};
+
+use crate::archs::{arch::Arch, arch::PageBlockDescriptor, PagerTrait};
+use crate::pager::{PhysAddr, VirtAddr};
+use crate::{Error, Result};
+
+use core::mem::size_of;
+use core::ptr::NonNull;
+
+/// Frames for each overflow link in the pool.
+const FRAMES_PER_LINK: usize = 8;
+
+/// End of a chain of links.
+pub const NO_LINK: u32 = u32::MAX;
+
+/// A leaf entry mapping a frame, and the page it maps.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rmap {
+    pub page_block_descriptor: NonNull<PageBlockDescriptor>,
+    pub virt_addr: VirtAddr,
+}
+
+#[derive(Debug)]
+struct Link {
+    rmap: Option<Rmap>, // None while on the free chain
+    next: u32,
+}
+
+/// Pool of links for mappings beyond the first of each frame.
+#[derive(Debug)]
+pub struct Overflow {
+    links: &'static mut [Link],
+    free: u32,
+    in_use: u32,
+}
+
+impl Overflow {
+    /// Bytes of storage for the links of a number of frames.
+    pub fn storage_bytes(frames: usize) -> usize {
+        frames / FRAMES_PER_LINK * size_of::<Link>()
+    }
+
+    /// Chain all the links in some storage onto the free chain.
+    ///
+    /// Note: The storage must be storage_bytes long, and not used for anything else.
+    pub unsafe fn new(ptr: *mut u8, frames: usize) -> Self {
+        let len = frames / FRAMES_PER_LINK;
+        let links = core::slice::from_raw_parts_mut(ptr as *mut Link, len);
+        for (i, link) in links.iter_mut().enumerate() {
+            let next = if i + 1 < len { i as u32 + 1 } else { NO_LINK };
+            *link = Link { rmap: None, next };
+        }
+        Self {
+            links,
+            free: if len > 0 { 0 } else { NO_LINK },
+            in_use: 0,
+        }
+    }
+
+    /// Point at the links where they are now mapped.
+    pub unsafe fn repoint(&mut self, ptr: *mut u8, frames: usize) {
+        let len = frames / FRAMES_PER_LINK;
+        self.links = core::slice::from_raw_parts_mut(ptr as *mut Link, len);
+    }
+
+    /// Number of links holding a mapping.
+    pub fn in_use(&self) -> u32 {
+        self.in_use
+    }
+
+    /// True iff no more mappings can be added.
+    pub fn is_full(&self) -> bool {
+        self.free == NO_LINK
+    }
+
+    /// The mappings on a chain.
+    pub fn chain(&self, head: u32) -> impl Iterator<Item = Rmap> + '_ {
+        let mut i = head;
+        core::iter::from_fn(move || {
+            let link = self.links.get(i as usize)?;
+            i = link.next;
+            link.rmap
+        })
+    }
+
+    /// Add a mapping to the front of a chain.
+    pub fn push(&mut self, head: &mut u32, rmap: Rmap) -> Result<()> {
+        let i = self.free;
+        if i == NO_LINK {
+            return Err(Error::OutOfMemory);
+        }
+        let link = &mut self.links[i as usize];
+        self.free = link.next;
+        *link = Link {
+            rmap: Some(rmap),
+            next: *head,
+        };
+        *head = i;
+        self.in_use += 1;
+        Ok(())
+    }
+
+    /// Take the first mapping off a chain.
+    pub fn pop(&mut self, head: &mut u32) -> Option<Rmap> {
+        let i = *head;
+        if i == NO_LINK {
+            return None;
+        }
+        let link = &mut self.links[i as usize];
+        *head = link.next;
+        let rmap = link.rmap.take();
+        link.next = self.free;
+        self.free = i;
+        self.in_use -= 1;
+        rmap
+    }
+
+    /// Remove the mapping by an entry from a chain, returning whether it was there.
+    pub fn remove(
+        &mut self,
+        head: &mut u32,
+        page_block_descriptor: NonNull<PageBlockDescriptor>,
+    ) -> bool {
+        let maps = |link: &Link| {
+            link.rmap.map(|rmap| rmap.page_block_descriptor) == Some(page_block_descriptor)
+        };
+        if *head == NO_LINK {
+            return false;
+        }
+        if maps(&self.links[*head as usize]) {
+            self.pop(head);
+            return true;
+        }
+        let mut prev = *head as usize;
+        while self.links[prev].next != NO_LINK {
+            let mut next = self.links[prev].next;
+            if maps(&self.links[next as usize]) {
+                self.pop(&mut next);
+                self.links[prev].next = next;
+                return true;
+            }
+            prev = next as usize;
+        }
+        false
+    }
+}
+
+impl FrameTableInner {
+    /// Record a leaf entry which maps a frame.
+    pub(super) fn add_mapping(&mut self, i: u32, rmap: Rmap) -> Result<()> {
+        let entry = &mut self.table[i];
+        if entry.page_block_descriptor.is_none() {
+            entry.page_block_descriptor = Some(rmap.page_block_descriptor);
+            entry.virt_addr = rmap.virt_addr;
+            return Ok(());
+        }
+        self.overflow.push(&mut entry.more_mappings, rmap)
+    }
+
+    /// Whether another leaf entry mapping a frame can be recorded.
+    pub(super) fn can_add_mapping(&self, i: u32) -> bool {
+        self.table[i].page_block_descriptor.is_none() || !self.overflow.is_full()
+    }
+
+    /// Every leaf entry known to map a frame.
+    pub(super) fn mappings(&self, i: u32) -> impl Iterator<Item = Rmap> + '_ {
+        let entry = &self.table[i];
+        let first = entry.page_block_descriptor.map(|page_block_descriptor| Rmap {
+            page_block_descriptor,
+            virt_addr: entry.virt_addr,
+        });
+        first
+            .into_iter()
+            .chain(self.overflow.chain(entry.more_mappings))
+    }
+
+    /// Forget a leaf entry which no longer maps a frame, returning whether it was known.
+    pub(super) fn remove_mapping(
+        &mut self,
+        i: u32,
+        page_block_descriptor: NonNull<PageBlockDescriptor>,
+    ) -> bool {
+        let entry = &mut self.table[i];
+        if entry.page_block_descriptor == Some(page_block_descriptor) {
+            // the next mapping takes its place in the entry
+            let next = self.overflow.pop(&mut entry.more_mappings);
+            entry.page_block_descriptor = next.map(|rmap| rmap.page_block_descriptor);
+            if let Some(rmap) = next {
+                entry.virt_addr = rmap.virt_addr;
+            }
+            return true;
+        }
+        self.overflow
+            .remove(&mut entry.more_mappings, page_block_descriptor)
+    }
+
+    /// Take the first known mapping of a frame.
+    pub(super) fn take_mapping(&mut self, i: u32) -> Option<Rmap> {
+        let entry = &self.table[i];
+        let rmap = entry.page_block_descriptor.map(|page_block_descriptor| Rmap {
+            page_block_descriptor,
+            virt_addr: entry.virt_addr,
+        })?;
+        self.remove_mapping(i, rmap.page_block_descriptor);
+        Some(rmap)
+    }
+
+    /// Forget all the mappings of a frame.
+    pub(super) fn forget_mappings(&mut self, i: u32) {
+        while self.take_mapping(i).is_some() {}
+    }
+
+    /// The only entry mapping a frame, if it is mapped exactly once and the entry is known.
+    pub(super) fn sole_mapping(&self, i: u32) -> Option<NonNull<PageBlockDescriptor>> {
+        let entry = &self.table[i];
+        match (entry.map_count, entry.more_mappings) {
+            (1, NO_LINK) => entry.page_block_descriptor,
+            _ => None,
+        }
+    }
+
+    /// Unmap a frame from every leaf entry known to map it.
+    ///
+    /// Each mapping's count is freed, so the frame is freed with its last mapping.
+    /// To keep the frame and its contents, count a mapping for it first. Returns the
+    /// number of entries unmapped.
+    pub(super) fn unmap_everywhere(&mut self, i: u32) -> Result<u32> {
+        let phys_addr = PhysAddr::ram_page(i as usize);
+        let mut count = 0;
+        while let Some(rmap) = self.take_mapping(i) {
+            debug!("unmap_everywhere: {:?} {:?}", rmap.virt_addr, phys_addr);
+            unsafe {
+                (*rmap.page_block_descriptor.as_ptr()).unmap();
+            }
+            Arch::invalidate_tlb(rmap.virt_addr)?;
+            self.free(phys_addr)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Clear the access flag of every leaf entry known to map a Warm frame.
+    ///
+    /// Only the Warm queue is visited, so the next access to each page is recorded
+    /// without walking the page tables. Returns the number of entries cleared.
+    pub(super) fn clear_warm_accessed(&self) -> Result<u32> {
+        let mut count = 0;
+        for i in self.table.iter(FrameUse::UserWarm) {
+            for rmap in self.mappings(i) {
+                let desc = unsafe { &mut *rmap.page_block_descriptor.as_ptr() };
+                if !desc.is_accessed() {
+                    continue;
+                }
+                desc.clear_accessed();
+                Arch::invalidate_tlb(rmap.virt_addr)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{with_inner, PAGES};
+    use super::*;
+
+    use crate::pager::{Attributes, FramePurpose};
+
+    fn rmap(desc: &mut PageBlockDescriptor, virt_addr: usize) -> Rmap {
+        Rmap {
+            page_block_descriptor: NonNull::from(desc),
+            virt_addr: VirtAddr::at(virt_addr),
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        let mut storage = [0u64; 16];
+        let mut overflow = unsafe { Overflow::new(storage.as_mut_ptr() as *mut u8, 16) };
+        let mut descs = [PageBlockDescriptor::new_entry(None, Attributes::USER_DATA); 3];
+        let a = rmap(&mut descs[0], 0x1000);
+        let b = rmap(&mut descs[1], 0x2000);
+        let c = rmap(&mut descs[2], 0x3000);
+
+        let mut head = NO_LINK;
+        assert_ok!(overflow.push(&mut head, a));
+        assert_ok!(overflow.push(&mut head, b));
+        assert_eq!(Err(Error::OutOfMemory), overflow.push(&mut head, c));
+        assert_eq!(2, overflow.in_use());
+
+        assert!(!overflow.remove(&mut head, c.page_block_descriptor));
+        assert!(overflow.remove(&mut head, a.page_block_descriptor));
+        assert_some_eq!(overflow.pop(&mut head), b);
+        assert_none!(overflow.pop(&mut head));
+        assert_eq!(0, overflow.in_use());
+    }
+
+    #[test]
+    fn shared_frame() {
+        with_inner(|inner| {
+            let phys_addr = inner.alloc_for_overwrite(FramePurpose::User).unwrap();
+            let i = inner.index(phys_addr);
+            let mut descs =
+                [PageBlockDescriptor::new_entry(Some(phys_addr), Attributes::USER_DATA); 3];
+            for (n, desc) in descs.iter_mut().enumerate() {
+                assert_ok!(inner.increment_map_count(phys_addr));
+                assert_ok!(inner.add_mapping(i, rmap(desc, 0x1000 * (n + 1))));
+            }
+            assert_none!(inner.sole_mapping(i));
+            assert_eq!(2, inner.stats().overflow_mappings);
+
+            // the first mapping is replaced by the next
+            assert!(inner.remove_mapping(i, NonNull::from(&mut descs[0])));
+            assert_ok!(inner.free(phys_addr));
+            assert_eq!(1, inner.stats().overflow_mappings);
+            assert!(!inner.remove_mapping(i, NonNull::from(&mut descs[0])));
+
+            assert_ok_eq!(inner.unmap_everywhere(i), 2);
+            assert!(descs[0].is_valid());
+            assert!(!descs[1].is_valid() && !descs[2].is_valid());
+            assert_eq!(0, inner.stats().overflow_mappings);
+            let stats = inner.stats();
+            assert_eq!((PAGES, stats.allocs), (stats.free, stats.frees));
+        });
+    }
+
+    #[test]
+    fn clearing_warm_accessed() {
+        with_inner(|inner| {
+            let phys_addr = inner.alloc_for_overwrite(FramePurpose::User).unwrap();
+            let i = inner.index(phys_addr);
+            let mut descs =
+                [PageBlockDescriptor::new_entry(Some(phys_addr), Attributes::USER_DATA); 2];
+            descs[0].set_accessed();
+            for (n, desc) in descs.iter_mut().enumerate() {
+                assert_ok!(inner.increment_map_count(phys_addr));
+                assert_ok!(inner.add_mapping(i, rmap(desc, 0x1000 * (n + 1))));
+            }
+            assert_ok_eq!(inner.clear_warm_accessed(), 1);
+            assert!(!descs[0].is_accessed() && !descs[1].is_accessed());
+        });
+    }
+}
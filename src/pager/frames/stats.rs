@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Counters for the frame table.
+//!
+//! Queue lengths are kept as frames move, so reading them is O(1). Demotion
+//! moves the whole Warm queue without visiting its frames, so a frame recorded
+//! as Warm with an old epoch is actually on the Cold queue.
+
+use super::{allocator, FrameTable, FrameTableInner, FrameUse};
+
+use crate::pager::AddrRange;
+use crate::{Error, Result};
+
+use core::mem::variant_count;
+
+/// Number of queues in the frame table.
+pub const FRAME_USES: usize = variant_count::<FrameUse>();
+
+/// Snapshot of the use of physical memory.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    /// Number of frames on each queue, indexed by FrameUse.
+    pub queue_lens: [u32; FRAME_USES],
+    /// Frames allocated to user processes.
+    pub user_count: u32,
+    /// User frames accessed since the last demotion.
+    pub user_warm_count: u32,
+    /// Frames of RAM managed by the table.
+    pub total: u32,
+    /// Frames on the Free queue.
+    pub free: u32,
+    /// Frames on the Zeroed queue.
+    pub zeroed: u32,
+    /// Frames allocated since start-up.
+    pub allocs: u64,
+    /// Frames freed since start-up.
+    pub frees: u64,
+    /// Mappings of shared frames held in overflow links.
+    pub overflow_mappings: u32,
+}
+
+impl FrameStats {
+    /// Number of frames on a queue.
+    pub fn queue_len(&self, frame_use: FrameUse) -> u32 {
+        self.queue_lens[frame_use as usize]
+    }
+}
+
+impl FrameTableInner {
+    pub(super) fn queue_len(&self, frame_use: FrameUse) -> u32 {
+        self.queue_lens[frame_use as usize]
+    }
+
+    /// The queue a frame is on.
+    pub(super) fn frame_use(&self, i: u32) -> FrameUse {
+        match self.table[i].frame_use {
+            FrameUse::UserWarm if !self.is_warm(i) => FrameUse::UserCold,
+            frame_use => frame_use,
+        }
+    }
+
+    fn moved(&mut self, i: u32, from: FrameUse, to: FrameUse) {
+        self.table[i].frame_use = to;
+        self.queue_lens[from as usize] -= 1;
+        self.queue_lens[to as usize] += 1;
+    }
+
+    /// Move a frame between queues.
+    pub(super) fn move_to(&mut self, i: u32, from: FrameUse, to: FrameUse) -> Result<()> {
+        self.table.remove_to(i, to)?;
+        self.moved(i, from, to);
+        Ok(())
+    }
+
+    /// Move the frame at the back of a queue to another.
+    pub(super) fn drip(&mut self, from: FrameUse, to: FrameUse) -> Result<u32> {
+        let i = self.table.drip_to(from, to)?;
+        self.moved(i, from, to);
+        Ok(i)
+    }
+
+    /// Move a run of frames to a queue.
+    pub(super) fn move_seq_to(&mut self, i: u32, j: u32, to: FrameUse) -> Result<()> {
+        self.table.remove_seq_to(i, j, to)?;
+        for k in i..=j {
+            let from = self.frame_use(k);
+            self.moved(k, from, to);
+        }
+        Ok(())
+    }
+
+    /// Move the whole of one queue to the front of another, without visiting its frames.
+    pub(super) fn clear(&mut self, from: FrameUse, to: FrameUse) -> Result<()> {
+        match self.table.clear_to(from, to) {
+            Ok(_) | Err(Error::Success) => {}
+            Err(e) => return Err(e),
+        }
+        self.queue_lens[to as usize] += self.queue_lens[from as usize];
+        self.queue_lens[from as usize] = 0;
+        Ok(())
+    }
+
+    pub(super) fn stats(&self) -> FrameStats {
+        FrameStats {
+            queue_lens: self.queue_lens,
+            user_count: self.user_count,
+            user_warm_count: self.user_warm_count,
+            total: self.ram_range.length_in_pages() as u32,
+            free: self.queue_len(FrameUse::Free),
+            zeroed: self.queue_len(FrameUse::Zeroed),
+            allocs: self.allocs,
+            frees: self.frees,
+            overflow_mappings: self.overflow.in_use(),
+        }
+    }
+}
+
+impl FrameTable {
+    /// Counters for the use of physical memory.
+    pub fn stats(&mut self) -> Result<FrameStats> {
+        Ok(self.inner()?.stats())
+    }
+}
+
+/// Counters for the use of physical memory.
+pub fn stats() -> Result<FrameStats> {
+    allocator().lock().stats()
+}
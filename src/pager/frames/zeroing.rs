@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Zeroing free frames in the background.
+//!
+//! Frames move from Free to Zeroing while they are cleared, so the frame table
+//! is not locked while a page is written, and then on to Zeroed where
+//! `alloc_zeroed` can take them without further work.
+
+use super::{allocator, FrameTable, FrameTableInner, FrameUse};
+
+use crate::pager::{mem_translation, PhysAddr, Translate, PAGESIZE_BYTES};
+use crate::Result;
+
+/// Default number of frames to keep zeroed in reserve.
+pub const DEFAULT_ZEROED_TARGET: u32 = 64;
+
+/// How often allocations were served from the zeroed reserve.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ZeroingStats {
+    /// Zeroed allocations taken straight from the Zeroed queue.
+    pub fast: u64,
+    /// Zeroed allocations which had to clear a page while the frame table was locked.
+    pub slow: u64,
+    /// Frames cleared by the background worker.
+    pub zeroed: u64,
+}
+
+/// Fill a frame with zeros.
+pub(super) fn zero(phys_addr: PhysAddr) -> Result<()> {
+    let page: *mut u8 = mem_translation().translate_phys(phys_addr)?.into();
+    unsafe {
+        core::ptr::write_bytes(page, 0, PAGESIZE_BYTES);
+    }
+    Ok(())
+}
+
+impl FrameTableInner {
+    /// Take a free frame to be zeroed, if the reserve is below target.
+    fn start_zeroing(&mut self) -> Result<Option<PhysAddr>> {
+        let reserve = self.queue_len(FrameUse::Zeroed) + self.queue_len(FrameUse::Zeroing);
+        // a frame set aside for the heap must stay free or zeroed meanwhile
+        if reserve >= self.zeroed_target || self.unreserved() == 0 {
+            return Ok(None);
+        }
+        match self.drip(FrameUse::Free, FrameUse::Zeroing) {
+            Ok(i) => Ok(Some(PhysAddr::ram_page(i as usize))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Add a frame which has been zeroed to the reserve.
+    fn finish_zeroing(&mut self, phys_addr: PhysAddr) -> Result<()> {
+        let i = self.index(phys_addr);
+        self.move_to(i, FrameUse::Zeroing, FrameUse::Zeroed)?;
+        self.zeroing_stats.zeroed += 1;
+        Ok(())
+    }
+}
+
+impl FrameTable {
+    /// Set the number of frames to keep zeroed in reserve.
+    pub fn set_zeroed_target(&mut self, pages: u32) -> Result<()> {
+        info!("set_zeroed_target: {}", pages);
+        self.inner()?.zeroed_target = pages;
+        Ok(())
+    }
+
+    /// Counters for the zeroed reserve.
+    pub fn zeroing_stats(&mut self) -> Result<ZeroingStats> {
+        Ok(self.inner()?.zeroing_stats)
+    }
+}
+
+/// Zero free frames until the reserve reaches its target or no frames are free.
+///
+/// Intended to be called when a core has nothing else to do. Returns the number
+/// of frames zeroed.
+pub fn zero_free_frames() -> Result<u32> {
+    let mut count = 0;
+    loop {
+        let phys_addr = match allocator().lock().inner()?.start_zeroing()? {
+            Some(phys_addr) => phys_addr,
+            None => break,
+        };
+        zero(phys_addr)?;
+        allocator().lock().inner()?.finish_zeroing(phys_addr)?;
+        count += 1;
+    }
+    if count > 0 {
+        debug!("zero_free_frames: {}", count);
+    }
+    Ok(count)
+}
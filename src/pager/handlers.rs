@@ -3,12 +3,13 @@
 //! Responding to virtual memory exceptions
 
 use super::{
-    frames, mem_translation, Addr, AddrRange, AttributeField, Attributes, FixedOffset,
-    FrameAllocator, FramePurpose, VirtAddr, VirtAddrRange, KERNEL_PAGE_DIRECTORY,
+    block, frames, layout, mem_fixed_offset, mem_translation, stack_guarded_by, swap, Addr,
+    AddrRange, AttributeField, Attributes, FixedOffset, FramePurpose, PhysAddr, RangeContent,
+    Translate, VirtAddr, VirtAddrRange, KERNEL_PAGE_DIRECTORY, PAGESIZE_BYTES,
 };
 
-use crate::archs::{arch::Arch, PageDirectory, PagerTrait};
-use crate::Result;
+use crate::archs::PageDirectory;
+use crate::{Error, Result};
 
 /// What the architecture should do after a handler invocation.
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -21,26 +22,254 @@ pub enum HandlerReturnAction {
 
 /// The kernel has accessed an invalid page.
 ///
-/// FIXME: is this called when fault_addr is outside of kernel_range?
+/// The kernel heap is mapped on demand, and reads of an on-demand page map the
+/// shared zero frame until the page is written. Persistent pages are given their
+/// own frame on first access, so they can be swapped out and checkpointed. Pages
+/// of block mappings are read from their device. Faults anywhere else are not
+/// satisfied.
 pub fn kernel_translation_fault(
     fault_addr: VirtAddr,
     _level: Option<u64>,
+    is_write: bool,
 ) -> Result<HandlerReturnAction> {
-    info!("kernel_translation_fault: {:?}", fault_addr);
+    info!("kernel_translation_fault: {:?} {}", fault_addr, is_write);
 
-    assert_gt!(fault_addr, Arch::kernel_base());
-    let phys_addr = frames::allocator()
+    // the page may be being written out, and can't be read back until it has been
+    frames::wait_for_paging_out(fault_addr)?;
+    let swapped = KERNEL_PAGE_DIRECTORY
         .lock()
-        .alloc_zeroed(FramePurpose::Kernel)?;
+        .swapped_out(fault_addr, mem_fixed_offset())?;
+    if let Some(sector) = swapped {
+        swap::reload(fault_addr, sector)?;
+        return Ok(HandlerReturnAction::Return);
+    }
+
+    match layout::content_at(fault_addr) {
+        Some(RangeContent::KernelHeap) => {}
+        Some(RangeContent::Persistent) => {
+            let phys_addr = frames::alloc_zeroed(FramePurpose::User)?;
+            KERNEL_PAGE_DIRECTORY.lock().map_translation(
+                VirtAddrRange::page_containing(fault_addr),
+                FixedOffset::new(phys_addr, fault_addr.page_base()),
+                WRITTEN_ATTRIBUTES,
+                frames::allocator(),
+                mem_translation(),
+            )?;
+            return Ok(HandlerReturnAction::Return);
+        }
+        Some(RangeContent::BlockMapped) => {
+            block::fault(fault_addr, is_write)?;
+            return Ok(HandlerReturnAction::Return);
+        }
+        Some(RangeContent::KernelStack) => {
+            if stack_guarded_by(fault_addr).is_some() {
+                kernel_stack_overflow(fault_addr)
+            }
+            return Err(Error::SegmentFault);
+        }
+        _ => return Err(Error::SegmentFault),
+    }
+
+    // the frame comes from those set aside when the heap grew
+    let (phys_addr, attributes) = if is_write {
+        let phys_addr = frames::allocator().lock().alloc_reserved()?;
+        (phys_addr, WRITTEN_ATTRIBUTES)
+    } else {
+        let phys_addr = frames::allocator().lock().zero_frame()?;
+        (phys_addr, Attributes::KERNEL_ZERO)
+    };
     let translation = FixedOffset::new(phys_addr, fault_addr.page_base());
-    const ATTRIBUTES: Attributes = Attributes::KERNEL_DATA.set(AttributeField::Accessed);
     let mut page_directory = KERNEL_PAGE_DIRECTORY.lock();
-    page_directory.map_translation(
+    let mapped = page_directory.map_translation(
         VirtAddrRange::page_containing(fault_addr),
         translation,
-        ATTRIBUTES,
+        attributes,
         frames::allocator(),
         mem_translation(),
+    );
+    if let Err(e) = mapped {
+        drop(page_directory);
+        if is_write {
+            frames::allocator().lock().discard_reserved(phys_addr)?;
+        }
+        return Err(e);
+    }
+    Ok(HandlerReturnAction::Return)
+}
+
+/// The kernel has run off the bottom of a stack.
+///
+/// Called for faults in a guard page, and when the exception vector finds the
+/// stack pointer itself is invalid.
+pub fn kernel_stack_overflow(fault_addr: VirtAddr) -> ! {
+    match stack_guarded_by(fault_addr) {
+        Some((core, stack)) => panic!(
+            "stack overflow on core {}: {:?} beyond stack {:?}",
+            core, fault_addr, stack
+        ),
+        None => panic!("stack overflow: {:?} beyond any stack", fault_addr),
+    }
+}
+
+/// Attributes of an on-demand kernel page once it has been written.
+const WRITTEN_ATTRIBUTES: Attributes = Attributes::KERNEL_DATA.set(AttributeField::Accessed);
+
+/// Allocate a frame for a private copy of a copy-on-write kernel page.
+///
+/// Pages of the heap take a frame set aside when the heap grew.
+fn alloc_copy(fault_addr: VirtAddr) -> Result<PhysAddr> {
+    match layout::content_at(fault_addr) {
+        Some(RangeContent::KernelHeap) => frames::allocator().lock().alloc_reserved(),
+        _ => frames::alloc_for_overwrite(FramePurpose::Kernel),
+    }
+}
+
+/// Free a frame from alloc_copy which was never mapped.
+fn discard_copy(fault_addr: VirtAddr, phys_addr: PhysAddr) -> Result<()> {
+    let mut allocator = frames::allocator().lock();
+    match layout::content_at(fault_addr) {
+        Some(RangeContent::KernelHeap) => allocator.discard_reserved(phys_addr),
+        _ => allocator.discard(phys_addr),
+    }
+}
+
+/// The kernel has written to a page without write permission.
+///
+/// The first write to a clean page marks it dirty. Dirty state is emulated in
+/// software rather than left to FEAT_HAFDBS, because hardware dirty updates also
+/// need hardware access flag updates, which would hide the access flag faults that
+/// page replacement relies on.
+///
+/// If the page is copy-on-write, give it a private copy of the frame and make it
+/// writable, otherwise the access is not allowed.
+pub fn kernel_permission_fault(
+    fault_addr: VirtAddr,
+    _level: Option<u64>,
+    is_write: bool,
+) -> Result<HandlerReturnAction> {
+    info!("kernel_permission_fault: {:?} {}", fault_addr, is_write);
+
+    if is_write
+        && KERNEL_PAGE_DIRECTORY
+            .lock()
+            .set_dirty(fault_addr, mem_fixed_offset())?
+    {
+        return Ok(HandlerReturnAction::Return);
+    }
+
+    let page = VirtAddrRange::page_containing(fault_addr);
+    let (attributes, shared) = {
+        let page_directory = KERNEL_PAGE_DIRECTORY.lock();
+        let attributes = page_directory.attributes(fault_addr, mem_fixed_offset())?;
+        let shared = page_directory.maps_to(page.base(), mem_fixed_offset())?;
+        (attributes, shared)
+    };
+    if !is_write || !attributes.is_set(AttributeField::CopyOnWrite) {
+        return Err(Error::SegmentFault);
+    }
+
+    let phys_addr = alloc_copy(fault_addr)?;
+    unsafe {
+        let src: *const u8 = mem_translation().translate_phys(shared)?.into();
+        let dst: *mut u8 = mem_translation().translate_phys(phys_addr)?.into();
+        core::ptr::copy_nonoverlapping(src, dst, PAGESIZE_BYTES);
+    }
+
+    let mut page_directory = KERNEL_PAGE_DIRECTORY.lock();
+    let unchanged = page_directory
+        .attributes(fault_addr, mem_fixed_offset())
+        .map(|attributes| attributes.is_set(AttributeField::CopyOnWrite))
+        .unwrap_or(false)
+        && page_directory.maps_to(page.base(), mem_fixed_offset()) == Ok(shared);
+    if !unchanged {
+        // another core copied the page first, so retry the access against its copy
+        drop(page_directory);
+        discard_copy(fault_addr, phys_addr)?;
+        return Ok(HandlerReturnAction::Return);
+    }
+    let remapped = page_directory.remap(
+        page,
+        FixedOffset::new(phys_addr, page.base()),
+        frames::allocator(),
+        mem_fixed_offset(),
+    );
+    if let Err(e) = remapped {
+        drop(page_directory);
+        discard_copy(fault_addr, phys_addr)?;
+        return Err(e);
+    }
+    page_directory.protect(
+        page,
+        WRITTEN_ATTRIBUTES,
+        frames::allocator(),
+        mem_fixed_offset(),
     )?;
     Ok(HandlerReturnAction::Return)
 }
+
+/// A page has been accessed for the first time since its access flag was cleared.
+///
+/// Record the access for page replacement, and if that ages the working set,
+/// clear the access flags on the pages that are now Cold.
+pub fn kernel_access_flag_fault(
+    fault_addr: VirtAddr,
+    _level: Option<u64>,
+) -> Result<HandlerReturnAction> {
+    info!("kernel_access_flag_fault: {:?}", fault_addr);
+
+    let mut page_directory = KERNEL_PAGE_DIRECTORY.lock();
+    let phys_addr = page_directory.set_accessed(fault_addr, mem_fixed_offset())?;
+    frames::allocator().lock().touch(phys_addr)?;
+    Ok(HandlerReturnAction::Return)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::archs::arch::{Arch, Cpu, PhysicalMemory, KERNEL_HANDLERS};
+    use crate::archs::PagerTrait;
+    use crate::util::locked::Locked;
+
+    use alloc::boxed::Box;
+
+    #[test]
+    fn first_write_marks_dirty() {
+        let memory = PhysicalMemory::new(2);
+        let mem = memory.translation();
+        let allocator: &'static Locked<PhysicalMemory> = Box::leak(Box::new(Locked::new(memory)));
+        let phys_addr = allocator.lock().alloc_zeroed(FramePurpose::Kernel).unwrap();
+        let page = VirtAddrRange::page_containing(Arch::kernel_base().increment(0x7f_0000_0000));
+        {
+            let mut page_directory = KERNEL_PAGE_DIRECTORY.lock();
+            let translation = FixedOffset::new(phys_addr, page.base());
+            assert_ok!(page_directory.map_translation(
+                page,
+                translation,
+                WRITTEN_ATTRIBUTES,
+                allocator,
+                &mem
+            ));
+            assert_ok!(page_directory.clear_dirty(page.base()));
+        }
+
+        let cpu = Cpu::new(&KERNEL_PAGE_DIRECTORY, KERNEL_HANDLERS, mem);
+        assert_ok!(cpu.store(page.base(), 42));
+        assert_ok_eq!(cpu.load(page.base()), 42);
+        assert_err!(cpu.fetch(page.base()));
+
+        assert_ok!(KERNEL_PAGE_DIRECTORY.lock().unmap(page, allocator, &mem));
+        assert_eq!(0, allocator.lock().allocated());
+    }
+
+    #[test]
+    fn unmapped_kernel_access() {
+        let memory = PhysicalMemory::new(1);
+        let cpu = Cpu::new(
+            &KERNEL_PAGE_DIRECTORY,
+            KERNEL_HANDLERS,
+            memory.translation(),
+        );
+        assert_err!(cpu.load(VirtAddr::at(usize::MAX).page_base()));
+    }
+}
@@ -1,11 +1,27 @@
 // SPDX-License-Identifier: Unlicense
 
+//! The layout of the address space.
+//!
+//! The extents needed to boot are placed in the kernel's half by `init`. Further
+//! extents, in either half, can be registered at a given range, or reserved
+//! wherever there is room, while the kernel runs. Extents must be page-aligned and
+//! must not overlap each other, or the unmapped guard gaps kept below some of the
+//! boot extents.
+//!
+//! With the `kaslr` feature, and a seed from the device tree, the frame table,
+//! stack, heap and device ranges are offset within their alignment. The same seed
+//! always gives the same layout.
+
 use crate::archs::{arch::Arch, PagerTrait};
 use crate::device;
 use crate::pager::{FixedOffset, PhysAddr};
+use crate::util::locked::Locked;
 use crate::{Error, Result};
 
-use super::{Addr, AddrRange, AttributeField, Attributes, PhysAddrRange, VirtAddr, VirtAddrRange};
+use super::{
+    Addr, AddrRange, AttributeField, Attributes, PhysAddrRange, VirtAddr, VirtAddrRange,
+    PAGESIZE_BYTES,
+};
 
 use core::fmt;
 use core::fmt::{Debug, Formatter};
@@ -29,13 +45,19 @@ pub enum RangeContent {
     KernelStack,
     /// Area for shared Kernel heap
     KernelHeap,
+    /// Area for pages which are checkpointed to the swap device
+    Persistent,
     /// Area to map memory-mapped device pages
     Device,
     /// The device tree blob
     DTB,
+    /// Area to map pages backed by block devices
+    BlockMapped,
+    /// Area reserved while the kernel runs, by name
+    Named(&'static str),
 }
 
-/// Range requiring to be mapped.
+/// How to place a range needed to boot.
 #[derive(Copy, Clone)]
 struct KernelExtent {
     content: RangeContent,
@@ -44,15 +66,14 @@ struct KernelExtent {
     virt_range_gap: &'static dyn Fn() -> Option<usize>,
     phys_addr_range: &'static dyn Fn() -> Option<PhysAddrRange>,
     attributes: Attributes,
-    virt_range: Option<VirtAddrRange>,
+    randomise: bool, // offset within its alignment under KASLR
 }
 
 impl Debug for KernelExtent {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
-            "KernelExtent {{{:?} => {:?}, {:#x}, {:#x}, {:?}, {:?}}}",
-            self.virt_range,
+            "KernelExtent {{{:?}, {:#x}, {:#x}, {:?}, {:?}}}",
             self.content,
             self.virt_range_align,
             self.virt_range_min_extent,
@@ -65,7 +86,7 @@ impl Debug for KernelExtent {
 const MB: usize = 1024 * 1024;
 const GB: usize = 1024 * MB;
 
-static mut LAYOUT: [KernelExtent; 10] = [
+const BOOT_LAYOUT: [KernelExtent; 12] = [
     KernelExtent {
         content: RangeContent::RAM,
         virt_range_align: 1 * GB,
@@ -73,7 +94,7 @@ static mut LAYOUT: [KernelExtent; 10] = [
         virt_range_gap: &{ || None },
         phys_addr_range: &{ || Some(Arch::ram_range()) },
         attributes: Attributes::RAM,
-        virt_range: None,
+        randomise: false,
     },
     KernelExtent {
         content: RangeContent::KernelText,
@@ -89,7 +110,7 @@ static mut LAYOUT: [KernelExtent; 10] = [
         },
         phys_addr_range: &{ || Some(Arch::text_image()) },
         attributes: Attributes::KERNEL_EXEC,
-        virt_range: None,
+        randomise: false,
     },
     KernelExtent {
         content: RangeContent::KernelStatic,
@@ -98,7 +119,7 @@ static mut LAYOUT: [KernelExtent; 10] = [
         virt_range_gap: &{ || None },
         phys_addr_range: &{ || Some(Arch::static_image()) },
         attributes: Attributes::KERNEL_RO_DATA,
-        virt_range: None,
+        randomise: false,
     },
     KernelExtent {
         content: RangeContent::KernelData,
@@ -107,7 +128,7 @@ static mut LAYOUT: [KernelExtent; 10] = [
         virt_range_gap: &{ || None },
         phys_addr_range: &{ || Some(Arch::data_image()) },
         attributes: Attributes::KERNEL_DATA,
-        virt_range: None,
+        randomise: false,
     },
     KernelExtent {
         content: RangeContent::ResetStack,
@@ -116,7 +137,7 @@ static mut LAYOUT: [KernelExtent; 10] = [
         virt_range_gap: &{ || None },
         phys_addr_range: &{ || Some(Arch::stack_range()) },
         attributes: Attributes::KERNEL_DATA,
-        virt_range: None,
+        randomise: false,
     },
     KernelExtent {
         content: RangeContent::FrameTable,
@@ -132,7 +153,7 @@ static mut LAYOUT: [KernelExtent; 10] = [
             }
         },
         attributes: Attributes::KERNEL_DATA.set(AttributeField::Block),
-        virt_range: None,
+        randomise: true,
     },
     KernelExtent {
         content: RangeContent::KernelStack,
@@ -141,7 +162,7 @@ static mut LAYOUT: [KernelExtent; 10] = [
         virt_range_gap: &{ || Some(1 * GB) },
         phys_addr_range: &{ || None },
         attributes: Attributes::KERNEL_DATA,
-        virt_range: None,
+        randomise: true,
     },
     KernelExtent {
         content: RangeContent::KernelHeap,
@@ -150,7 +171,16 @@ static mut LAYOUT: [KernelExtent; 10] = [
         virt_range_gap: &{ || Some(1 * GB) },
         phys_addr_range: &{ || None },
         attributes: Attributes::KERNEL_DATA,
-        virt_range: None,
+        randomise: true,
+    },
+    KernelExtent {
+        content: RangeContent::Persistent,
+        virt_range_align: 1 * GB,
+        virt_range_min_extent: 1 * GB,
+        virt_range_gap: &{ || Some(1 * GB) },
+        phys_addr_range: &{ || None },
+        attributes: Attributes::KERNEL_DATA,
+        randomise: false,
     },
     KernelExtent {
         content: RangeContent::Device,
@@ -159,7 +189,7 @@ static mut LAYOUT: [KernelExtent; 10] = [
         virt_range_gap: &{ || Some(1 * GB) },
         phys_addr_range: &{ || None },
         attributes: Attributes::DEVICE,
-        virt_range: None,
+        randomise: true,
     },
     KernelExtent {
         content: RangeContent::DTB,
@@ -168,13 +198,193 @@ static mut LAYOUT: [KernelExtent; 10] = [
         virt_range_gap: &{ || None },
         phys_addr_range: &{ || unsafe { device::PDTB } },
         attributes: Attributes::KERNEL_RO_DATA,
-        virt_range: None,
+        randomise: false,
+    },
+    KernelExtent {
+        content: RangeContent::BlockMapped,
+        virt_range_align: 1 * GB,
+        virt_range_min_extent: 64 * GB,
+        virt_range_gap: &{ || Some(1 * GB) },
+        phys_addr_range: &{ || None },
+        attributes: Attributes::KERNEL_DATA,
+        randomise: false,
     },
 ];
 
+/// Granularity of KASLR offsets, so block mappings stay aligned.
+const KASLR_GRANULE: usize = 2 * MB;
+
+/// Deterministic source of KASLR offsets (splitmix64).
+///
+/// FIXME: The kernel text is not relocatable, so is not offset.
+struct Kaslr {
+    state: u64,
+}
+
+impl Kaslr {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Offset from the seed, if layout randomisation is enabled and there is a seed.
+    fn from_arch() -> Option<Self> {
+        if cfg!(feature = "kaslr") {
+            Arch::kaslr_seed().map(Self::new)
+        } else {
+            None
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A whole number of granules less than an alignment.
+    fn offset_within(&mut self, align: usize) -> usize {
+        let granules = (align / KASLR_GRANULE) as u64;
+        if granules <= 1 {
+            return 0;
+        }
+        (self.next() % granules) as usize * KASLR_GRANULE
+    }
+}
+
+/// A placed range of the kernel address space.
+#[derive(Copy, Clone, Debug)]
+struct Extent {
+    content: RangeContent,
+    virt_range: VirtAddrRange,
+    phys_addr_range: Option<PhysAddrRange>,
+    attributes: Attributes,
+    guard: usize, // unmapped bytes kept free below the range
+    boot: bool,   // placed by init, so cannot be released
+}
+
+impl Extent {
+    /// The range including its guard gap.
+    fn guarded(&self) -> VirtAddrRange {
+        VirtAddrRange::between(
+            self.virt_range.base().decrement(self.guard),
+            self.virt_range.top(),
+        )
+    }
+}
+
+/// Most extents the layout can hold.
+const MAX_EXTENTS: usize = 32;
+
+/// The live set of extents, sorted by address.
+///
+/// Fixed capacity, so that the layout can be changed without allocating.
+struct Registry {
+    extents: [Option<Extent>; MAX_EXTENTS],
+    len: usize,
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Self {
+            extents: [None; MAX_EXTENTS],
+            len: 0,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Extent> {
+        self.extents[..self.len].iter().flatten()
+    }
+
+    fn get(&self, i: usize) -> Option<Extent> {
+        self.extents[..self.len].get(i).copied().flatten()
+    }
+
+    fn clear(&mut self) {
+        self.extents = [None; MAX_EXTENTS];
+        self.len = 0;
+    }
+
+    /// Add an extent, keeping the layout sorted.
+    ///
+    /// The extent must be within the user range, or above the kernel base.
+    fn insert(&mut self, extent: Extent) -> Result<()> {
+        let virt_range = extent.virt_range;
+        if !virt_range.is_aligned(PAGESIZE_BYTES)
+            || virt_range.base().get().checked_add(virt_range.length()).is_none()
+            || virt_range.base().get() < extent.guard
+        {
+            return Err(Error::UnexpectedValue);
+        }
+        if !(virt_range.base() >= Arch::kernel_base() || Arch::user_range().covers(&virt_range)) {
+            return Err(Error::UnexpectedValue);
+        }
+        let overlap = |a: VirtAddrRange, b: VirtAddrRange| {
+            a.intersection(&b)
+                .map_or(false, |overlap| overlap.length() > 0)
+        };
+        let overlaps = |other: &Extent| {
+            overlap(other.guarded(), virt_range) || overlap(other.virt_range, extent.guarded())
+        };
+        if self.iter().any(overlaps) {
+            return Err(Error::UnexpectedValue);
+        }
+        if self.len == MAX_EXTENTS {
+            return Err(Error::OutOfMemory);
+        }
+        let i = self
+            .iter()
+            .position(|other| other.virt_range.base() > virt_range.base())
+            .unwrap_or(self.len);
+        self.extents[i..=self.len].rotate_right(1);
+        self.extents[i] = Some(extent);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove an extent which was added at runtime.
+    fn remove(&mut self, virt_range: VirtAddrRange) -> Result<Extent> {
+        let i = self
+            .iter()
+            .position(|extent| !extent.boot && extent.virt_range == virt_range)
+            .ok_or(Error::UnexpectedValue)?;
+        let result = self.extents[i].take();
+        self.extents[i..self.len].rotate_left(1);
+        self.len -= 1;
+        result.ok_or(Error::UnexpectedValue)
+    }
+
+    /// Lowest unused range of a given length and alignment, above a base address.
+    ///
+    /// The range is clear of the guard gaps below extents.
+    fn find_space(&self, base: VirtAddr, length: usize, align: usize) -> Result<VirtAddrRange> {
+        let mut candidate = base.align_up(align);
+        for extent in self.iter() {
+            let top = candidate
+                .get()
+                .checked_add(length)
+                .ok_or(Error::OutOfPages)?;
+            if top <= extent.guarded().base().get() {
+                break;
+            }
+            if extent.virt_range.top().get() > candidate.get() {
+                candidate = extent.virt_range.top().align_up(align);
+            }
+        }
+        candidate
+            .get()
+            .checked_add(length)
+            .ok_or(Error::OutOfPages)?;
+        Ok(VirtAddrRange::new(candidate, length))
+    }
+}
+
+static LAYOUT: Locked<Registry> = Locked::new(Registry::new());
+
 static mut MEM_FIXED_OFFSET: FixedOffset = FixedOffset::identity();
 
-/// Initialise
+/// Place the ranges needed to boot.
 pub fn init() -> Result<()> {
     major!("init");
     info!("Kernel base: {:?}", Arch::kernel_base());
@@ -183,56 +393,147 @@ pub fn init() -> Result<()> {
         Arch::ram_range(),
         Arch::ram_range().length() / MB
     );
+    info!("RAM banks: {:?}", Arch::ram_banks());
+    info!("Reserved: {:?}", Arch::reserved_ranges());
     info!("Kernel offset: {:?}", Arch::kernel_offset());
 
+    let mut kaslr = Kaslr::from_arch();
+    if kaslr.is_some() {
+        info!("KASLR enabled");
+    }
+
+    let mut layout = LAYOUT.lock();
+    layout.clear();
     let mut virt_addr = Arch::kernel_base();
-    unsafe {
-        for extent in LAYOUT.iter_mut() {
-            let base = if extent.virt_range_align > 0 {
-                virt_addr.align_up(extent.virt_range_align)
-            } else {
-                virt_addr
-            };
-            let virt_range_gap = (extent.virt_range_gap)();
-            let base = match virt_range_gap {
-                Some(gap) => base.increment(gap),
-                None => base,
-            };
-
-            let phys_addr_range = (extent.phys_addr_range)();
-
-            let length = if let Some(phys_addr_range) = phys_addr_range {
-                core::cmp::max(phys_addr_range.length(), extent.virt_range_min_extent)
-            } else {
-                extent.virt_range_min_extent
-            };
-            extent.virt_range = Some(VirtAddrRange::new(base, length));
-
-            if extent.content == RangeContent::RAM {
-                let phys_addr = phys_addr_range.unwrap().base();
-                let translation = FixedOffset::new(phys_addr, base);
+    for kernel_extent in BOOT_LAYOUT.iter() {
+        let base = if kernel_extent.virt_range_align > 0 {
+            virt_addr.align_up(kernel_extent.virt_range_align)
+        } else {
+            virt_addr
+        };
+        let virt_range_gap = (kernel_extent.virt_range_gap)();
+        let base = match virt_range_gap {
+            Some(gap) => base.increment(gap),
+            None => base,
+        };
+        let base = match (kernel_extent.randomise, kaslr.as_mut()) {
+            (true, Some(kaslr)) => {
+                base.increment(kaslr.offset_within(kernel_extent.virt_range_align))
+            }
+            _ => base,
+        };
+
+        let phys_addr_range = (kernel_extent.phys_addr_range)();
+
+        let length = if let Some(phys_addr_range) = phys_addr_range {
+            core::cmp::max(phys_addr_range.length(), kernel_extent.virt_range_min_extent)
+        } else {
+            kernel_extent.virt_range_min_extent
+        };
+        let extent = Extent {
+            content: kernel_extent.content,
+            virt_range: VirtAddrRange::new(base, length),
+            phys_addr_range,
+            attributes: kernel_extent.attributes,
+            guard: virt_range_gap.unwrap_or(0),
+            boot: true,
+        };
+
+        if extent.content == RangeContent::RAM {
+            let phys_addr = phys_addr_range.ok_or(Error::UnexpectedValue)?.base();
+            let translation = FixedOffset::new(phys_addr, base);
+            unsafe {
                 MEM_FIXED_OFFSET = translation;
             }
+        }
 
-            virt_addr = extent.virt_range.unwrap().step().base();
+        virt_addr = extent.virt_range.step().base();
 
-            info!("{:?}", extent);
-        }
+        info!("{:?}", extent);
+        layout.insert(extent)?;
     }
+    drop(layout);
 
     #[cfg(not(test))]
-    assert_eq!(
-        Arch::kernel_offset().offset(),
-        FixedOffset::new(
+    {
+        let expected = FixedOffset::new(
             PhysAddr::at(0x04008_0000),
-            get_range(RangeContent::KernelText)?.base()
-        )
-        .offset()
-    );
+            get_range(RangeContent::KernelText)?.base(),
+        );
+        if Arch::kernel_offset().offset() != expected.offset() {
+            return Err(Error::UnexpectedValue);
+        }
+    }
 
     Ok(())
 }
 
+/// Add content at a chosen range of the user range, or of kernel address space.
+///
+/// The range is not mapped.
+pub fn register_range(
+    content: RangeContent,
+    virt_range: VirtAddrRange,
+    phys_addr_range: Option<PhysAddrRange>,
+    attributes: Attributes,
+) -> Result<()> {
+    major!("register_range: {:?} {:?}", content, virt_range);
+    LAYOUT.lock().insert(Extent {
+        content,
+        virt_range,
+        phys_addr_range,
+        attributes,
+        guard: 0,
+        boot: false,
+    })
+}
+
+/// Add content at the lowest unused range with a given length and alignment, and
+/// return the range.
+///
+/// The range is taken from the user range if the attributes allow user access,
+/// and otherwise from kernel address space. The range is not mapped.
+pub fn reserve_range(
+    content: RangeContent,
+    length: usize,
+    align: usize,
+    attributes: Attributes,
+) -> Result<VirtAddrRange> {
+    use AttributeField::*;
+
+    major!("reserve_range: {:?} {:#x}", content, length);
+    if length == 0 || !align.is_power_of_two() || align < PAGESIZE_BYTES {
+        return Err(Error::UnexpectedValue);
+    }
+    let user =
+        attributes.is_set(UserRead) || attributes.is_set(UserWrite) || attributes.is_set(UserExec);
+    let base = if user {
+        Arch::user_range().base()
+    } else {
+        Arch::kernel_base()
+    };
+    let mut layout = LAYOUT.lock();
+    let virt_range = layout.find_space(base, length, align)?;
+    if user && !Arch::user_range().covers(&virt_range) {
+        return Err(Error::OutOfPages);
+    }
+    layout.insert(Extent {
+        content,
+        virt_range,
+        phys_addr_range: None,
+        attributes,
+        guard: 0,
+        boot: false,
+    })?;
+    Ok(virt_range)
+}
+
+/// Remove content added at runtime from the layout.
+pub fn release_range(virt_range: VirtAddrRange) -> Result<()> {
+    major!("release_range: {:?}", virt_range);
+    LAYOUT.lock().remove(virt_range).map(|_| ())
+}
+
 /// Range requiring to be mapped.
 #[derive(Debug)]
 pub struct KernelRange {
@@ -243,11 +544,11 @@ pub struct KernelRange {
 }
 
 impl KernelRange {
-    fn from(extent: &KernelExtent) -> Self {
+    fn from(extent: &Extent) -> Self {
         Self {
             content: extent.content,
-            virt_addr_range: extent.virt_range.expect("Layout not initialised"),
-            phys_addr_range: (extent.phys_addr_range)(),
+            virt_addr_range: extent.virt_range,
+            phys_addr_range: extent.phys_addr_range,
             attributes: extent.attributes,
         }
     }
@@ -256,7 +557,6 @@ impl KernelRange {
 /// Iterable over the memory layout.
 pub struct LayoutIterator {
     i: usize,
-    next_base: VirtAddr,
 }
 
 impl Debug for LayoutIterator {
@@ -267,10 +567,7 @@ impl Debug for LayoutIterator {
 
 impl LayoutIterator {
     fn new() -> Self {
-        Self {
-            i: 0,
-            next_base: Arch::kernel_base(),
-        }
+        Self { i: 0 }
     }
 }
 
@@ -278,16 +575,10 @@ impl Iterator for LayoutIterator {
     type Item = KernelRange;
 
     fn next(&mut self) -> Option<Self::Item> {
-        unsafe {
-            if self.i >= LAYOUT.len() {
-                return None;
-            }
-            info!("{:?}", &LAYOUT[self.i]);
-            let result = KernelRange::from(&LAYOUT[self.i]);
-            self.next_base = result.virt_addr_range.step().base();
-            self.i += 1;
-            Some(result)
-        }
+        let extent = LAYOUT.lock().get(self.i)?;
+        info!("{:?}", extent);
+        self.i += 1;
+        Some(KernelRange::from(&extent))
     }
 }
 
@@ -328,28 +619,35 @@ pub fn mem_fixed_offset() -> &'static FixedOffset {
     unsafe { &MEM_FIXED_OFFSET }
 }
 
-/// Search the kernel layout for the virtual address range of the first kernel heap area.
+/// Search the kernel layout for the virtual address range of the first extent with some content.
 pub fn get_range(content: RangeContent) -> Result<VirtAddrRange> {
     info!("get_range: {:?}", content);
-    unsafe {
-        LAYOUT
-            .iter()
-            .find(|extent| (*extent).content == content)
-            .and_then(|e| e.virt_range)
-            .ok_or(Error::UnInitialised)
-    }
+    LAYOUT
+        .lock()
+        .iter()
+        .find(|extent| extent.content == content)
+        .map(|extent| extent.virt_range)
+        .ok_or(Error::UnInitialised)
+}
+
+/// Find the content of the kernel layout range containing a virtual address.
+pub fn content_at(virt_addr: VirtAddr) -> Option<RangeContent> {
+    LAYOUT
+        .lock()
+        .iter()
+        .find(|extent| extent.virt_range.contains(virt_addr))
+        .map(|extent| extent.content)
 }
 
-/// Search the kernel layout for the virtual address range of the first kernel heap area.
+/// Search the kernel layout for the physical address range of the first extent with some content.
 pub fn get_phys_range(content: RangeContent) -> Result<PhysAddrRange> {
     info!("get_phys_range: {:?}", content);
-    unsafe {
-        LAYOUT
-            .iter()
-            .find(|extent| (*extent).content == content)
-            .and_then(|e| (e.phys_addr_range)())
-            .ok_or(Error::Undefined)
-    }
+    LAYOUT
+        .lock()
+        .iter()
+        .find(|extent| extent.content == content)
+        .and_then(|extent| extent.phys_addr_range)
+        .ok_or(Error::Undefined)
 }
 
 #[cfg(test)]
@@ -366,6 +664,102 @@ mod tests {
                 get_phys_range(item.content)
             );
             assert_eq!(item.virt_addr_range, get_range(item.content).unwrap());
+            assert_eq!(Some(item.content), content_at(item.virt_addr_range.base()));
         }
+        assert_eq!(None, content_at(VirtAddr::null()));
+    }
+
+    #[test]
+    fn kaslr_offsets() {
+        let offsets = |seed| {
+            let mut kaslr = Kaslr::new(seed);
+            [
+                kaslr.offset_within(GB),
+                kaslr.offset_within(GB),
+                kaslr.offset_within(GB),
+            ]
+        };
+        assert_eq!(offsets(42), offsets(42));
+        assert_ne!(offsets(42), offsets(43));
+        for offset in offsets(42).iter() {
+            assert!(*offset < GB);
+            assert_eq!(0, offset % KASLR_GRANULE);
+        }
+        assert_eq!(0, Kaslr::new(42).offset_within(2 * MB));
+    }
+
+    #[test]
+    fn registry() {
+        let base = Arch::kernel_base();
+        let extent = |offset: usize, length: usize| Extent {
+            content: RangeContent::Named("test"),
+            virt_range: VirtAddrRange::new(base.increment(offset), length),
+            phys_addr_range: None,
+            attributes: Attributes::KERNEL_DATA,
+            guard: 0,
+            boot: false,
+        };
+        let mut registry = Registry::new();
+        assert_ok!(registry.insert(extent(0x4000, 0x2000)));
+        assert_ok!(registry.insert(Extent {
+            boot: true,
+            ..extent(0x0, 0x1000)
+        }));
+        assert_err!(registry.insert(extent(0x5000, 0x1000)));
+        assert_err!(registry.insert(extent(0x8800, 0x1000)));
+        assert_err!(registry.insert(Extent {
+            virt_range: VirtAddrRange::new(VirtAddr::at(0x1000), 0x1000),
+            ..extent(0x0, 0x1000)
+        }));
+        // user areas
+        let user = Arch::user_range().base();
+        assert_ok!(registry.insert(Extent {
+            virt_range: VirtAddrRange::new(user, 0x1000),
+            ..extent(0x0, 0x1000)
+        }));
+        assert_ok_eq!(
+            registry.find_space(user, 0x1000, 0x1000),
+            VirtAddrRange::new(user.increment(0x1000), 0x1000)
+        );
+        assert_ok!(registry.remove(VirtAddrRange::new(user, 0x1000)));
+
+        // lowest gap which fits
+        assert_ok_eq!(
+            registry.find_space(base, 0x2000, 0x1000),
+            VirtAddrRange::new(base.increment(0x1000), 0x2000)
+        );
+        assert_ok_eq!(
+            registry.find_space(base, 0x4000, 0x1000),
+            VirtAddrRange::new(base.increment(0x6000), 0x4000)
+        );
+        assert_ok_eq!(
+            registry.find_space(base, 0x1000, 0x4000),
+            VirtAddrRange::new(base.increment(0x8000), 0x1000)
+        );
+
+        assert_err!(registry.remove(extent(0x0, 0x1000).virt_range));
+        assert_ok!(registry.remove(extent(0x4000, 0x2000).virt_range));
+        assert_err!(registry.remove(extent(0x4000, 0x2000).virt_range));
+        assert_eq!(1, registry.iter().count());
+
+        // guard gaps are left unused
+        let guarded = Extent {
+            guard: 0x2000,
+            ..extent(0x4000, 0x1000)
+        };
+        assert_err!(registry.insert(Extent {
+            guard: 0x2000,
+            ..extent(0x2000, 0x1000)
+        }));
+        assert_ok!(registry.insert(guarded));
+        assert_err!(registry.insert(extent(0x3000, 0x1000)));
+        assert_ok_eq!(
+            registry.find_space(base, 0x1000, 0x1000),
+            VirtAddrRange::new(base.increment(0x1000), 0x1000)
+        );
+        assert_ok_eq!(
+            registry.find_space(base, 0x2000, 0x1000),
+            VirtAddrRange::new(base.increment(0x5000), 0x2000)
+        );
     }
 }
@@ -3,54 +3,87 @@
 //! Managing virtual address space, address translation and page faults.
 
 mod addr;
+mod alloc;
+mod asid;
 mod attributes;
-mod bump;
+mod block;
+mod checkpoint;
+mod dma;
 mod frames;
 mod handlers;
 mod layout;
 mod owned;
 mod page;
 mod phys_addr;
+mod range;
+mod space;
+mod swap;
 mod translation;
 mod virt_addr;
 
-#[cfg(not(test))]
-mod alloc;
-
+pub use self::alloc::{heap_stats, slab_stats, HeapStats, SlabStats};
 pub use addr::*;
 pub use attributes::*;
+pub use block::BlockMapping;
 pub use handlers::*;
 pub use owned::*;
 pub use page::*;
 pub use phys_addr::*;
+pub use space::{switch_to_kernel, AddressSpace};
 pub use translation::*;
 pub use virt_addr::*;
 
 pub use frames::allocator as frame_allocator;
 pub use frames::Allocator as FrameAllocator;
 pub use frames::Purpose as FramePurpose;
+pub use frames::{evict_cold_pages, zero_free_frames, FrameStats, FrameUse, ZeroingStats};
 
-pub use layout::{get_range, mem_fixed_offset, mem_translation, RangeContent};
+pub use layout::{
+    get_range, mem_fixed_offset, mem_translation, register_range, release_range, reserve_range,
+    RangeContent,
+};
 
 use crate::archs::{arch, arch::Arch, DeviceTrait, PageDirectory, PagerTrait};
 use crate::debug::Level;
-use crate::pager::bump::PageBumpAllocator;
 use crate::pager::frames::Purpose;
+use crate::pager::range::PageRangeAllocator;
 use crate::util::locked::Locked;
-use crate::Result;
+use crate::{Error, Result};
 
-use ::alloc::sync::Arc;
+use ::alloc::{sync::Arc, vec::Vec};
 
 /// Major interface trait of paging module.
 pub trait Paging {
     /// Map a memory-mapped IO range to an unused kernel address range in the
     /// device range, with access attributes for device memory.
     fn map_device(phys_addr_range: PhysAddrRange) -> Result<Arc<OwnedMapping>>;
-    /// Map contiguous physical pages to an unused device memory range in the
-    /// with access attributes for device memory.
+    /// Map contiguous physical pages from the DMA pool to an unused device memory
+    /// range, with access attributes for device memory.
     fn map_dma(contiguous_pages: u8) -> Result<Arc<OwnedMapping>>;
     /// Return the current physical address for a virtual address
     fn maps_to(virt_addr: VirtAddr) -> Result<PhysAddr>;
+    /// Return the physical segments backing a resident virtual range, in order,
+    /// merging physically adjacent pages, for a device to transfer to or from.
+    ///
+    /// The pages are marked dirty, and their frames are pinned until the segments
+    /// are released.
+    fn dma_segments(virt_addr_range: VirtAddrRange) -> Result<Vec<PhysAddrRange>>;
+    /// Unpin the frames of segments from dma_segments, once the transfer has finished.
+    fn release_dma_segments(segments: &[PhysAddrRange]) -> Result<()>;
+    /// Return counters for the use of physical memory.
+    fn frame_stats() -> Result<FrameStats>;
+    /// Map a range of the block-mapped area to consecutive sectors of a block device,
+    /// reading pages on first access and writing back those that change.
+    fn map_block(
+        virt_addr_range: VirtAddrRange,
+        device_name: &str,
+        start_sector: u64,
+        attributes: Attributes,
+    ) -> Result<BlockMapping>;
+    /// Write the persistent area to the swap device, to be mapped again after a restart.
+    fn checkpoint() -> Result<()>;
+    /// Check the mappings of the kernel ranges against the attribute policy.
+    fn audit() -> Result<()>;
 }
 
 /// Implements the Paging interface trait.
@@ -71,31 +104,50 @@ impl Paging for Pager {
             frames::allocator(),
             mem_translation(),
         )?;
-        Ok(Arc::new(OwnedMapping::new(virt_addr_range)))
+        Ok(Arc::new(OwnedMapping::from_pool(
+            virt_addr_range,
+            &DEVICE_MEM_ALLOCATOR,
+        )))
     }
 
     fn map_dma(contiguous_pages: u8) -> Result<Arc<OwnedMapping>> {
         major!("map_dma");
-        if contiguous_pages > 1 {
-            unimplemented!()
-        }
         let virt_addr_range = DEVICE_MEM_ALLOCATOR
             .lock()
             .alloc(contiguous_pages as usize)?;
-        let phys_addr_page = frames::allocator()
-            .lock()
-            .alloc_zeroed(Purpose::DirectMemoryAccess)?;
-        info!("phys_addr_page: {:?}", phys_addr_page);
-        let translation = FixedOffset::new(phys_addr_page, virt_addr_range.base());
+        let phys_addr_range = match frames::alloc_contiguous(contiguous_pages as u32) {
+            Ok(phys_addr_range) => phys_addr_range,
+            Err(e) => {
+                DEVICE_MEM_ALLOCATOR.lock().free(virt_addr_range)?;
+                return Err(e);
+            }
+        };
+        info!("phys_addr_range: {:?}", phys_addr_range);
+        let translation = FixedOffset::new(phys_addr_range.base(), virt_addr_range.base());
         let mut page_directory = KERNEL_PAGE_DIRECTORY.lock();
-        let virt_addr_range = page_directory.map_translation(
+        let virt_addr_range = match page_directory.map_translation(
             virt_addr_range,
             translation,
-            Attributes::DEVICE,
+            Attributes::DMA,
             frames::allocator(),
             mem_translation(),
-        )?;
-        Ok(Arc::new(OwnedMapping::new(virt_addr_range)))
+        ) {
+            Ok(virt_addr_range) => virt_addr_range,
+            Err(e) => {
+                drop(page_directory);
+                let mut allocator = frames::allocator().lock();
+                for phys_addr in phys_addr_range.chunks(PAGESIZE_BYTES) {
+                    allocator.discard(phys_addr)?;
+                }
+                drop(allocator);
+                DEVICE_MEM_ALLOCATOR.lock().free(virt_addr_range)?;
+                return Err(e);
+            }
+        };
+        Ok(Arc::new(OwnedMapping::from_pool(
+            virt_addr_range,
+            &DEVICE_MEM_ALLOCATOR,
+        )))
     }
 
     fn maps_to(virt_addr: VirtAddr) -> Result<PhysAddr> {
@@ -103,22 +155,125 @@ impl Paging for Pager {
             .lock()
             .maps_to(virt_addr, mem_fixed_offset())
     }
+
+    fn dma_segments(virt_addr_range: VirtAddrRange) -> Result<Vec<PhysAddrRange>> {
+        info!("dma_segments: {:?}", virt_addr_range);
+        let mut page_directory = KERNEL_PAGE_DIRECTORY.lock();
+        let segments = dma::segments(
+            virt_addr_range,
+            page_directory.mappings(virt_addr_range, mem_fixed_offset())?,
+        )?;
+        let mut page = VirtAddrRange::page_containing(virt_addr_range.base());
+        while page.base() < virt_addr_range.top() {
+            page_directory.set_dirty(page.base(), mem_fixed_offset())?;
+            page = page.step();
+        }
+        let mut allocator = frames::allocator().lock();
+        for phys_addr in dma::frames(&segments) {
+            allocator.increment_map_count(phys_addr)?;
+        }
+        Ok(segments)
+    }
+
+    fn release_dma_segments(segments: &[PhysAddrRange]) -> Result<()> {
+        info!("release_dma_segments: {:?}", segments);
+        let mut allocator = frames::allocator().lock();
+        for phys_addr in dma::frames(segments) {
+            allocator.free(phys_addr)?;
+        }
+        Ok(())
+    }
+
+    fn frame_stats() -> Result<FrameStats> {
+        frames::stats()
+    }
+
+    fn map_block(
+        virt_addr_range: VirtAddrRange,
+        device_name: &str,
+        start_sector: u64,
+        attributes: Attributes,
+    ) -> Result<BlockMapping> {
+        block::map(virt_addr_range, device_name, start_sector, attributes)
+    }
+
+    fn checkpoint() -> Result<()> {
+        checkpoint::checkpoint()
+    }
+
+    fn audit() -> Result<()> {
+        major!("audit");
+        // not taking the layout lock under the page directory lock
+        let kernel_ranges = layout::layout()?.into_iter().collect::<Vec<_>>();
+        let is_never_writable = |content: layout::RangeContent| {
+            use layout::RangeContent::*;
+            matches!(content, KernelText | KernelStatic)
+        };
+        let page_directory = KERNEL_PAGE_DIRECTORY.lock();
+        // the frames of the kernel image, which no alias may make writable either
+        let mut never_writable_frames = Vec::new();
+        for kernel_range in kernel_ranges.iter() {
+            if is_never_writable(kernel_range.content) {
+                let mappings =
+                    page_directory.mappings(kernel_range.virt_addr_range, mem_fixed_offset())?;
+                never_writable_frames.extend(mappings.filter_map(|(_, frames, _, _)| frames));
+            }
+        }
+        for kernel_range in kernel_ranges {
+            attributes::audit_mappings(
+                page_directory.mappings(kernel_range.virt_addr_range, mem_fixed_offset())?,
+                is_never_writable(kernel_range.content),
+                &never_writable_frames,
+            )?;
+        }
+        Ok(())
+    }
 }
 
 /// Number of bytes in a cluster-wide atomic page.
 pub const PAGESIZE_BYTES: usize = 4096;
 
 /// Available virtual memory within device range.
-static DEVICE_MEM_ALLOCATOR: Locked<PageBumpAllocator> = Locked::new(PageBumpAllocator::new());
+static DEVICE_MEM_ALLOCATOR: Locked<PageRangeAllocator> = Locked::new(PageRangeAllocator::new());
 
 /// Available virtual memory for handler stacks (1 per CPU).
-static KERNEL_STACK_ALLOCATOR: Locked<PageBumpAllocator> = Locked::new(PageBumpAllocator::new());
+static KERNEL_STACK_ALLOCATOR: Locked<PageRangeAllocator> =
+    Locked::new(PageRangeAllocator::new());
+
+/// Most cores which can be given a kernel stack.
+pub(crate) const MAX_CORES: usize = 8;
+
+/// Kernel stack of each core, including the guard page at its base.
+static KERNEL_STACKS: Locked<[Option<VirtAddrRange>; MAX_CORES]> =
+    Locked::new([None; MAX_CORES]);
+
+/// Find the core whose kernel stack guard page contains the address.
+///
+/// Returns the core and the usable range of its stack.
+fn stack_guarded_by(virt_addr: VirtAddr) -> Option<(usize, VirtAddrRange)> {
+    let stacks = KERNEL_STACKS.lock();
+    stacks.iter().enumerate().find_map(|(core, stack)| {
+        let stack = (*stack)?;
+        let guard = stack.resize(PAGESIZE_BYTES);
+        if guard.contains(virt_addr) {
+            Some((core, VirtAddrRange::between(guard.top(), stack.top())))
+        } else {
+            None
+        }
+    })
+}
 
 /// Pointers to kernel page directory.
 static KERNEL_PAGE_DIRECTORY: Locked<arch::PageDirectory> = Locked::new(arch::PageDirectory::new());
 
+/// Where the kernel continues once init is running on the kernel stack.
+static NEXT: Locked<Option<fn() -> !>> = Locked::new(None);
+
 /// Initialise the virtual memory manager and jump to the kernel in high memory.
 ///
+/// Block devices are found, the swap device is attached and the persistent area
+/// is restored from the last checkpoint before the kernel continues.
+///
 /// Can only reference debug, arch and self. Other modules not initialised.
 pub fn init(next: fn() -> !) -> ! {
     fn do_init() -> Result<VirtAddr> {
@@ -151,11 +306,33 @@ pub fn init(next: fn() -> !) -> ! {
         #[cfg(not(test))]
         alloc::init()?;
 
+        attributes::enforce_policy();
+
         allocate_core_stack()
     }
 
+    *NEXT.lock() = Some(next);
     let stack_pointer = do_init().expect("pager::init");
-    Arch::move_stack(stack_pointer, next)
+    Arch::move_stack(stack_pointer, start)
+}
+
+/// Attach the swap device and restore the persistent area, on the kernel stack.
+fn start() -> ! {
+    major!("start");
+
+    crate::device::init().expect("device::init");
+    match swap::init() {
+        Ok(true) => {
+            if let Err(e) = checkpoint::restore() {
+                error!("not restored: {:?}", e);
+            }
+        }
+        Ok(false) => {}
+        Err(e) => error!("running without swap: {:?}", e),
+    }
+
+    let next = NEXT.lock().take().expect("pager::init");
+    next()
 }
 
 fn allocate_core_stack() -> Result<VirtAddr> {
@@ -171,6 +348,10 @@ fn allocate_core_stack() -> Result<VirtAddr> {
         // include a guard page
         lock.alloc(KERNEL_STACK_LEN_PAGES + 1)?
     };
+    *KERNEL_STACKS
+        .lock()
+        .get_mut(arch::core_id() as usize)
+        .ok_or(Error::OutOfMemory)? = Some(kernel_stack);
 
     let attributes = Attributes::new()
         .set(KernelRead)
@@ -181,7 +362,7 @@ fn allocate_core_stack() -> Result<VirtAddr> {
     let mut kernel_stack_page = kernel_stack.resize(PAGESIZE_BYTES).step();
 
     for _ in 0..KERNEL_STACK_LEN_PAGES {
-        let phys_addr = frames::allocator().lock().alloc_zeroed(Purpose::Kernel)?;
+        let phys_addr = FrameAllocator::alloc_zeroed_local(frames::allocator(), Purpose::Kernel)?;
         let translation = FixedOffset::new(phys_addr, kernel_stack_page.base());
         KERNEL_PAGE_DIRECTORY.lock().map_translation(
             kernel_stack_page,
@@ -211,6 +392,7 @@ fn map_ranges(
     allocator: &Locked<impl FrameAllocator>,
 ) -> Result<()> {
     let mem_access_translation = &Identity::new();
+    let mut linear_map = None; // translation of the RAM range, once it is mapped
 
     for kernel_range in layout::layout()? {
         use layout::RangeContent::*;
@@ -222,15 +404,26 @@ fn map_ranges(
             let translation =
                 FixedOffset::new(phys_addr_range.base(), kernel_range.virt_addr_range.base());
 
-            page_directory.map_translation(
-                kernel_range
-                    .virt_addr_range
-                    .resize(phys_addr_range.length()),
-                translation,
-                attributes,
-                allocator,
-                mem_access_translation,
-            )?;
+            // leave holes between banks of RAM unmapped
+            let mapped = match kernel_range.content {
+                RAM => {
+                    linear_map = Some(translation);
+                    Arch::ram_banks()
+                }
+                _ => PhysAddrRanges::from(phys_addr_range),
+            };
+            for phys_addr_range in mapped.as_slice() {
+                page_directory.map_translation(
+                    VirtAddrRange::new(
+                        translation.translate_phys(phys_addr_range.base())?,
+                        phys_addr_range.length(),
+                    ),
+                    translation,
+                    attributes,
+                    allocator,
+                    mem_access_translation,
+                )?;
+            }
         }
 
         match kernel_range.content {
@@ -253,6 +446,25 @@ fn map_ranges(
                     .lock()
                     .reset(kernel_range.virt_addr_range)?;
             }
+            KernelText | KernelStatic => {
+                // the image must not be writable through the linear map of RAM either
+                if let (Some(linear_map), Some(phys_addr_range)) =
+                    (linear_map, kernel_range.phys_addr_range)
+                {
+                    let alias = VirtAddrRange::new(
+                        linear_map.translate_phys(phys_addr_range.base())?,
+                        phys_addr_range.length(),
+                    );
+                    page_directory.protect(
+                        alias,
+                        Attributes::RAM
+                            .clear(AttributeField::KernelWrite)
+                            .set(AttributeField::Accessed),
+                        allocator,
+                        &FixedOffset::identity(),
+                    )?;
+                }
+            }
             _ => {}
         };
     }
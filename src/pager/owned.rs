@@ -2,20 +2,42 @@
 
 //! A handle for owned kernel-mapped pages which should be freed on Drop.
 
-use super::{frames, mem_fixed_offset, AddrRange, VirtAddr, VirtAddrRange, KERNEL_PAGE_DIRECTORY};
+use super::{
+    frames, mem_fixed_offset, range::PageRangeAllocator, AddrRange, VirtAddr, VirtAddrRange,
+    KERNEL_PAGE_DIRECTORY,
+};
 
 use crate::archs::PageDirectory;
+use crate::util::locked::Locked;
 
 /// An owned mapping which will be unmapped on Drop.
-#[derive(Debug, Clone)]
+///
+/// Not Clone, since each copy would unmap the range again; share it as an
+/// `Arc<OwnedMapping>` instead.
+#[derive(Debug)]
 pub struct OwnedMapping {
     virt_addr_range: VirtAddrRange,
+    pool: Option<&'static Locked<PageRangeAllocator>>, // to return the range to on Drop
 }
 
 impl OwnedMapping {
     /// Make a virtual address range subject to unmapping on Drop.
     pub fn new(virt_addr_range: VirtAddrRange) -> Self {
-        Self { virt_addr_range }
+        Self {
+            virt_addr_range,
+            pool: None,
+        }
+    }
+
+    /// Make a range allocated from a pool subject to unmapping and release on Drop.
+    pub(in crate::pager) fn from_pool(
+        virt_addr_range: VirtAddrRange,
+        pool: &'static Locked<PageRangeAllocator>,
+    ) -> Self {
+        Self {
+            virt_addr_range,
+            pool: Some(pool),
+        }
     }
 
     /// Get the base address of the range.
@@ -44,5 +66,11 @@ impl Drop for OwnedMapping {
                 mem_fixed_offset(),
             )
             .expect("PageDirectory::unmap");
+        drop(page_directory);
+        if let Some(pool) = self.pool {
+            pool.lock()
+                .free(self.virt_addr_range)
+                .expect("PageRangeAllocator::free");
+        }
     }
 }
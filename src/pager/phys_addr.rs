@@ -136,6 +136,80 @@ impl PhysAddrRange {
     }
 }
 
+/// Most ranges which can be held in a PhysAddrRanges.
+pub const MAX_PHYS_ADDR_RANGES: usize = 8;
+
+/// A short list of physical address ranges, sorted by base.
+#[derive(Copy, Clone, PartialEq)]
+pub struct PhysAddrRanges {
+    ranges: [PhysAddrRange; MAX_PHYS_ADDR_RANGES],
+    len: usize,
+}
+
+impl Debug for PhysAddrRanges {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{:?}", self.as_slice())
+    }
+}
+
+impl PhysAddrRanges {
+    /// Const for compile-time constant
+    pub const fn new() -> Self {
+        Self {
+            ranges: [PhysAddrRange::fixed(PhysAddr::null(), 0); MAX_PHYS_ADDR_RANGES],
+            len: 0,
+        }
+    }
+
+    /// Add a range, keeping the list sorted.
+    pub fn push(&mut self, range: PhysAddrRange) -> crate::Result<()> {
+        if self.len == MAX_PHYS_ADDR_RANGES {
+            return Err(crate::Error::OutOfMemory);
+        }
+        let i = self.as_slice().iter().take_while(|r| r.base() <= range.base()).count();
+        for j in (i..self.len).rev() {
+            self.ranges[j + 1] = self.ranges[j];
+        }
+        self.ranges[i] = range;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// The ranges, sorted by base.
+    pub fn as_slice(&self) -> &[PhysAddrRange] {
+        &self.ranges[..self.len]
+    }
+
+    /// True iff any range contains the address.
+    pub fn contains(&self, phys_addr: PhysAddr) -> bool {
+        self.as_slice().iter().any(|r| r.contains(phys_addr))
+    }
+
+    /// The smallest range covering every range in the list.
+    pub fn span(&self) -> Option<PhysAddrRange> {
+        let first = self.as_slice().first()?;
+        let top = self.as_slice().iter().map(|r| r.top()).max()?;
+        Some(PhysAddrRange::between(first.base(), top))
+    }
+
+    /// Gaps within the span which are not covered by any range.
+    pub fn holes(&self) -> impl Iterator<Item = PhysAddrRange> + '_ {
+        self.as_slice()
+            .windows(2)
+            .filter(|pair| pair[0].top() < pair[1].base())
+            .map(|pair| PhysAddrRange::between(pair[0].top(), pair[1].base()))
+    }
+}
+
+impl From<PhysAddrRange> for PhysAddrRanges {
+    fn from(range: PhysAddrRange) -> Self {
+        let mut result = Self::new();
+        result.ranges[0] = range;
+        result.len = 1;
+        result
+    }
+}
+
 impl Iterator for PhysAddrRangeIterator {
     type Item = PhysAddr;
 
@@ -196,6 +270,34 @@ mod tests {
         assert!(!range.is_aligned(0x100));
     }
 
+    #[test]
+    fn ranges() {
+        let mut ranges = PhysAddrRanges::new();
+        assert_none!(ranges.span());
+        let high = PhysAddrRange::between(PhysAddr(0x8000), PhysAddr(0xa000));
+        let low = PhysAddrRange::between(PhysAddr(0x1000), PhysAddr(0x3000));
+        assert_ok!(ranges.push(high));
+        assert_ok!(ranges.push(low));
+        assert_eq!(ranges.as_slice(), &[low, high]);
+        assert!(ranges.contains(PhysAddr(0x2000)));
+        assert!(!ranges.contains(PhysAddr(0x3000)));
+        assert_some_eq!(
+            ranges.span(),
+            PhysAddrRange::between(PhysAddr(0x1000), PhysAddr(0xa000))
+        );
+        let mut holes = ranges.holes();
+        assert_some_eq!(
+            holes.next(),
+            PhysAddrRange::between(PhysAddr(0x3000), PhysAddr(0x8000))
+        );
+        assert_none!(holes.next());
+
+        for _ in 2..MAX_PHYS_ADDR_RANGES {
+            assert_ok!(ranges.push(low));
+        }
+        assert_err!(ranges.push(low));
+    }
+
     #[test]
     fn iterator() {
         let range = PhysAddrRange::between(PhysAddr(0x1000), PhysAddr(0x3000));
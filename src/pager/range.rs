@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Allocate and free pages within a range.
+//!
+//! Suited for device MMIO and kernel stack ranges. Free space is kept as a short
+//! list of ranges, sorted by address, so no heap is needed.
+
+use super::{Addr, AddrRange, VirtAddr, VirtAddrRange, PAGESIZE_BYTES};
+use crate::{Error, Result};
+
+use core::fmt::{Debug, Formatter};
+
+/// Most separate free ranges that can be tracked.
+const MAX_FREE_RANGES: usize = 32;
+
+/// An allocator of pages within a single range.
+pub struct PageRangeAllocator {
+    free: [Option<VirtAddrRange>; MAX_FREE_RANGES], // sorted by base, no gaps
+    len: usize,
+}
+
+impl PageRangeAllocator {
+    /// Empty allocator.
+    pub const fn new() -> Self {
+        Self {
+            free: [None; MAX_FREE_RANGES],
+            len: 0,
+        }
+    }
+
+    /// Replace the contents of the allocator with a single free range.
+    pub fn reset(&mut self, range: VirtAddrRange) -> Result<()> {
+        self.free = [None; MAX_FREE_RANGES];
+        self.free[0] = Some(range);
+        self.len = 1;
+        Ok(())
+    }
+
+    fn range(&self, i: usize) -> VirtAddrRange {
+        self.free[i].expect("free range")
+    }
+
+    fn remove(&mut self, i: usize) {
+        for j in i..self.len - 1 {
+            self.free[j] = self.free[j + 1];
+        }
+        self.len -= 1;
+        self.free[self.len] = None;
+    }
+
+    fn insert(&mut self, i: usize, range: VirtAddrRange) -> Result<()> {
+        if self.len == MAX_FREE_RANGES {
+            return Err(Error::OutOfMemory);
+        }
+        for j in (i..self.len).rev() {
+            self.free[j + 1] = self.free[j];
+        }
+        self.free[i] = Some(range);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Allocate a number of pages from the top of the highest free range large enough.
+    pub fn alloc(&mut self, pages: usize) -> Result<VirtAddrRange> {
+        info!("allocating {} pages", pages);
+        let length = pages * PAGESIZE_BYTES;
+        for i in (0..self.len).rev() {
+            let range = self.range(i);
+            if range.length() < length {
+                continue;
+            }
+            let remaining = range.length() - length;
+            if remaining == 0 {
+                self.remove(i);
+            } else {
+                self.free[i] = Some(range.resize(remaining));
+            }
+            return Ok(VirtAddrRange::new(range.base().increment(remaining), length));
+        }
+        Err(Error::OutOfMemory)
+    }
+
+    /// Return a previously allocated range, merging it with free neighbours.
+    pub fn free(&mut self, range: VirtAddrRange) -> Result<()> {
+        info!("freeing {:?}", range);
+        let i = (0..self.len)
+            .find(|i| self.range(*i).base() > range.base())
+            .unwrap_or(self.len);
+        let below = if i > 0 { self.free[i - 1] } else { None };
+        let above = if i < self.len { self.free[i] } else { None };
+        if below.map_or(false, |below| below.top() > range.base())
+            || above.map_or(false, |above| range.top() > above.base())
+        {
+            return Err(Error::UnexpectedValue);
+        }
+
+        let joins_below = below.map_or(false, |below| below.top() == range.base());
+        let joins_above = above.map_or(false, |above| range.top() == above.base());
+        match (joins_below, joins_above) {
+            (true, true) => {
+                let merged = VirtAddrRange::between(self.range(i - 1).base(), self.range(i).top());
+                self.free[i - 1] = Some(merged);
+                self.remove(i);
+            }
+            (true, false) => {
+                let merged = VirtAddrRange::between(self.range(i - 1).base(), range.top());
+                self.free[i - 1] = Some(merged);
+            }
+            (false, true) => {
+                let merged = VirtAddrRange::between(range.base(), self.range(i).top());
+                self.free[i] = Some(merged);
+            }
+            (false, false) => {
+                self.insert(i, range)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debug for PageRangeAllocator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "PageRangeAllocator{{ free: {:?} }}", &self.free[..self.len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc() {
+        let mut allocator = PageRangeAllocator::new();
+        assert_err!(allocator.alloc(1));
+
+        let base = VirtAddr::at(0xd000);
+        let virt_addr_range = VirtAddrRange::new(base, 0x3000);
+        assert_ok!(allocator.reset(virt_addr_range));
+
+        assert_ok_eq!(allocator.alloc(1), VirtAddrRange::new(VirtAddr::at(0xf000), 0x1000));
+        assert_ok_eq!(allocator.alloc(2), VirtAddrRange::new(VirtAddr::at(0xd000), 0x2000));
+        assert_err!(allocator.alloc(1));
+    }
+
+    #[test]
+    fn test_free() {
+        let mut allocator = PageRangeAllocator::new();
+        let virt_addr_range = VirtAddrRange::new(VirtAddr::at(0x10000), 0x4000);
+        assert_ok!(allocator.reset(virt_addr_range));
+
+        let a = allocator.alloc(1).unwrap();
+        let b = allocator.alloc(1).unwrap();
+        let c = allocator.alloc(1).unwrap();
+        assert_ok!(allocator.free(b));
+        assert_err!(allocator.free(b));
+        assert_eq!(allocator.len, 2);
+
+        // fills gap
+        assert_ok_eq!(allocator.alloc(1), b);
+        assert_ok!(allocator.free(a));
+        assert_ok!(allocator.free(c));
+        assert_eq!(allocator.len, 2);
+        assert_ok!(allocator.free(b));
+        assert_eq!(allocator.len, 1);
+        assert_ok_eq!(allocator.alloc(4), virt_addr_range);
+    }
+}
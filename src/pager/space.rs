@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: Unlicense
+
+//! User address spaces, each with its own lower half page directory.
+//!
+//! The kernel half of every address space is shared, and its pages are global.
+//! User pages are not global, so are tagged in the TLB with the ASID of the
+//! address space which was loaded when they were cached.
+
+use super::{
+    asid::{Asid, AsidAllocator, ASID_LIMIT},
+    frames, mem_fixed_offset, mem_translation, AddrRange, Attributes, FixedOffset, FramePurpose,
+    PhysAddr, VirtAddr, VirtAddrRange, KERNEL_PAGE_DIRECTORY, PAGESIZE_BYTES,
+};
+
+use crate::archs::{arch, arch::Arch, PageDirectory, PagerTrait};
+use crate::util::locked::Locked;
+use crate::{Error, Result};
+
+/// ASIDs for user address spaces.
+static ASID_ALLOCATOR: Locked<AsidAllocator> = Locked::new(AsidAllocator::new(ASID_LIMIT));
+
+/// A user address space, sharing the kernel mappings.
+pub struct AddressSpace {
+    page_directory: Locked<arch::PageDirectory>,
+    asid: Locked<Option<Asid>>, // None until first switched to
+}
+
+impl AddressSpace {
+    /// Create an address space with nothing mapped in the user range.
+    pub fn new() -> Result<Self> {
+        major!("new");
+        let page_directory = KERNEL_PAGE_DIRECTORY
+            .lock()
+            .new_user(frames::allocator(), mem_translation())?;
+        Ok(Self {
+            page_directory: Locked::new(page_directory),
+            asid: Locked::new(None),
+        })
+    }
+
+    /// Map zeroed frames across a range of user addresses.
+    pub fn map_zeroed(&self, virt_addr_range: VirtAddrRange, attributes: Attributes) -> Result<()> {
+        info!("map_zeroed: {:?}", virt_addr_range);
+        if !Arch::user_range().covers(&virt_addr_range) {
+            return Err(Error::SegmentFault);
+        }
+        // before any frames are allocated
+        attributes.validate()?;
+        let mut page_directory = self.page_directory.lock();
+        let mut page = virt_addr_range.resize(PAGESIZE_BYTES);
+        for _ in 0..virt_addr_range.length_in_pages() {
+            let phys_addr = frames::alloc_zeroed(FramePurpose::User)?;
+            let mapped = page_directory.map_translation(
+                page,
+                FixedOffset::new(phys_addr, page.base()),
+                attributes,
+                frames::allocator(),
+                mem_translation(),
+            );
+            if let Err(e) = mapped {
+                frames::allocator().lock().discard(phys_addr)?;
+                return Err(e);
+            }
+            page = page.step();
+        }
+        Ok(())
+    }
+
+    /// Return the physical address a user address maps to.
+    pub fn maps_to(&self, virt_addr: VirtAddr) -> Result<PhysAddr> {
+        self.page_directory
+            .lock()
+            .maps_to(virt_addr, mem_fixed_offset())
+    }
+
+    /// Load this address space on the current core.
+    pub fn switch_to(&self) -> Result<()> {
+        let mut asid = self.asid.lock();
+        let core = arch::core_id() as usize;
+        let (current, flush) = ASID_ALLOCATOR.lock().refresh(core, *asid);
+        if flush {
+            Arch::invalidate_tlb_all()?;
+        }
+        *asid = Some(current);
+        Arch::switch_address_space(&*self.page_directory.lock(), current.value())
+    }
+}
+
+/// Load the kernel page directory's lower half on the current core.
+pub fn switch_to_kernel() -> Result<()> {
+    Arch::switch_address_space(&*KERNEL_PAGE_DIRECTORY.lock(), Asid::KERNEL.value())?;
+    ASID_ALLOCATOR.lock().release(arch::core_id() as usize);
+    Ok(())
+}
+
+impl Drop for AddressSpace {
+    /// Free the user range's frames and tables, unless some core may still walk them.
+    fn drop(&mut self) {
+        major!("drop");
+        let asid = *self.asid.lock();
+        if let Some(asid) = asid {
+            if ASID_ALLOCATOR.lock().is_loaded(asid) {
+                error!("AddressSpace::drop: ASID {} is still loaded", asid.value());
+                return;
+            }
+        }
+        self.page_directory
+            .lock()
+            .free_user(frames::allocator(), mem_fixed_offset())
+            .expect("AddressSpace::drop");
+        if let Some(asid) = asid {
+            Arch::invalidate_tlb_asid(asid.value()).expect("Arch::invalidate_tlb_asid");
+        }
+    }
+}
@@ -0,0 +1,403 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Backing store for evicted pages on a block device.
+//!
+//! The swap device is a whole block device set aside for swap, marked like a Linux
+//! swap area with a signature at the end of its first page, eg. by `mkswap`. It is
+//! taken from the devices which can be block mapped, so nothing else writes to it.
+//!
+//! The device is divided into page-sized slots. The first slot is reserved so that
+//! a swapped-out page is never recorded at sector zero, and holds the header of the
+//! last checkpoint before the signature.
+
+use super::{
+    frames, mem_fixed_offset, mem_translation, Addr, FramePurpose, PhysAddr, Translate, VirtAddr,
+    KERNEL_PAGE_DIRECTORY, MAX_CORES, PAGESIZE_BYTES,
+};
+
+use crate::archs::{arch, PageDirectory};
+use crate::device::{Block, Sector, BLOCK_DEVICES};
+use crate::util::locked::Locked;
+use crate::{Error, Result};
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+
+use core::num::NonZeroU64;
+
+/// Bytes in a block device sector.
+const SECTOR_BYTES: usize = 512;
+
+/// Sectors in a swap slot.
+pub(in crate::pager) const SECTORS_PER_PAGE: u64 = (PAGESIZE_BYTES / SECTOR_BYTES) as u64;
+
+/// Marks a swap device, at the end of its first slot.
+const SIGNATURE: &[u8] = b"SWAPSPACE2";
+
+/// Bytes at the start of the first slot which can hold a checkpoint header.
+pub(in crate::pager) const HEADER_BYTES: usize = PAGESIZE_BYTES - SIGNATURE.len();
+
+type Disk = Arc<Locked<Box<dyn Block + Send>>>;
+
+/// Bitmaps of the slots in use on the swap device.
+///
+/// A slot is in use while a page or the frame holding it refers to it, or while the
+/// last checkpoint does. Slots of the last checkpoint are never written, so a page
+/// which has changed since is written to a fresh slot.
+#[derive(Debug)]
+struct SwapSlots {
+    bitmap: Vec<u64>,
+    checkpointed: Vec<u64>,
+    slots: u64,
+    next: u64,
+}
+
+impl SwapSlots {
+    fn new(slots: u64) -> Self {
+        let words = ((slots + 63) / 64) as usize;
+        let mut result = Self {
+            bitmap: vec![0u64; words],
+            checkpointed: vec![0u64; words],
+            slots,
+            next: 1,
+        };
+        result.bitmap[0] = 1;
+        result
+    }
+
+    fn alloc(&mut self) -> Result<u64> {
+        for n in 0..self.slots {
+            let slot = (self.next + n) % self.slots;
+            let (word, bit) = ((slot / 64) as usize, slot % 64);
+            if (self.bitmap[word] | self.checkpointed[word]) & (1 << bit) == 0 {
+                self.bitmap[word] |= 1 << bit;
+                self.next = slot + 1;
+                return Ok(slot);
+            }
+        }
+        Err(Error::DeviceAtCapacity)
+    }
+
+    /// Mark a slot in use which was allocated before the kernel started.
+    fn reserve(&mut self, slot: u64) -> Result<()> {
+        let (word, bit) = ((slot / 64) as usize, slot % 64);
+        if slot == 0 || slot >= self.slots || self.bitmap[word] & (1 << bit) != 0 {
+            return Err(Error::UnexpectedValue);
+        }
+        self.bitmap[word] |= 1 << bit;
+        Ok(())
+    }
+
+    /// Stop using a slot, which is free unless the last checkpoint refers to it.
+    fn free(&mut self, slot: u64) -> Result<()> {
+        let (word, bit) = ((slot / 64) as usize, slot % 64);
+        if slot == 0 || slot >= self.slots || self.bitmap[word] & (1 << bit) == 0 {
+            return Err(Error::UnexpectedValue);
+        }
+        self.bitmap[word] &= !(1 << bit);
+        Ok(())
+    }
+
+    /// Replace the slots of the last checkpoint, freeing those no longer in use.
+    fn set_checkpoint(&mut self, slots: &[u64]) -> Result<()> {
+        if slots.iter().any(|slot| *slot == 0 || *slot >= self.slots) {
+            return Err(Error::UnexpectedValue);
+        }
+        self.checkpointed.fill(0);
+        for slot in slots {
+            let (word, bit) = ((slot / 64) as usize, slot % 64);
+            self.checkpointed[word] |= 1 << bit;
+        }
+        Ok(())
+    }
+
+    fn is_checkpointed(&self, slot: u64) -> bool {
+        let (word, bit) = ((slot / 64) as usize, slot % 64);
+        slot < self.slots && self.checkpointed[word] & (1 << bit) != 0
+    }
+}
+
+struct Swap {
+    disk: Disk,
+    slots: SwapSlots,
+}
+
+static SWAP: Locked<Option<Swap>> = Locked::new(None);
+
+/// Use the block device marked for swap, if there is one.
+///
+/// Without a swap device, pages are not evicted and the persistent area can't be
+/// checkpointed. Returns whether a swap device was found.
+pub(in crate::pager) fn init() -> Result<bool> {
+    major!("init");
+
+    let (name, disk) = match find_swap_device()? {
+        Some(found) => found,
+        None => {
+            info!("no swap device, running without swap");
+            return Ok(false);
+        }
+    };
+    BLOCK_DEVICES.lock().remove(&name);
+    let slots = disk.lock().capacity().0 / SECTORS_PER_PAGE;
+    info!("{}: {} swap slots", name, slots);
+
+    *SWAP.lock() = Some(Swap {
+        disk,
+        slots: SwapSlots::new(slots),
+    });
+    Ok(true)
+}
+
+/// The first block device whose first slot ends with the swap signature.
+fn find_swap_device() -> Result<Option<(String, Disk)>> {
+    let block_devices: Vec<(String, Disk)> = BLOCK_DEVICES
+        .lock()
+        .iter()
+        .map(|(name, disk)| (name.clone(), disk.clone()))
+        .collect();
+    let phys_addr = frames::allocator()
+        .lock()
+        .alloc_for_overwrite(FramePurpose::Kernel)?;
+    let mut result = Ok(None);
+    for (name, disk) in block_devices {
+        match is_swap_device(&disk, phys_addr) {
+            Ok(false) => continue,
+            Ok(true) => result = Ok(Some((name, disk))),
+            Err(e) => result = Err(e),
+        }
+        break;
+    }
+    frames::allocator().lock().discard(phys_addr)?;
+    result
+}
+
+/// Read the first slot of a device into a frame, and check for the swap signature.
+fn is_swap_device(disk: &Disk, phys_addr: PhysAddr) -> Result<bool> {
+    let mut disk = disk.lock();
+    if disk.capacity().0 < SECTORS_PER_PAGE {
+        return Ok(false);
+    }
+    let id = disk.read(&[phys_addr], Sector(0))?;
+    wait(&mut disk, id)?;
+    let page: *const u8 = mem_translation().translate_phys(phys_addr)?.into();
+    let page = unsafe { core::slice::from_raw_parts(page, PAGESIZE_BYTES) };
+    Ok(&page[HEADER_BYTES..] == SIGNATURE)
+}
+
+/// Reserve a slot on the swap device and return its first sector.
+pub(in crate::pager) fn alloc_sector() -> Result<NonZeroU64> {
+    let mut lock = SWAP.lock();
+    let swap = lock.as_mut().ok_or(Error::OutOfPages)?;
+    let slot = swap.slots.alloc()?;
+    Ok(NonZeroU64::new(slot * SECTORS_PER_PAGE).expect("slot zero reserved"))
+}
+
+/// Mark the slot starting at a sector in use, because a checkpointed page is mapped to it.
+pub(in crate::pager) fn reserve(sector: NonZeroU64) -> Result<()> {
+    let mut lock = SWAP.lock();
+    let swap = lock.as_mut().ok_or(Error::UnInitialised)?;
+    swap.slots.reserve(sector.get() / SECTORS_PER_PAGE)
+}
+
+/// Return a slot to the swap device, once the last checkpoint no longer refers to it.
+pub(in crate::pager) fn release(sector: NonZeroU64) -> Result<()> {
+    let mut lock = SWAP.lock();
+    let swap = lock.as_mut().ok_or(Error::UnInitialised)?;
+    swap.slots.free(sector.get() / SECTORS_PER_PAGE)
+}
+
+/// Record the slots of a checkpoint which has been written, releasing those of the last.
+pub(in crate::pager) fn set_checkpoint(sectors: &[NonZeroU64]) -> Result<()> {
+    let slots: Vec<u64> = sectors
+        .iter()
+        .map(|sector| sector.get() / SECTORS_PER_PAGE)
+        .collect();
+    let mut lock = SWAP.lock();
+    let swap = lock.as_mut().ok_or(Error::UnInitialised)?;
+    swap.slots.set_checkpoint(&slots)
+}
+
+/// Whether the last checkpoint refers to the slot starting at a sector.
+///
+/// Such a slot must not be written, so a changed page is given a fresh slot.
+pub(in crate::pager) fn is_checkpointed(sector: NonZeroU64) -> bool {
+    SWAP.lock().as_ref().map_or(false, |swap| {
+        swap.slots.is_checkpointed(sector.get() / SECTORS_PER_PAGE)
+    })
+}
+
+/// Whether a block device is the swap device.
+pub(in crate::pager) fn is_swap_disk(disk: &Disk) -> bool {
+    SWAP.lock()
+        .as_ref()
+        .map_or(false, |swap| Arc::ptr_eq(&swap.disk, disk))
+}
+
+fn disk() -> Result<Disk> {
+    let lock = SWAP.lock();
+    let swap = lock.as_ref().ok_or(Error::UnInitialised)?;
+    Ok(swap.disk.clone())
+}
+
+/// Spin until a request completes.
+pub(in crate::pager) fn wait(
+    disk: &mut Box<dyn Block + Send>,
+    id: crate::device::RequestId,
+) -> Result<()> {
+    loop {
+        match disk.status(id) {
+            Err(Error::WouldBlock) => continue,
+            result => return result.and(Ok(())),
+        }
+    }
+}
+
+/// Write a frame to its slot.
+pub(in crate::pager) fn write_page(phys_addr: PhysAddr, sector: NonZeroU64) -> Result<()> {
+    debug!("write_page: {:?} -> {}", phys_addr, sector);
+    let disk = disk()?;
+    let mut disk = disk.lock();
+    let id = disk.write(&[phys_addr], Sector(sector.get()))?;
+    wait(&mut disk, id)
+}
+
+/// Read a frame from its slot.
+pub(in crate::pager) fn read_page(phys_addr: PhysAddr, sector: NonZeroU64) -> Result<()> {
+    debug!("read_page: {:?} <- {}", phys_addr, sector);
+    let disk = disk()?;
+    let mut disk = disk.lock();
+    let id = disk.read(&[phys_addr], Sector(sector.get()))?;
+    wait(&mut disk, id)
+}
+
+/// Write a frame to the reserved first slot, after its first HEADER_BYTES are filled.
+pub(in crate::pager) fn write_header(phys_addr: PhysAddr) -> Result<()> {
+    debug!("write_header: {:?}", phys_addr);
+    let page: *mut u8 = mem_translation().translate_phys(phys_addr)?.into();
+    unsafe {
+        let signature = core::slice::from_raw_parts_mut(page.add(HEADER_BYTES), SIGNATURE.len());
+        signature.copy_from_slice(SIGNATURE);
+    }
+    let disk = disk()?;
+    let mut disk = disk.lock();
+    let id = disk.write(&[phys_addr], Sector(0))?;
+    wait(&mut disk, id)
+}
+
+/// Read the reserved first slot into a frame.
+pub(in crate::pager) fn read_header(phys_addr: PhysAddr) -> Result<()> {
+    debug!("read_header: {:?}", phys_addr);
+    let disk = disk()?;
+    let mut disk = disk.lock();
+    let id = disk.read(&[phys_addr], Sector(0))?;
+    wait(&mut disk, id)
+}
+
+/// Wait until completed writes are durable.
+pub(in crate::pager) fn flush() -> Result<()> {
+    let disk = disk()?;
+    let mut disk = disk.lock();
+    let id = disk.flush()?;
+    wait(&mut disk, id)
+}
+
+/// Page each core is reloading, so that only one core reloads a page at a time.
+static RELOADING: Locked<[Option<VirtAddr>; MAX_CORES]> = Locked::new([None; MAX_CORES]);
+
+/// Bring a swapped-out page back into a new frame and map it where it was.
+///
+/// The slot stays with the frame until it is freed, so the page can be written
+/// back to the same place. If another core is reloading the page, waits for it
+/// to finish instead.
+pub(in crate::pager) fn reload(virt_addr: VirtAddr, sector: NonZeroU64) -> Result<()> {
+    info!("reload: {:?} <- {}", virt_addr, sector);
+    let virt_addr = virt_addr.page_base();
+    let core = arch::core_id() as usize;
+    loop {
+        let mut reloading = RELOADING.lock();
+        if !reloading.contains(&Some(virt_addr)) {
+            *reloading.get_mut(core).ok_or(Error::UnexpectedValue)? = Some(virt_addr);
+            break;
+        }
+        drop(reloading);
+        core::hint::spin_loop();
+    }
+    let result = reload_page(virt_addr, sector);
+    RELOADING.lock()[core] = None;
+    result
+}
+
+/// Read a page into a new frame and map it, unless it has been reloaded already.
+fn reload_page(virt_addr: VirtAddr, sector: NonZeroU64) -> Result<()> {
+    let swapped = KERNEL_PAGE_DIRECTORY
+        .lock()
+        .swapped_out(virt_addr, mem_fixed_offset())?;
+    if swapped != Some(sector) {
+        debug!("reload: already reloaded {:?}", virt_addr);
+        return Ok(());
+    }
+    let phys_addr = frames::alloc_for_overwrite(FramePurpose::User)?;
+    let result = read_page(phys_addr, sector).and_then(|_| {
+        KERNEL_PAGE_DIRECTORY.lock().swap_in(
+            virt_addr,
+            phys_addr,
+            frames::allocator(),
+            mem_fixed_offset(),
+        )
+    });
+    if let Err(e) = result {
+        frames::allocator().lock().discard(phys_addr)?;
+        return Err(e);
+    }
+    frames::allocator().lock().set_persisted(phys_addr, sector)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots() {
+        let mut slots = SwapSlots::new(130);
+        assert_ok_eq!(slots.alloc(), 1);
+        assert_ok_eq!(slots.alloc(), 2);
+        assert_err!(slots.free(0));
+        assert_ok!(slots.free(1));
+        assert_err!(slots.free(1));
+        for i in 3..130 {
+            assert_ok_eq!(slots.alloc(), i);
+        }
+        assert_ok_eq!(slots.alloc(), 1);
+        assert_err!(slots.alloc());
+    }
+
+    #[test]
+    fn reserve() {
+        let mut slots = SwapSlots::new(130);
+        assert_err!(slots.reserve(0));
+        assert_ok!(slots.reserve(1));
+        assert_err!(slots.reserve(1));
+        assert_err!(slots.reserve(130));
+        assert_ok_eq!(slots.alloc(), 2);
+        assert_ok!(slots.free(1));
+    }
+
+    #[test]
+    fn checkpointed() {
+        let mut slots = SwapSlots::new(4);
+        assert_ok!(slots.reserve(1));
+        assert_ok!(slots.reserve(2));
+        assert_ok!(slots.set_checkpoint(&[1, 2]));
+        assert!(slots.is_checkpointed(1));
+
+        // a slot freed while checkpointed is not reused until the next checkpoint
+        assert_ok!(slots.free(1));
+        assert_ok_eq!(slots.alloc(), 3);
+        assert_err!(slots.alloc());
+        assert_ok!(slots.set_checkpoint(&[2, 3]));
+        assert!(!slots.is_checkpointed(1));
+        assert_err!(slots.set_checkpoint(&[4]));
+        assert!(slots.is_checkpointed(3));
+        assert_ok_eq!(slots.alloc(), 1);
+    }
+}
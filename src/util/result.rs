@@ -27,6 +27,10 @@ pub enum Error {
     DeviceIncompatible,
     /// Device unable to accept request
     DeviceAtCapacity,
+    /// Device does not support the request
+    Unsupported,
+    /// Mapping attributes not permitted by policy
+    ForbiddenAttributes,
     /// Function failed with undefined error
     UnknownError,
     /// Function failed because not implemented
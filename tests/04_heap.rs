@@ -6,6 +6,7 @@
 #![reexport_test_harness_main = "test_main"]
 #![test_runner(libkernel::util::testing::test_runner)]
 #![feature(format_args_nl)] // for debug macros
+#![feature(allocator_api)] // for Box::try_new
 #![allow(unused_imports)]
 
 #[macro_use]
@@ -34,6 +35,47 @@ fn test_heap() {
     assert_eq!(*x, 1);
 }
 
+#[kernel_test]
+fn test_fallible_heap() {
+    info!("test_fallible_heap");
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use libkernel::pager;
+
+    let x = assert_ok!(Box::try_new([7u64; 512]));
+    let stats = pager::heap_stats();
+    assert!(stats.used >= 4096);
+    assert!(stats.high_water >= stats.used);
+    assert!(stats.size <= stats.limit);
+    assert_eq!(x[511], 7);
+
+    // larger than the heap range, so fails without panicking
+    let mut v: Vec<u8> = Vec::new();
+    assert_err!(v.try_reserve(stats.limit + 1));
+    assert_ok!(v.try_reserve(4096));
+}
+
+#[kernel_test]
+fn test_slab() {
+    info!("test_slab");
+    use alloc::boxed::Box;
+    use libkernel::pager;
+
+    let before = pager::slab_stats()[1];
+    let x = Box::new([3u64; 4]);
+    let after = pager::slab_stats()[1];
+    assert_eq!(after.size, 32);
+    assert_eq!(after.in_use, before.in_use + 1);
+    assert!(after.capacity >= after.in_use);
+    assert_eq!(x[3], 3);
+
+    // large allocations are whole pages of the heap range
+    let heap = pager::heap_stats();
+    let y = Box::new([5u8; 8000]);
+    assert_eq!(pager::heap_stats().used, heap.used + 8192);
+    assert_eq!(y[7999], 5);
+}
+
 use libkernel::debug::Level;
 
 #[no_mangle]
@@ -15,15 +15,16 @@ extern crate libkernel;
 
 extern crate alloc;
 
-use libkernel::Result;
 use libkernel::{device, device::Sector};
 use libkernel::{
     pager,
-    pager::{Page, Paging},
+    pager::{AddrRange, Page, Paging, VirtAddr, VirtAddrRange},
 };
+use libkernel::{Error, Result};
 
 use alloc::boxed::Box;
 
+use core::ptr;
 use core::sync::{atomic, atomic::Ordering};
 
 use test_macros::kernel_test;
@@ -84,9 +85,8 @@ fn read() -> Result<Box<[Page; 4]>> {
 
 #[kernel_test]
 fn device_init() {
-    major!("initialising device");
-    device::init().expect("device::init");
-    info!("done");
+    // devices are initialised with the pager, which takes the swap device
+    assert_eq!(1, device::BLOCK_DEVICES.lock().len());
 
     _breakpoint();
 
@@ -98,6 +98,30 @@ fn device_init() {
     debug!("returned");
 }
 
+#[kernel_test]
+fn read_unaligned() {
+    let mut buffer = box [0xffu8; 3 * 512 + 1];
+    let bytes = &mut buffer[1..];
+    let virt_addr_range = VirtAddrRange::new(VirtAddr::from(&bytes[0]), bytes.len());
+    let segments = pager::Pager::dma_segments(virt_addr_range).expect("dma_segments");
+
+    let disk = {
+        let block_devices = device::BLOCK_DEVICES.lock();
+        let (_, disk) = block_devices
+            .first_key_value()
+            .expect("block_devices.first_key");
+        disk.clone()
+    };
+    let mut disk = disk.lock();
+    let id = disk.read_segments(&segments, Sector(24)).expect("read_segments");
+    while let Err(Error::WouldBlock) = disk.status(id) {}
+    atomic::fence(Ordering::SeqCst);
+    assert!(bytes
+        .iter()
+        .all(|byte| unsafe { ptr::read_volatile(byte) } == 0));
+    pager::Pager::release_dma_segments(&segments).expect("release_dma_segments");
+}
+
 use libkernel::debug::{Level, _breakpoint};
 
 #[no_mangle]
@@ -109,7 +133,7 @@ fn _override_log_levels() -> (Level, &'static [(&'static str, Level)]) {
         ("pager::layout", Level::Major),
         ("pager::frames", Level::Info),
         ("pager::frames::deque", Level::Major),
-        ("pager::bump", Level::Major),
+        ("pager::range", Level::Major),
         ("pager::alloc", Level::Major),
     ];
     (Level::Trace, LOG_LEVEL_SETTINGS)
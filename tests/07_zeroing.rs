@@ -0,0 +1,55 @@
+// SPDX-License-Identifier: Unlicense
+
+#![feature(custom_test_frameworks)]
+#![no_main]
+#![no_std]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(libkernel::util::testing::test_runner)]
+#![feature(format_args_nl)] // for debug macros
+
+#[allow(unused_imports)]
+#[macro_use]
+extern crate libkernel;
+
+use libkernel::pager::{self, FrameAllocator, FramePurpose, Pager, Paging};
+
+use test_macros::kernel_test;
+
+#[no_mangle]
+pub extern "C" fn collect_tests() -> () {
+    test_main()
+}
+
+#[kernel_test]
+fn zeroing_reserve() {
+    let allocator = pager::frame_allocator();
+    allocator.lock().set_zeroed_target(4).unwrap();
+    assert_eq!(4, pager::zero_free_frames().unwrap());
+    assert_eq!(4, Pager::frame_stats().unwrap().zeroed);
+    assert_eq!(0, pager::zero_free_frames().unwrap());
+
+    let before = allocator.lock().zeroing_stats().unwrap();
+    let phys_addr = allocator
+        .lock()
+        .alloc_zeroed(FramePurpose::Kernel)
+        .unwrap();
+    let after = allocator.lock().zeroing_stats().unwrap();
+    assert_eq!(before.fast + 1, after.fast);
+    assert_eq!(3, Pager::frame_stats().unwrap().zeroed);
+    assert_eq!(before.slow, after.slow);
+    info!("allocated: {:?}", phys_addr);
+
+    assert_eq!(1, pager::zero_free_frames().unwrap());
+}
+
+use libkernel::debug::Level;
+
+#[no_mangle]
+fn _override_log_levels() -> (Level, &'static [(&'static str, Level)]) {
+    const LOG_LEVEL_SETTINGS: &[(&str, Level)] = &[
+        ("aarch64::pager", Level::Major),
+        ("pager::layout", Level::Major),
+        ("pager::frames", Level::Major),
+    ];
+    (Level::Trace, LOG_LEVEL_SETTINGS)
+}
@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: Unlicense
+
+#![feature(custom_test_frameworks)]
+#![no_main]
+#![no_std]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(libkernel::util::testing::test_runner)]
+#![feature(format_args_nl)] // for debug macros
+
+#[allow(unused_imports)]
+#[macro_use]
+extern crate libkernel;
+
+use libkernel::pager::{Addr, Pager, Paging, PAGESIZE_BYTES};
+
+use test_macros::kernel_test;
+
+#[no_mangle]
+pub extern "C" fn collect_tests() -> () {
+    test_main()
+}
+
+#[kernel_test]
+fn contiguous_dma() {
+    let mapping = Pager::map_dma(4).expect("Pager::map_dma");
+    assert_eq!(4 * PAGESIZE_BYTES, mapping.length());
+
+    let base = Pager::maps_to(mapping.base()).unwrap();
+    for i in 1..4 {
+        let phys_addr = Pager::maps_to(mapping.base().increment(i * PAGESIZE_BYTES)).unwrap();
+        assert_eq!(base.increment(i * PAGESIZE_BYTES), phys_addr);
+    }
+    drop(mapping);
+
+    // freed frames are reused
+    let mapping = Pager::map_dma(2).expect("Pager::map_dma");
+    assert_eq!(base, Pager::maps_to(mapping.base()).unwrap());
+}
+
+#[kernel_test]
+fn reclaim_virtual_range() {
+    let mapping = Pager::map_dma(4).expect("Pager::map_dma");
+    let virt_addr_range = mapping.range();
+    drop(mapping);
+
+    // freed device range is reused
+    let mapping = Pager::map_dma(4).expect("Pager::map_dma");
+    assert_eq!(virt_addr_range, mapping.range());
+}
+
+use libkernel::debug::Level;
+
+#[no_mangle]
+fn _override_log_levels() -> (Level, &'static [(&'static str, Level)]) {
+    const LOG_LEVEL_SETTINGS: &[(&str, Level)] = &[
+        ("aarch64::pager", Level::Major),
+        ("pager::layout", Level::Major),
+        ("pager::frames", Level::Major),
+    ];
+    (Level::Trace, LOG_LEVEL_SETTINGS)
+}
@@ -0,0 +1,48 @@
+// SPDX-License-Identifier: Unlicense
+
+#![feature(custom_test_frameworks)]
+#![no_main]
+#![no_std]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(libkernel::util::testing::test_runner)]
+#![feature(format_args_nl)] // for debug macros
+
+#[allow(unused_imports)]
+#[macro_use]
+extern crate libkernel;
+
+mod panic_exit_success;
+
+use test_macros::kernel_test;
+
+#[no_mangle]
+pub extern "C" fn collect_tests() -> () {
+    test_main()
+}
+
+#[allow(unconditional_recursion)]
+#[inline(never)]
+fn recurse(depth: u64) -> u64 {
+    let frame = [depth; 64];
+    let total = unsafe { core::ptr::read_volatile(&frame[63]) };
+    total + recurse(depth + 1)
+}
+
+#[kernel_test]
+fn stack_overflow() {
+    info!("overflowing intentionally");
+    recurse(0);
+    unreachable!()
+}
+
+use libkernel::debug::Level;
+
+#[no_mangle]
+fn _override_log_levels() -> (Level, &'static [(&'static str, Level)]) {
+    const LOG_LEVEL_SETTINGS: &[(&str, Level)] = &[
+        ("aarch64::pager", Level::Major),
+        ("pager::layout", Level::Major),
+        ("pager::frames", Level::Major),
+    ];
+    (Level::Trace, LOG_LEVEL_SETTINGS)
+}
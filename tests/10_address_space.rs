@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: Unlicense
+
+#![feature(custom_test_frameworks)]
+#![no_main]
+#![no_std]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(libkernel::util::testing::test_runner)]
+#![feature(format_args_nl)] // for debug macros
+
+#[allow(unused_imports)]
+#[macro_use]
+extern crate libkernel;
+
+use libkernel::pager::{
+    self, AddrRange, AddressSpace, AttributeField, Attributes, Pager, Paging, VirtAddr,
+    VirtAddrRange, PAGESIZE_BYTES,
+};
+
+use core::ptr;
+
+use test_macros::kernel_test;
+
+#[no_mangle]
+pub extern "C" fn collect_tests() -> () {
+    test_main()
+}
+
+fn user_page() -> VirtAddrRange {
+    VirtAddrRange::new(VirtAddr::at(0x80_0000_0000), PAGESIZE_BYTES)
+}
+
+#[kernel_test]
+fn separate_address_spaces() {
+    let attributes = Attributes::USER_DATA | AttributeField::Accessed;
+    let a = AddressSpace::new().expect("AddressSpace::new");
+    let b = AddressSpace::new().expect("AddressSpace::new");
+    a.map_zeroed(user_page(), attributes).unwrap();
+    b.map_zeroed(user_page(), attributes).unwrap();
+    assert_ne!(
+        a.maps_to(user_page().base()).unwrap(),
+        b.maps_to(user_page().base()).unwrap()
+    );
+
+    let p: *mut u64 = user_page().base().into();
+    unsafe {
+        a.switch_to().unwrap();
+        ptr::write_volatile(p, 1);
+        b.switch_to().unwrap();
+        assert_eq!(0, ptr::read_volatile(p));
+        ptr::write_volatile(p, 2);
+        a.switch_to().unwrap();
+        assert_eq!(1, ptr::read_volatile(p));
+    }
+
+    pager::switch_to_kernel().unwrap();
+}
+
+#[kernel_test]
+fn outside_user_range() {
+    let a = AddressSpace::new().expect("AddressSpace::new");
+    let kernel_page = VirtAddrRange::new(VirtAddr::at(0x1000), PAGESIZE_BYTES);
+    assert!(a.map_zeroed(kernel_page, Attributes::USER_DATA).is_err());
+}
+
+#[kernel_test]
+fn drop_frees_frames() {
+    let before = Pager::frame_stats().unwrap();
+    {
+        let a = AddressSpace::new().expect("AddressSpace::new");
+        a.map_zeroed(user_page().resize(4 * PAGESIZE_BYTES), Attributes::USER_DATA)
+            .unwrap();
+    }
+    let after = Pager::frame_stats().unwrap();
+    assert_eq!(after.allocs - before.allocs, after.frees - before.frees);
+}
+
+use libkernel::debug::Level;
+
+#[no_mangle]
+fn _override_log_levels() -> (Level, &'static [(&'static str, Level)]) {
+    const LOG_LEVEL_SETTINGS: &[(&str, Level)] = &[
+        ("aarch64::pager", Level::Major),
+        ("pager::layout", Level::Major),
+        ("pager::frames", Level::Major),
+    ];
+    (Level::Trace, LOG_LEVEL_SETTINGS)
+}
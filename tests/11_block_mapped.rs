@@ -0,0 +1,101 @@
+// SPDX-License-Identifier: Unlicense
+
+#![feature(custom_test_frameworks)]
+#![no_main]
+#![no_std]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(libkernel::util::testing::test_runner)]
+#![feature(format_args_nl)] // for debug macros
+
+#[allow(unused_imports)]
+#[macro_use]
+extern crate libkernel;
+
+extern crate alloc;
+
+use libkernel::device;
+use libkernel::pager::{
+    self, AddrRange, Attributes, Pager, Paging, RangeContent, VirtAddrRange, PAGESIZE_BYTES,
+};
+
+use alloc::string::String;
+
+use core::ptr;
+
+use test_macros::kernel_test;
+
+#[no_mangle]
+pub extern "C" fn collect_tests() -> () {
+    test_main()
+}
+
+const SECTORS_PER_PAGE: u64 = (PAGESIZE_BYTES / 512) as u64;
+
+/// Name of the disk, and the sector of its last page.
+fn last_page() -> (String, u64) {
+    let block_devices = device::BLOCK_DEVICES.lock();
+    let (name, disk) = block_devices.iter().next().expect("BLOCK_DEVICES");
+    let capacity = disk.lock().capacity().0;
+    (name.clone(), capacity - SECTORS_PER_PAGE)
+}
+
+fn block_page() -> VirtAddrRange {
+    pager::get_range(RangeContent::BlockMapped)
+        .expect("get_range")
+        .resize(PAGESIZE_BYTES)
+}
+
+#[kernel_test]
+fn write_back_and_reload() {
+    let (name, sector) = last_page();
+    let attributes = Attributes::KERNEL_DATA;
+
+    {
+        let mapping = Pager::map_block(block_page(), &name, sector, attributes).unwrap();
+        let p: *mut u64 = mapping.base().into();
+        unsafe {
+            assert_eq!(0, ptr::read_volatile(p.offset(42)));
+            ptr::write_volatile(p.offset(42), 10203040);
+        }
+        mapping.flush().unwrap();
+    }
+
+    {
+        let mapping = Pager::map_block(block_page(), &name, sector, attributes).unwrap();
+        let p: *mut u64 = mapping.base().into();
+        unsafe {
+            assert_eq!(10203040, ptr::read_volatile(p.offset(42)));
+            // leave the disk as it was
+            ptr::write_volatile(p.offset(42), 0);
+        }
+    }
+}
+
+#[kernel_test]
+fn rejects_bad_ranges() {
+    let (name, sector) = last_page();
+    let attributes = Attributes::KERNEL_DATA;
+    let heap_page = pager::get_range(RangeContent::KernelHeap)
+        .unwrap()
+        .resize(PAGESIZE_BYTES);
+    assert!(Pager::map_block(heap_page, &name, sector, attributes).is_err());
+    assert!(Pager::map_block(block_page(), "nonesuch", sector, attributes).is_err());
+    assert!(Pager::map_block(block_page(), &name, sector + 1, attributes).is_err());
+    // the swap device has been taken from the devices which can be mapped
+    assert_eq!(1, device::BLOCK_DEVICES.lock().len());
+
+    let _mapping = Pager::map_block(block_page(), &name, 0, attributes).unwrap();
+    assert!(Pager::map_block(block_page(), &name, 0, attributes).is_err());
+}
+
+use libkernel::debug::Level;
+
+#[no_mangle]
+fn _override_log_levels() -> (Level, &'static [(&'static str, Level)]) {
+    const LOG_LEVEL_SETTINGS: &[(&str, Level)] = &[
+        ("aarch64::pager", Level::Major),
+        ("pager::layout", Level::Major),
+        ("pager::frames", Level::Major),
+    ];
+    (Level::Trace, LOG_LEVEL_SETTINGS)
+}
@@ -0,0 +1,56 @@
+// SPDX-License-Identifier: Unlicense
+
+#![feature(custom_test_frameworks)]
+#![no_main]
+#![no_std]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(libkernel::util::testing::test_runner)]
+#![feature(format_args_nl)] // for debug macros
+
+#[allow(unused_imports)]
+#[macro_use]
+extern crate libkernel;
+
+use libkernel::pager::{self, Pager, Paging, RangeContent};
+
+use core::ptr;
+
+use test_macros::kernel_test;
+
+#[no_mangle]
+pub extern "C" fn collect_tests() -> () {
+    test_main()
+}
+
+#[kernel_test]
+fn checkpoint_cleans_pages() {
+    let base = pager::get_range(RangeContent::Persistent).unwrap().base();
+    let p: *mut u64 = base.into();
+    unsafe {
+        ptr::write_volatile(p, 10203040);
+    }
+    let phys_addr = Pager::maps_to(base).unwrap();
+    assert!(pager::frame_allocator().lock().is_dirty(phys_addr).unwrap());
+
+    Pager::checkpoint().unwrap();
+    assert!(!pager::frame_allocator().lock().is_dirty(phys_addr).unwrap());
+
+    unsafe {
+        ptr::write_volatile(p, 50607080);
+    }
+    assert!(pager::frame_allocator().lock().is_dirty(phys_addr).unwrap());
+    Pager::checkpoint().unwrap();
+    assert!(!pager::frame_allocator().lock().is_dirty(phys_addr).unwrap());
+}
+
+use libkernel::debug::Level;
+
+#[no_mangle]
+fn _override_log_levels() -> (Level, &'static [(&'static str, Level)]) {
+    const LOG_LEVEL_SETTINGS: &[(&str, Level)] = &[
+        ("aarch64::pager", Level::Major),
+        ("pager::layout", Level::Major),
+        ("pager::frames", Level::Major),
+    ];
+    (Level::Trace, LOG_LEVEL_SETTINGS)
+}
@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: Unlicense
+
+#![feature(custom_test_frameworks)]
+#![no_main]
+#![no_std]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(libkernel::util::testing::test_runner)]
+#![feature(format_args_nl)] // for debug macros
+
+#[allow(unused_imports)]
+#[macro_use]
+extern crate libkernel;
+
+use libkernel::pager::{
+    AddressSpace, AttributeField, Attributes, Pager, Paging, VirtAddr, VirtAddrRange,
+    PAGESIZE_BYTES,
+};
+use libkernel::Error;
+
+use test_macros::kernel_test;
+
+#[no_mangle]
+pub extern "C" fn collect_tests() -> () {
+    test_main()
+}
+
+fn user_page() -> VirtAddrRange {
+    VirtAddrRange::new(VirtAddr::at(0x80_0000_0000), PAGESIZE_BYTES)
+}
+
+#[kernel_test]
+fn boot_mappings_pass_audit() {
+    Pager::audit().unwrap();
+}
+
+#[kernel_test]
+fn rejects_forbidden_attributes() {
+    let a = AddressSpace::new().expect("AddressSpace::new");
+    assert_eq!(
+        Err(Error::ForbiddenAttributes),
+        a.map_zeroed(user_page(), Attributes::USER_RWX)
+    );
+    assert_eq!(
+        Err(Error::ForbiddenAttributes),
+        a.map_zeroed(user_page(), Attributes::USER_DATA | AttributeField::Device)
+    );
+    a.map_zeroed(user_page(), Attributes::USER_DATA).unwrap();
+    Pager::audit().unwrap();
+}
+
+use libkernel::debug::Level;
+
+#[no_mangle]
+fn _override_log_levels() -> (Level, &'static [(&'static str, Level)]) {
+    const LOG_LEVEL_SETTINGS: &[(&str, Level)] = &[
+        ("aarch64::pager", Level::Major),
+        ("pager::layout", Level::Major),
+        ("pager::frames", Level::Major),
+    ];
+    (Level::Trace, LOG_LEVEL_SETTINGS)
+}
@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: Unlicense
+
+#![feature(custom_test_frameworks)]
+#![no_main]
+#![no_std]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(libkernel::util::testing::test_runner)]
+#![feature(format_args_nl)] // for debug macros
+
+#[allow(unused_imports)]
+#[macro_use]
+extern crate libkernel;
+
+use libkernel::pager::{self, Pager, Paging, RangeContent};
+
+use core::ptr;
+
+use test_macros::kernel_test;
+
+#[no_mangle]
+pub extern "C" fn collect_tests() -> () {
+    test_main()
+}
+
+/// Runs after 12_checkpoint, whose last checkpoint is restored at boot.
+#[kernel_test]
+fn restored_on_demand() {
+    let base = pager::get_range(RangeContent::Persistent).unwrap().base();
+    // swapped out until it is touched
+    assert!(Pager::maps_to(base).is_err());
+
+    let p: *const u64 = base.into();
+    unsafe {
+        assert_eq!(50607080, ptr::read_volatile(p));
+    }
+    assert!(Pager::maps_to(base).is_ok());
+}
+
+use libkernel::debug::Level;
+
+#[no_mangle]
+fn _override_log_levels() -> (Level, &'static [(&'static str, Level)]) {
+    const LOG_LEVEL_SETTINGS: &[(&str, Level)] = &[
+        ("aarch64::pager", Level::Major),
+        ("pager::layout", Level::Major),
+        ("pager::frames", Level::Major),
+    ];
+    (Level::Trace, LOG_LEVEL_SETTINGS)
+}